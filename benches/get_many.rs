@@ -0,0 +1,97 @@
+// Compares batched `get_many` against looping over `get` on a database that is
+// larger than the CPU caches.
+//
+// Run with: cargo bench --bench get_many -- [num_keys]
+
+use kvfast_lib::database::{Database, Key, KEY_SIZE};
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+use tempfile::NamedTempFile;
+
+const VALUE_SIZE: usize = 32;
+const BATCH: usize = 1024;
+const ROUNDS: usize = 5;
+
+// Small deterministic generator so the benchmark needs no extra dependencies
+fn splitmix64(state: &mut u64) -> u64 {
+    *state = state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+    let mut z = *state;
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    z ^ (z >> 31)
+}
+
+fn random_key(state: &mut u64) -> Key {
+    let mut key = [0u8; 16];
+    key[..8].copy_from_slice(&splitmix64(state).to_le_bytes());
+    key[8..].copy_from_slice(&splitmix64(state).to_le_bytes());
+    key
+}
+
+fn report(name: &str, lookups: usize, elapsed: Duration) -> f64 {
+    let per_sec = lookups as f64 / elapsed.as_secs_f64();
+    println!(
+        "{:<10} {:>8.1} ns/lookup {:>8.2} M lookups/s",
+        name,
+        elapsed.as_nanos() as f64 / lookups as f64,
+        per_sec / 1e6
+    );
+    per_sec
+}
+
+fn main() -> std::io::Result<()> {
+    let num_keys: usize = std::env::args()
+        .skip(1)
+        .find_map(|arg| arg.parse().ok())
+        .unwrap_or(4_000_000);
+
+    let mut state = 42;
+    let keys: Vec<Key> = (0..num_keys).map(|_| random_key(&mut state)).collect();
+
+    let data_file = NamedTempFile::new()?;
+    let index_file = NamedTempFile::new()?;
+    let value = [7u8; VALUE_SIZE];
+    Database::<KEY_SIZE>::write_database(
+        data_file.path(),
+        index_file.path(),
+        keys.iter(),
+        std::iter::repeat_n(&value[..], num_keys),
+        1,
+    )?;
+    let db = Database::<KEY_SIZE>::open(data_file.path(), index_file.path())?;
+
+    // Random query order, so every lookup is a likely cache and TLB miss
+    let queries: Vec<Key> = (0..num_keys)
+        .map(|_| keys[splitmix64(&mut state) as usize % num_keys])
+        .collect();
+    println!("{} keys, {} byte values, batches of {}", num_keys, VALUE_SIZE, BATCH);
+
+    let mut best_get = Duration::MAX;
+    let mut best_get_many = Duration::MAX;
+    let mut out = Vec::with_capacity(BATCH);
+    for _ in 0..ROUNDS {
+        let start = Instant::now();
+        let mut found = 0usize;
+        for key in &queries {
+            found += db.get(black_box(key)).map_or(0, |v| v.len());
+        }
+        black_box(found);
+        best_get = best_get.min(start.elapsed());
+
+        let start = Instant::now();
+        let mut found = 0usize;
+        for batch in queries.chunks(BATCH) {
+            out.clear();
+            db.get_many_into(black_box(batch), &mut out);
+            found += out.iter().map(|v| v.map_or(0, |v| v.len())).sum::<usize>();
+        }
+        black_box(found);
+        best_get_many = best_get_many.min(start.elapsed());
+    }
+
+    let get = report("get", queries.len(), best_get);
+    let get_many = report("get_many", queries.len(), best_get_many);
+    println!("speedup    {:>8.2}x", get_many / get);
+
+    Ok(())
+}
@@ -0,0 +1,1018 @@
+use crate::database::{
+    fingerprint, key_digest, Database, DuplicatePolicy, Key, KeyCheck, ValueWidth, WriteOptions, DIGEST_SIZE,
+    KEY_SIZE, VAR_KEY_SIZE,
+};
+use crate::error::{Error, Result};
+use crate::header::{
+    DabaHeader, INDEX_FLAG_EF_OFFSETS, INDEX_FLAG_FIXED_VALUES, INDEX_FLAG_KEYS_ONLY, INDEX_FLAG_SHARED_VALUES,
+    INDEX_FLAG_SORTED_KEYS, INDEX_FLAG_VARIABLE_KEYS, VALUE_CODEC_RAW,
+};
+use crate::writer::{build_mphf, encode_ef_offsets, write_single_file, write_two_files, IndexSections, ValuesHasher};
+use rayon::prelude::*;
+use std::collections::HashMap;
+use std::hash::{BuildHasher, Hasher, RandomState};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::SystemTime;
+use std::{
+    fs::{self, File},
+    io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write},
+    ops::{AddAssign, Range},
+};
+use xxhash_rust::xxh3::xxh3_128;
+
+// Default number of value bytes held in memory while reordering values
+pub const DEFAULT_BUFFER_SIZE: usize = 256 << 20;
+
+// Size of a record header in the scratch file: slot and value length
+const RECORD_HEADER_SIZE: usize = 16;
+
+// Memory a reorder window needs per slot besides its value: the record header
+// read back from the scratch file, and the slot's source and start positions
+const WINDOW_SLOT_OVERHEAD: usize = RECORD_HEADER_SIZE + 2 * std::mem::size_of::<usize>();
+
+// Slots per unit of work when keys and values are copied in parallel
+const PAR_CHUNK_SLOTS: usize = 4096;
+
+// Random id for the data header of a new build. `RandomState` keys are seeded
+// from the OS and differ on every call; the time guards against a platform
+// where they are not.
+fn new_build_id() -> u64 {
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u128(SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).map_or(0, |since| since.as_nanos()));
+    hasher.finish()
+}
+
+// Builds a database without holding its values in memory. Values are spilled
+// to a temporary file as they are inserted, and only the keys are kept in
+// memory, for the MPHF. `finish` then reorders the values into MPHF slot order
+// holding at most about `buffer_size` bytes of them in memory at a time.
+// Building the MPHF and copying keys and values into slot order run on
+// `WriteOptions::threads` threads.
+//
+// Temporary files are created in the spill directory and removed when the
+// builder is finished or dropped. Reordering more values than fit in the
+// buffer needs scratch space for a second copy of them.
+pub struct DatabaseBuilder<const N: usize = KEY_SIZE> {
+    options: WriteOptions,
+    buffer_size: usize,
+    spill_dir: PathBuf,
+    key_bytes: Vec<u8>,   // keys concatenated in insertion order
+    key_ends: Vec<u64>,   // end of each key in `key_bytes` (variable-length keys only)
+    value_ends: Vec<u64>, // end of each value in the spill file
+    spill: BufWriter<File>,
+    spill_file: TempFile,
+    keys_only: bool,      // write a static set: no value offsets and no values
+    value_codec: (u64, u64), // codec and type hash recorded in the data header
+}
+
+// Outcome of a build
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct BuildReport {
+    // Keys stored in the database
+    pub num_keys: usize,
+    // Keys that were inserted more than once
+    pub duplicate_keys: usize,
+    // Insertions resolved by `WriteOptions::duplicates`: every insertion of a
+    // duplicate key beyond the first
+    pub collisions: usize,
+}
+
+impl AddAssign for BuildReport {
+    fn add_assign(&mut self, other: Self) {
+        self.num_keys += other.num_keys;
+        self.duplicate_keys += other.duplicate_keys;
+        self.collisions += other.collisions;
+    }
+}
+
+// A file removed when dropped
+pub(crate) struct TempFile {
+    path: PathBuf,
+}
+
+impl TempFile {
+    // Creates a new, uniquely named file in `dir`, open for reading and writing
+    pub(crate) fn create_in(dir: &Path, tag: &str) -> io::Result<(Self, File)> {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+        loop {
+            let name = format!(".kvfast-{}-{}-{}", tag, std::process::id(), COUNTER.fetch_add(1, Ordering::Relaxed));
+            let path = dir.join(name);
+            match fs::OpenOptions::new().read(true).write(true).create_new(true).open(&path) {
+                Ok(file) => return Ok((TempFile { path }, file)),
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+impl TempFile {
+    // Renames the file to `path`, replacing any file there; it is then no
+    // longer removed on drop
+    pub(crate) fn persist(mut self, path: &Path) -> io::Result<()> {
+        fs::rename(&self.path, path)?;
+        self.path = PathBuf::new();
+        Ok(())
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        if !self.path.as_os_str().is_empty() {
+            let _ = fs::remove_file(&self.path);
+        }
+    }
+}
+
+impl<const N: usize> Database<N> {
+    // Starts a `DatabaseBuilder` for this key width
+    pub fn builder<P: AsRef<Path>>(spill_dir: P, options: WriteOptions) -> Result<DatabaseBuilder<N>> {
+        DatabaseBuilder::new(spill_dir, options)
+    }
+}
+
+impl<const N: usize> DatabaseBuilder<N> {
+    // Creates a builder spilling values to a temporary file in `spill_dir`
+    pub fn new<P: AsRef<Path>>(spill_dir: P, options: WriteOptions) -> Result<Self> {
+        let key_check = options.key_check;
+        if KeyCheck::from_code(key_check.to_code()) != Some(key_check) {
+            return Err(Error::InvalidArgument(format!(
+                "Unsupported key check {:?}: fingerprints must be 8, 16 or 32 bits",
+                key_check
+            )));
+        }
+        if options.sorted_keys && key_check != KeyCheck::Keys {
+            return Err(Error::InvalidArgument("A sorted key index needs KeyCheck::Keys".to_string()));
+        }
+        if options.dedup_values && (options.compress_offsets || matches!(options.value_width, ValueWidth::Fixed(_))) {
+            return Err(Error::InvalidArgument(
+                "Deduplicated values cannot have compressed offsets or a fixed width".to_string(),
+            ));
+        }
+        if options.value_block_size == Some(0) {
+            return Err(Error::InvalidArgument("Value block size must be positive".to_string()));
+        }
+
+        let spill_dir = spill_dir.as_ref().to_path_buf();
+        let (spill_file, spill) = TempFile::create_in(&spill_dir, "spill")?;
+        Ok(DatabaseBuilder {
+            options,
+            buffer_size: DEFAULT_BUFFER_SIZE,
+            spill_dir,
+            key_bytes: Vec::new(),
+            key_ends: Vec::new(),
+            value_ends: Vec::new(),
+            spill: BufWriter::new(spill),
+            spill_file,
+            keys_only: false,
+            value_codec: (VALUE_CODEC_RAW, 0),
+        })
+    }
+
+    // Makes `finish_single` write a static set (see `StaticSet`), dropping the values
+    pub(crate) fn keys_only(mut self) -> Self {
+        self.keys_only = true;
+        self
+    }
+
+    // Records that the values are encoded with `codec` from the type hashed to
+    // `value_type` (see `TypedBuilder`)
+    pub(crate) fn value_codec(mut self, codec: u64, value_type: u64) -> Self {
+        self.value_codec = (codec, value_type);
+        self
+    }
+
+    // Sets how many bytes `finish` may hold in memory while reordering values:
+    // the values themselves plus a few dozen bytes of bookkeeping per value
+    pub fn with_buffer_size(mut self, buffer_size: usize) -> Self {
+        self.buffer_size = buffer_size.max(1);
+        self
+    }
+
+    // Adds a key and its value. The value is written to the spill file right away.
+    pub fn insert(&mut self, key: &[u8], value: &[u8]) -> Result<()> {
+        if N != VAR_KEY_SIZE && key.len() != N {
+            return Err(Error::InvalidKeyLength { expected: N, got: key.len() });
+        }
+        if let ValueWidth::Fixed(width) = self.options.value_width
+            && value.len() != width
+            && !self.keys_only
+        {
+            return Err(Error::InvalidValueLength { expected: width, got: value.len() });
+        }
+
+        self.spill.write_all(value)?;
+        self.value_ends.push(self.value_ends.last().copied().unwrap_or(0) + value.len() as u64);
+        self.key_bytes.extend_from_slice(key);
+        if N == VAR_KEY_SIZE {
+            self.key_ends.push(self.key_bytes.len() as u64);
+        }
+        Ok(())
+    }
+
+    // Number of keys inserted so far
+    pub fn len(&self) -> usize {
+        self.value_ends.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // Builds the MPHF and writes a two-file database, opened with `Database::open`
+    pub fn finish<P: AsRef<Path>>(mut self, path_data: P, path_index: P, version: u32) -> Result<BuildReport> {
+        let (path_data, path_index) = (path_data.as_ref(), path_index.as_ref());
+        self.thread_pool()?.install(|| {
+            let (live, report) = self.resolve_duplicates()?;
+            let value_width = self.stored_value_width(&live)?;
+            let (sections, stored) = self.index_sections(&live, value_width)?;
+            let header = self.data_header(version, DabaHeader::SIZE, sections.num_keys as usize, value_width);
+            let values_size = self.slot_values_size(&stored);
+            write_two_files(path_data, path_index, sections, header, values_size, |out| {
+                self.write_values(&stored, out)
+            })?;
+            Ok(report)
+        })
+    }
+
+    // Builds the MPHF and writes a single-file database, opened with `Database::open_single`
+    pub fn finish_single<P: AsRef<Path>>(mut self, path: P, version: u32) -> Result<BuildReport> {
+        let path = path.as_ref();
+        self.thread_pool()?.install(|| {
+            let (live, report) = self.resolve_duplicates()?;
+            let value_width = self.stored_value_width(&live)?;
+            let (sections, stored) = self.index_sections(&live, value_width)?;
+            let header = self.data_header(version, 0, sections.num_keys as usize, value_width);
+            let values_size = if self.keys_only { 0 } else { self.slot_values_size(&stored) };
+            write_single_file(path, sections, header, values_size, |out| self.write_values(&stored, out))?;
+            Ok(report)
+        })
+    }
+
+    // Pool running the build, with `WriteOptions::threads` threads
+    fn thread_pool(&self) -> Result<rayon::ThreadPool> {
+        rayon::ThreadPoolBuilder::new()
+            .num_threads(self.options.threads.unwrap_or(0))
+            .build()
+            .map_err(|e| Error::Io(io::Error::other(e)))
+    }
+
+    // Bytes of values in the spill file, including those of dropped duplicates
+    fn spilled_size(&self) -> u64 {
+        self.value_ends.last().copied().unwrap_or(0)
+    }
+
+    // Bytes of the values stored in the database
+    fn slot_values_size(&self, stored: &[usize]) -> u64 {
+        stored.par_iter().map(|&original_idx| self.value_len(original_idx) as u64).sum()
+    }
+
+    fn data_header(
+        &self,
+        version: u32,
+        values_start: usize,
+        num_keys: usize,
+        value_width: Option<usize>,
+    ) -> DabaHeader {
+        DabaHeader {
+            magic: *b"DABA",
+            version,
+            num_keys: num_keys as u64,
+            key_size: N as u64,
+            values_start,
+            value_width: value_width.unwrap_or(0) as u64,
+            value_codec: self.value_codec.0,
+            value_type: self.value_codec.1,
+            build_id: new_build_id(),
+        }
+    }
+
+    // Width of every value stored at the `live` positions, if they are to be
+    // stored without offsets (see `WriteOptions::value_width`). Merged values
+    // are checked here, since they bypass `insert`'s check.
+    fn stored_value_width(&self, live: &[usize]) -> Result<Option<usize>> {
+        if self.keys_only || self.options.dedup_values {
+            return Ok(None);
+        }
+        let mut lens = live.iter().map(|&idx| self.value_len(idx));
+        match self.options.value_width {
+            ValueWidth::Variable => Ok(None),
+            ValueWidth::Fixed(width) => match lens.find(|&len| len != width) {
+                Some(got) => Err(Error::InvalidValueLength { expected: width, got }),
+                None => Ok(Some(width)),
+            },
+            ValueWidth::Detect => {
+                let first = lens.next();
+                Ok(first.filter(|&width| lens.all(|len| len == width)))
+            }
+        }
+    }
+
+    // Key inserted at position `idx`
+    fn key(&self, idx: usize) -> &[u8] {
+        if N == VAR_KEY_SIZE {
+            let start = if idx == 0 { 0 } else { self.key_ends[idx - 1] as usize };
+            &self.key_bytes[start..self.key_ends[idx] as usize]
+        } else {
+            &self.key_bytes[idx * N..(idx + 1) * N]
+        }
+    }
+
+    // Byte range of the value inserted at position `idx` in the spill file
+    fn value_range(&self, idx: usize) -> Range<u64> {
+        let start = if idx == 0 { 0 } else { self.value_ends[idx - 1] };
+        start..self.value_ends[idx]
+    }
+
+    // Value inserted at position `idx`, read back from the spill file
+    fn read_value(&mut self, idx: usize) -> io::Result<Vec<u8>> {
+        let range = self.value_range(idx);
+        self.spill.flush()?;
+        let mut spill = self.spill.get_ref();
+        spill.seek(SeekFrom::Start(range.start))?;
+        let mut value = vec![0u8; (range.end - range.start) as usize];
+        spill.read_exact(&mut value)?;
+        // Later inserts append at the file position
+        spill.seek(SeekFrom::End(0))?;
+        Ok(value)
+    }
+
+    // Finds the keys inserted more than once and resolves them according to
+    // `WriteOptions::duplicates`. Merged values are inserted again as new
+    // entries. Returns the insertion positions to store, in insertion order.
+    fn resolve_duplicates(&mut self) -> Result<(Vec<usize>, BuildReport)> {
+        let mut order: Vec<usize> = (0..self.len()).collect();
+        order.par_sort_unstable_by(|&a, &b| self.key(a).cmp(self.key(b)).then(a.cmp(&b)));
+        // Runs of positions holding the same key, each in insertion order
+        let runs: Vec<Vec<usize>> = order
+            .chunk_by(|&a, &b| self.key(a) == self.key(b))
+            .filter(|run| run.len() > 1)
+            .map(|run| run.to_vec())
+            .collect();
+        drop(order);
+
+        let mut report = BuildReport {
+            num_keys: 0,
+            duplicate_keys: runs.len(),
+            collisions: runs.iter().map(|run| run.len() - 1).sum(),
+        };
+        let mut dropped = vec![false; self.len()];
+        for run in &runs {
+            let kept = match &self.options.duplicates {
+                DuplicatePolicy::Error => return Err(Error::DuplicateKey { key: self.key(run[0]).to_vec() }),
+                DuplicatePolicy::KeepFirst => Some(run[0]),
+                DuplicatePolicy::KeepLast => run.last().copied(),
+                DuplicatePolicy::Merge(_) => None,
+            };
+            for &idx in run {
+                dropped[idx] = Some(idx) != kept;
+            }
+        }
+        if let DuplicatePolicy::Merge(merge) = self.options.duplicates.clone() {
+            for run in &runs {
+                let key = self.key(run[0]).to_vec();
+                let mut value = self.read_value(run[0])?;
+                for &idx in &run[1..] {
+                    value = merge(&key, &value, &self.read_value(idx)?);
+                }
+                self.insert(&key, &value)?;
+            }
+        }
+        dropped.resize(self.len(), false);
+
+        let live: Vec<usize> = (0..self.len()).filter(|&idx| !dropped[idx]).collect();
+        report.num_keys = live.len();
+        Ok((live, report))
+    }
+
+    // Builds the MPHF over the keys inserted at the `live` positions and the
+    // index sections, and returns them with the insertion positions of the
+    // values to store, in order: the key mapped to every MPHF slot, or with
+    // `dedup_values` the first slot holding each distinct value. The keys are
+    // released afterwards.
+    fn index_sections(&mut self, live: &[usize], value_width: Option<usize>) -> Result<(IndexSections, Vec<usize>)> {
+        let key_check = self.options.key_check;
+
+        // Fixed-width keys use the fast path; VAR_KEY_SIZE stores a key heap and hashes key digests
+        let variable_keys = N == VAR_KEY_SIZE;
+
+        // Build PtrHash with default parameters, then create mapping from MPHF index to original index
+        let (mphf, mut mphf_to_original) = if variable_keys {
+            let digests: Vec<Key<DIGEST_SIZE>> = live.par_iter().map(|&idx| key_digest(self.key(idx))).collect();
+            build_mphf(&digests)?
+        } else if live.len() == self.len() {
+            build_mphf(self.key_bytes.as_chunks::<N>().0)?
+        } else {
+            let keys = self.key_bytes.as_chunks::<N>().0;
+            let keys: Vec<Key<N>> = live.par_iter().map(|&idx| keys[idx]).collect();
+            build_mphf(&keys)?
+        };
+        mphf_to_original.par_iter_mut().for_each(|idx| *idx = live[*idx]);
+
+        // Keys (or key offsets into the key heap, or fingerprints) in MPHF order
+        let key_heap_stored = variable_keys && key_check == KeyCheck::Keys;
+        let mut keys = Vec::new();
+        let mut key_heap = Vec::new();
+        if let Some(width) = key_check.fingerprint_width() {
+            keys = vec![0u8; mphf_to_original.len() * width];
+            keys.par_chunks_mut(width).zip(&mphf_to_original).for_each(|(stored, &original_idx)| {
+                stored.copy_from_slice(&fingerprint(self.key(original_idx)).to_le_bytes()[..width]);
+            });
+        } else if key_heap_stored {
+            let key_starts = prefix_sums(mphf_to_original.par_iter().map(|&original_idx| self.key(original_idx).len()));
+            keys = key_starts.iter().flat_map(|&start| (start as u64).to_le_bytes()).collect();
+            key_heap = vec![0u8; key_starts.last().copied().unwrap_or(0)];
+            par_gather(&mut key_heap, &key_starts, |i| self.key(mphf_to_original[i]));
+        } else if key_check == KeyCheck::Keys {
+            keys = vec![0u8; mphf_to_original.len() * N];
+            keys.par_chunks_mut(N).zip(&mphf_to_original).for_each(|(stored, &original_idx)| {
+                stored.copy_from_slice(self.key(original_idx));
+            });
+        }
+
+        // Slots in key order
+        let mut sorted_keys = Vec::new();
+        if self.options.sorted_keys {
+            let mut sorted: Vec<usize> = (0..mphf_to_original.len()).collect();
+            sorted.par_sort_unstable_by(|&a, &b| self.key(mphf_to_original[a]).cmp(self.key(mphf_to_original[b])));
+            sorted_keys = sorted.par_iter().flat_map_iter(|&slot| (slot as u64).to_le_bytes()).collect();
+        }
+        self.key_bytes = Vec::new();
+        self.key_ends = Vec::new();
+
+        // Value offsets in MPHF order, unless values have a fixed width
+        let mut offsets = Vec::new();
+        let mut ef_offsets = false;
+        let mut distinct = None;
+        if !self.keys_only && value_width.is_none() && self.options.dedup_values {
+            let (distinct_values, slot_values) = self.distinct_values(&mphf_to_original, xxh3_128)?;
+            let value_starts = prefix_sums(distinct_values.par_iter().map(|&idx| self.value_len(idx)));
+            offsets = slot_values
+                .par_iter()
+                .zip(&mphf_to_original)
+                .flat_map_iter(|(&value, &original_idx)| {
+                    let len = self.value_len(original_idx) as u64;
+                    (value_starts[value] as u64).to_le_bytes().into_iter().chain(len.to_le_bytes())
+                })
+                .collect();
+            distinct = Some(distinct_values);
+        } else if !self.keys_only && value_width.is_none() {
+            let value_starts =
+                prefix_sums(mphf_to_original.par_iter().map(|&original_idx| self.value_len(original_idx)));
+            let (starts, values_size) = value_starts.split_at(mphf_to_original.len());
+            if self.options.compress_offsets
+                && let Some(encoded) = encode_ef_offsets(starts, values_size[0])?
+            {
+                offsets = encoded;
+                ef_offsets = true;
+            } else {
+                offsets = starts.par_iter().flat_map_iter(|&start| (start as u64).to_le_bytes()).collect();
+            }
+        }
+
+        let sections = IndexSections {
+            num_keys: mphf_to_original.len() as u64,
+            flags: if variable_keys { INDEX_FLAG_VARIABLE_KEYS } else { 0 }
+                | if self.options.sorted_keys { INDEX_FLAG_SORTED_KEYS } else { 0 }
+                | if self.keys_only { INDEX_FLAG_KEYS_ONLY } else { 0 }
+                | if value_width.is_some() { INDEX_FLAG_FIXED_VALUES } else { 0 }
+                | if ef_offsets { INDEX_FLAG_EF_OFFSETS } else { 0 }
+                | if distinct.is_some() { INDEX_FLAG_SHARED_VALUES } else { 0 },
+            key_check,
+            mphf,
+            keys,
+            key_heap,
+            offsets,
+            value_block_size: self.options.value_block_size.unwrap_or(0),
+            value_checksums: Vec::new(),
+            values_checksum: 0,
+            sorted_keys,
+        };
+        Ok((sections, distinct.unwrap_or(mphf_to_original)))
+    }
+
+    // Reads the spilled values of the MPHF slots once, in insertion order, and
+    // returns the insertion positions of the distinct ones, in order of their
+    // first slot, along with the index among them of every slot's value. Each
+    // value is compared byte for byte with the distinct values seen so far
+    // that have its length and `hash`, so values that only collide are kept
+    // apart. Their bytes are kept in memory within half of `buffer_size`;
+    // beyond that, a match is checked by reading the earlier value back.
+    fn distinct_values(
+        &mut self,
+        mphf_to_original: &[usize],
+        hash: fn(&[u8]) -> u128,
+    ) -> io::Result<(Vec<usize>, Vec<usize>)> {
+        // Distinct value of every insertion position; values of dropped duplicates are skipped
+        let mut value_of = vec![usize::MAX; self.len()];
+        for &original_idx in mphf_to_original {
+            value_of[original_idx] = 0;
+        }
+
+        self.spill.flush()?;
+        let mut spill = self.spill.get_ref();
+        spill.seek(SeekFrom::Start(0))?;
+        let mut reader = BufReader::new(spill);
+        let mut earlier_values = File::open(&self.spill_file.path)?;
+        let cache_budget = self.buffer_size / 2;
+        let mut cached_bytes = 0;
+
+        // First holder of every distinct value, with its bytes while they fit
+        let mut representatives: Vec<(usize, Option<Vec<u8>>)> = Vec::new();
+        let mut candidates: HashMap<(u128, usize), Vec<usize>> = HashMap::new();
+        let mut value = Vec::new();
+        let mut earlier = Vec::new();
+        for (original_idx, value_id) in value_of.iter_mut().enumerate() {
+            let len = self.value_len(original_idx);
+            if *value_id == usize::MAX {
+                reader.seek_relative(len as i64)?;
+                continue;
+            }
+            value.resize(len, 0);
+            reader.read_exact(&mut value)?;
+
+            let ids = candidates.entry((hash(&value), len)).or_default();
+            let mut found = None;
+            for &id in ids.iter() {
+                let equal = match &representatives[id] {
+                    (_, Some(bytes)) => *bytes == value,
+                    (holder, None) => {
+                        earlier_values.seek(SeekFrom::Start(self.value_range(*holder).start))?;
+                        earlier.resize(len, 0);
+                        earlier_values.read_exact(&mut earlier)?;
+                        earlier == value
+                    }
+                };
+                if equal {
+                    found = Some(id);
+                    break;
+                }
+            }
+            *value_id = match found {
+                Some(id) => id,
+                None => {
+                    let cached = (cached_bytes + len <= cache_budget).then(|| value.clone());
+                    cached_bytes += cached.as_ref().map_or(0, Vec::len);
+                    representatives.push((original_idx, cached));
+                    ids.push(representatives.len() - 1);
+                    representatives.len() - 1
+                }
+            };
+        }
+        spill.seek(SeekFrom::End(0))?;
+
+        // Number the distinct values in order of their first slot
+        let mut distinct_of = vec![usize::MAX; representatives.len()];
+        let mut distinct = Vec::new();
+        let slot_values = mphf_to_original
+            .iter()
+            .map(|&original_idx| {
+                let id = value_of[original_idx];
+                if distinct_of[id] == usize::MAX {
+                    distinct_of[id] = distinct.len();
+                    distinct.push(representatives[id].0);
+                }
+                distinct_of[id]
+            })
+            .collect();
+        Ok((distinct, slot_values))
+    }
+
+    // Length of the value inserted at position `idx`
+    fn value_len(&self, idx: usize) -> usize {
+        let range = self.value_range(idx);
+        (range.end - range.start) as usize
+    }
+
+    // Cuts the slots into windows: (first slot, bytes of values). A window
+    // holds at most `window_size` bytes of values plus `WINDOW_SLOT_OVERHEAD`
+    // per slot, so runs of small or empty values are cut too, or a single
+    // larger value.
+    fn value_windows(&self, mphf_to_original: &[usize], window_size: usize) -> Vec<(usize, usize)> {
+        let mut windows: Vec<(usize, usize)> = Vec::new();
+        let mut window_cost = 0;
+        for (slot, &original_idx) in mphf_to_original.iter().enumerate() {
+            let len = self.value_len(original_idx);
+            let cost = len + WINDOW_SLOT_OVERHEAD;
+            match windows.last_mut() {
+                Some((_, bytes)) if window_cost + cost <= window_size => {
+                    *bytes += len;
+                    window_cost += cost;
+                }
+                _ => {
+                    windows.push((slot, len));
+                    window_cost = cost;
+                }
+            }
+        }
+        windows
+    }
+
+    // Copies the spilled values to `out` in MPHF slot order (or the distinct
+    // ones, with `dedup_values`) and returns their checksums. Slots are cut into
+    // windows of at most half of `buffer_size` (see `value_windows`), and each
+    // window is gathered into a buffer in parallel from an in-memory copy of its
+    // values. If there is only one window and the whole spill file, dropped
+    // values included, fits in the other half, that copy is the spill file;
+    // otherwise one sequential pass first distributes the values to a region
+    // per window of a scratch file, which is then read back one at a time.
+    fn write_values<W: Write>(&mut self, mphf_to_original: &[usize], out: &mut W) -> io::Result<(u64, Vec<u8>)> {
+        let mut hasher = ValuesHasher::new(self.options.value_block_size.unwrap_or(0));
+        if self.keys_only {
+            return Ok(hasher.finish());
+        }
+        self.spill.flush()?;
+        let mut spill = self.spill.get_ref();
+        spill.seek(SeekFrom::Start(0))?;
+
+        let window_size = (self.buffer_size / 2).max(1);
+        let windows = self.value_windows(mphf_to_original, window_size);
+        let window_end = |w: usize| windows.get(w + 1).map_or(mphf_to_original.len(), |&(start, _)| start);
+
+        if windows.is_empty() || (windows.len() == 1 && self.spilled_size() <= window_size as u64) {
+            let mut spilled = Vec::with_capacity(self.spilled_size() as usize);
+            spill.read_to_end(&mut spilled)?;
+            let starts = prefix_sums(mphf_to_original.par_iter().map(|&original_idx| self.value_len(original_idx)));
+            let mut buffer = vec![0u8; starts.last().copied().unwrap_or(0)];
+            par_gather(&mut buffer, &starts, |i| {
+                let range = self.value_range(mphf_to_original[i]);
+                &spilled[range.start as usize..range.end as usize]
+            });
+            out.write_all(&buffer)?;
+            hasher.update(&buffer);
+            return Ok(hasher.finish());
+        }
+
+        // Values of dropped duplicates have no slot and are skipped
+        let mut slot_of = vec![usize::MAX; self.len()];
+        for (slot, &original_idx) in mphf_to_original.iter().enumerate() {
+            slot_of[original_idx] = slot;
+        }
+
+        // Regions of the scratch file: the values of a window plus a record header per value
+        let mut region_starts = Vec::with_capacity(windows.len() + 1);
+        let mut region_cursor = 0u64;
+        for (w, &(first_slot, bytes)) in windows.iter().enumerate() {
+            region_starts.push(region_cursor);
+            region_cursor += (bytes + (window_end(w) - first_slot) * RECORD_HEADER_SIZE) as u64;
+        }
+        region_starts.push(region_cursor);
+
+        // Distribute the values to their window's region, buffering a little per window
+        let (_scratch_file, mut scratch) = TempFile::create_in(&self.spill_dir, "reorder")?;
+        let pending_limit = (self.buffer_size / windows.len()).max(4096);
+        let mut pending: Vec<Vec<u8>> = vec![Vec::new(); windows.len()];
+        let mut region_cursors = region_starts.clone();
+        let mut reader = BufReader::new(spill);
+        let mut value = Vec::new();
+        for (original_idx, &slot) in slot_of.iter().enumerate() {
+            if slot == usize::MAX {
+                reader.seek_relative(self.value_len(original_idx) as i64)?;
+                continue;
+            }
+            value.resize(self.value_len(original_idx), 0);
+            reader.read_exact(&mut value)?;
+
+            let w = windows.partition_point(|&(first_slot, _)| first_slot <= slot) - 1;
+            let records = &mut pending[w];
+            records.extend_from_slice(&(slot as u64).to_le_bytes());
+            records.extend_from_slice(&(value.len() as u64).to_le_bytes());
+            records.extend_from_slice(&value);
+            if records.len() >= pending_limit {
+                scratch.seek(SeekFrom::Start(region_cursors[w]))?;
+                scratch.write_all(records)?;
+                region_cursors[w] += records.len() as u64;
+                records.clear();
+            }
+        }
+        for (w, records) in pending.iter().enumerate() {
+            scratch.seek(SeekFrom::Start(region_cursors[w]))?;
+            scratch.write_all(records)?;
+        }
+        drop(pending);
+        drop(slot_of);
+
+        // Gather each window in slot order and write it out
+        for (w, &(first_slot, bytes)) in windows.iter().enumerate() {
+            let slots = first_slot..window_end(w);
+            let mut region = vec![0u8; (region_starts[w + 1] - region_starts[w]) as usize];
+            scratch.seek(SeekFrom::Start(region_starts[w]))?;
+            scratch.read_exact(&mut region)?;
+
+            // Find where each slot's value is in the region
+            let mut sources = vec![0usize; slots.len()];
+            let mut position = 0;
+            while position < region.len() {
+                let record_header = &region[position..position + RECORD_HEADER_SIZE];
+                let slot = u64::from_le_bytes(record_header[..8].try_into().unwrap_or_default()) as usize;
+                let len = u64::from_le_bytes(record_header[8..].try_into().unwrap_or_default()) as usize;
+                sources[slot - slots.start] = position + RECORD_HEADER_SIZE;
+                position += RECORD_HEADER_SIZE + len;
+            }
+
+            let starts = prefix_sums(mphf_to_original[slots].par_iter().map(|&original_idx| self.value_len(original_idx)));
+            let mut buffer = vec![0u8; bytes];
+            par_gather(&mut buffer, &starts, |i| &region[sources[i]..sources[i] + starts[i + 1] - starts[i]]);
+            out.write_all(&buffer)?;
+            hasher.update(&buffer);
+        }
+
+        Ok(hasher.finish())
+    }
+}
+
+// Start of every item when items of the given lengths are laid out back to
+// back, followed by the total length
+fn prefix_sums(lens: impl IndexedParallelIterator<Item = usize>) -> Vec<usize> {
+    let lens: Vec<usize> = lens.collect();
+    let mut starts = Vec::with_capacity(lens.len() + 1);
+    let mut cursor = 0;
+    starts.push(cursor);
+    for len in lens {
+        cursor += len;
+        starts.push(cursor);
+    }
+    starts
+}
+
+// Fills `out` with `source(i)` at `starts[i]..starts[i + 1]` for every item,
+// splitting the copy into chunks of items spread over the thread pool
+fn par_gather<'a>(out: &mut [u8], starts: &[usize], source: impl Fn(usize) -> &'a [u8] + Sync) {
+    let num_items = starts.len() - 1;
+    let mut chunks = Vec::with_capacity(num_items.div_ceil(PAR_CHUNK_SLOTS));
+    let mut rest = out;
+    for first in (0..num_items).step_by(PAR_CHUNK_SLOTS) {
+        let end = (first + PAR_CHUNK_SLOTS).min(num_items);
+        let (chunk, tail) = std::mem::take(&mut rest).split_at_mut(starts[end] - starts[first]);
+        rest = tail;
+        chunks.push((first..end, chunk));
+    }
+
+    chunks.into_par_iter().for_each(|(items, chunk)| {
+        let base = starts[items.start];
+        for i in items {
+            chunk[starts[i] - base..starts[i + 1] - base].copy_from_slice(source(i));
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{BuildReport, DatabaseBuilder, DEFAULT_BUFFER_SIZE};
+    use crate::database::{Database, DuplicatePolicy, VarDatabase, WriteOptions, KEY_SIZE, VAR_KEY_SIZE};
+    use crate::error::{Error, Result};
+    use std::io;
+    use std::sync::Arc;
+    use tempfile::{NamedTempFile, TempDir};
+
+    #[test]
+    fn test_builder_reorders_with_a_small_buffer() -> io::Result<()> {
+        let dir = TempDir::new()?;
+        let data_path = dir.path().join("db.data");
+        let index_path = dir.path().join("db.index");
+
+        // A 1 KiB buffer forces many reorder windows, including values larger than the buffer
+        let keys: Vec<Vec<u8>> = (0..2000u32).map(|i| format!("key-{}", i).into_bytes()).collect();
+        let values: Vec<Vec<u8>> = (0..2000usize).map(|i| vec![i as u8; if i % 500 == 0 { 3000 } else { i % 37 }]).collect();
+        let options = WriteOptions { value_block_size: Some(512), ..Default::default() };
+        let mut builder = VarDatabase::builder(dir.path(), options)?.with_buffer_size(1024);
+        for (key, value) in keys.iter().zip(&values) {
+            builder.insert(key, value)?;
+        }
+        assert_eq!(builder.len(), 2000);
+        builder.finish(&data_path, &index_path, 1)?;
+
+        let db = VarDatabase::open(&data_path, &index_path)?;
+        for (key, value) in keys.iter().zip(&values) {
+            assert_eq!(db.get(key), Some(value.as_slice()));
+        }
+        assert!(db.verify().is_empty());
+
+        // Only the database files are left behind
+        let mut names: Vec<_> = std::fs::read_dir(dir.path())?.map(|e| e.map(|e| e.file_name())).collect::<Result<_, _>>()?;
+        names.sort();
+        assert_eq!(names, ["db.data", "db.index"]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_reorder_windows_bound_empty_values() -> io::Result<()> {
+        let dir = TempDir::new()?;
+        let path = dir.path().join("db.kvdb");
+
+        // Mostly empty values: windows must close on the per-slot overhead alone
+        let keys: Vec<[u8; KEY_SIZE]> = (0..5000u128).map(|i| i.to_le_bytes()).collect();
+        let values: Vec<Vec<u8>> = (0..5000usize).map(|i| vec![1; usize::from(i % 100 == 0) * 10]).collect();
+        let mut builder = Database::<KEY_SIZE>::builder(dir.path(), WriteOptions::default())?.with_buffer_size(512);
+        for (key, value) in keys.iter().zip(&values) {
+            builder.insert(key, value)?;
+        }
+
+        let window_size = 256;
+        let stored: Vec<usize> = (0..builder.len()).collect();
+        let windows = builder.value_windows(&stored, window_size);
+        let window_ends = windows.iter().skip(1).map(|&(start, _)| start).chain([stored.len()]);
+        for (&(start, bytes), end) in windows.iter().zip(window_ends) {
+            assert!(end - start == 1 || bytes + (end - start) * super::WINDOW_SLOT_OVERHEAD <= window_size);
+        }
+        assert!(windows.len() >= 5000 * super::WINDOW_SLOT_OVERHEAD / window_size);
+
+        builder.finish_single(&path, 1)?;
+        let db = Database::<KEY_SIZE>::open_single(&path)?;
+        let expected: Vec<Option<&[u8]>> = values.iter().map(|v| Some(v.as_slice())).collect();
+        assert_eq!(db.get_many(&keys), expected);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_dedup_compares_values_with_equal_hashes() -> io::Result<()> {
+        let dir = TempDir::new()?;
+        let values: [&[u8]; 6] = [b"red", b"blue", b"red", b"green", b"blue", b"pink"];
+        let mut builder = VarDatabase::builder(dir.path(), WriteOptions { dedup_values: true, ..Default::default() })?;
+        for (i, value) in values.iter().enumerate() {
+            builder.insert(format!("key-{}", i).as_bytes(), value)?;
+        }
+
+        // Every value hashes the same: only equal bytes may share a copy
+        let slots: Vec<usize> = (0..values.len()).collect();
+        let (distinct, slot_values) = builder.distinct_values(&slots, |_| 0)?;
+        assert_eq!(distinct, vec![0, 1, 3, 5]);
+        assert_eq!(slot_values, vec![0, 1, 0, 2, 1, 3]);
+
+        // Copies of a value that collides with an earlier one are shared too,
+        // whether the values are compared in memory or read back; distinct
+        // values are numbered in slot order
+        let slots = [5, 4, 3, 2, 1, 0, 6, 7];
+        builder.insert(b"key-6", b"pink")?;
+        builder.insert(b"key-7", b"pink")?;
+        for buffer_size in [DEFAULT_BUFFER_SIZE, 1] {
+            builder.buffer_size = buffer_size;
+            let (distinct, slot_values) = builder.distinct_values(&slots, |_| 0)?;
+            assert_eq!(distinct, vec![5, 1, 3, 0]);
+            assert_eq!(slot_values, vec![0, 1, 2, 3, 1, 3, 0, 0]);
+        }
+
+        let path = dir.path().join("db.kvdb");
+        builder.finish_single(&path, 1)?;
+        let db = VarDatabase::open_single(&path)?;
+        for (i, value) in values.iter().enumerate() {
+            assert_eq!(db.get(format!("key-{}", i).as_bytes()), Some(*value));
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_dedup_of_many_copies_stays_within_the_buffer() -> io::Result<()> {
+        let dir = TempDir::new()?;
+        let path = dir.path().join("db.kvdb");
+
+        // 20000 keys sharing three 1 KiB values: the distinct values fit in one
+        // window, the 20 MB of copies in the spill file do not
+        let shared: Vec<Vec<u8>> = (0..3u8).map(|i| vec![i; 1024]).collect();
+        let options = WriteOptions { dedup_values: true, ..Default::default() };
+        let mut builder = Database::<KEY_SIZE>::builder(dir.path(), options)?.with_buffer_size(64 * 1024);
+        let keys: Vec<[u8; KEY_SIZE]> = (0..20000u128).map(|i| i.to_le_bytes()).collect();
+        for (i, key) in keys.iter().enumerate() {
+            builder.insert(key, &shared[i % 3])?;
+        }
+        assert!(builder.spilled_size() > 64 * 1024 / 2);
+        builder.finish_single(&path, 1)?;
+
+        let db = Database::<KEY_SIZE>::open_single(&path)?;
+        assert!(std::fs::metadata(&path)?.len() < 1024 * 1024);
+        for (i, key) in keys.iter().enumerate() {
+            assert_eq!(db.get(key), Some(&shared[i % 3][..]));
+        }
+        assert!(db.verify().is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_dropped_duplicates_count_against_the_buffer() -> io::Result<()> {
+        let dir = TempDir::new()?;
+        let path = dir.path().join("db.kvdb");
+
+        // 100 keys written 50 times each: the live values fit in one window,
+        // but the spill file holding every copy is far larger than the buffer
+        let options = WriteOptions { duplicates: DuplicatePolicy::KeepLast, ..Default::default() };
+        let mut builder = VarDatabase::builder(dir.path(), options)?.with_buffer_size(64 * 1024);
+        for round in 0..50u8 {
+            for i in 0..100u32 {
+                builder.insert(format!("key-{}", i).as_bytes(), &[round; 100])?;
+            }
+        }
+        assert!(builder.spilled_size() > 64 * 1024 / 2);
+        builder.finish_single(&path, 1)?;
+
+        let db = VarDatabase::open_single(&path)?;
+        assert_eq!(db.len(), 100);
+        for i in 0..100u32 {
+            assert_eq!(db.get(format!("key-{}", i).as_bytes()), Some(&[49u8; 100][..]));
+        }
+        assert!(db.verify().is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_builder_single_file_and_key_validation() -> io::Result<()> {
+        let dir = TempDir::new()?;
+        let file = NamedTempFile::new_in(dir.path())?;
+
+        let mut builder = DatabaseBuilder::<KEY_SIZE>::new(dir.path(), WriteOptions::default())?.with_buffer_size(64);
+        for i in 0..300u128 {
+            builder.insert(&i.to_le_bytes(), &i.to_be_bytes())?;
+        }
+        let err = builder.insert(b"short", b"value").expect_err("Insert should fail");
+        assert!(matches!(err, Error::InvalidKeyLength { expected: KEY_SIZE, got: 5 }));
+        builder.finish_single(file.path(), 1)?;
+
+        let db = Database::<KEY_SIZE>::open_single(file.path())?;
+        for i in 0..300u128 {
+            assert_eq!(db.get(&i.to_le_bytes()), Some(&i.to_be_bytes()[..]));
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_duplicate_policies() -> io::Result<()> {
+        let dir = TempDir::new()?;
+        let file = NamedTempFile::new_in(dir.path())?;
+
+        // Every key is inserted once, and keys divisible by 10 twice more
+        let mut entries: Vec<(String, String)> = (0..500).map(|i| (format!("k{}", i), format!("a{}", i))).collect();
+        for round in ["b", "c"] {
+            entries.extend((0..500).step_by(10).map(|i| (format!("k{}", i), format!("{}{}", round, i))));
+        }
+        let build = |duplicates: DuplicatePolicy| -> Result<(BuildReport, VarDatabase)> {
+            let options = WriteOptions { duplicates, ..Default::default() };
+            let mut builder = DatabaseBuilder::<VAR_KEY_SIZE>::new(dir.path(), options)?.with_buffer_size(256);
+            for (key, value) in &entries {
+                builder.insert(key.as_bytes(), value.as_bytes())?;
+            }
+            let report = builder.finish_single(file.path(), 1)?;
+            Ok((report, VarDatabase::open_single(file.path())?))
+        };
+
+        let err = build(DuplicatePolicy::Error).err().expect("Build should fail");
+        assert!(matches!(err, Error::DuplicateKey { .. }));
+
+        let merge = |_: &[u8], kept: &[u8], next: &[u8]| [kept, b"+", next].concat();
+        for (policy, expected) in [
+            (DuplicatePolicy::KeepFirst, "a0"),
+            (DuplicatePolicy::KeepLast, "c0"),
+            (DuplicatePolicy::Merge(Arc::new(merge)), "a0+b0+c0"),
+        ] {
+            let (report, db) = build(policy)?;
+            assert_eq!(report, BuildReport { num_keys: 500, duplicate_keys: 50, collisions: 100 });
+            assert_eq!(db.len(), 500);
+            assert_eq!(db.get(b"k0"), Some(expected.as_bytes()));
+            assert_eq!(db.get(b"k1"), Some(&b"a1"[..]));
+            assert!(db.verify().is_empty());
+        }
+
+        // More keys than values, and more values than keys
+        let keys = [b"x", b"y", b"z"];
+        let options = WriteOptions::default();
+        let err = VarDatabase::write_database_single(file.path(), keys.iter(), keys[..2].iter(), 1, &options)
+            .expect_err("Write should fail");
+        assert!(matches!(err, Error::LengthMismatch { keys: 3, values: 2 }));
+        let err = VarDatabase::write_database_single(file.path(), keys[..1].iter(), keys.iter(), 1, &options)
+            .expect_err("Write should fail");
+        assert!(matches!(err, Error::LengthMismatch { keys: 1, values: 3 }));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_parallel_builds_with_any_thread_count() -> io::Result<()> {
+        let dir = TempDir::new()?;
+        let keys: Vec<Vec<u8>> = (0..20_000u32).map(|i| format!("key-{}", i).into_bytes()).collect();
+
+        // PtrHash may pick different pilots depending on the thread count, so
+        // compare lookups rather than bytes
+        for (threads, buffer_size) in [(1, DEFAULT_BUFFER_SIZE), (4, DEFAULT_BUFFER_SIZE), (4, 4096)] {
+            let path = dir.path().join(format!("db-{}-{}", threads, buffer_size));
+            let options = WriteOptions { threads: Some(threads), value_block_size: Some(1024), ..Default::default() };
+            let mut builder = VarDatabase::builder(dir.path(), options)?.with_buffer_size(buffer_size);
+            for key in &keys {
+                builder.insert(key, &key.repeat(3))?;
+            }
+            builder.finish_single(&path, 1)?;
+
+            let db = VarDatabase::open_single(&path)?;
+            for key in &keys {
+                assert_eq!(db.get(key), Some(&key.repeat(3)[..]));
+            }
+            assert!(db.verify().is_empty());
+        }
+
+        Ok(())
+    }
+}
@@ -1,247 +1,981 @@
-use crate::header::{DabaHeader, IndexHeader};
+use crate::header::{
+    parse_header, DabaHeader, FileHeader, IndexHeader, SectionEntry, SECTION_KEYS, SECTION_KEY_HEAP, SECTION_META, SECTION_MPHF,
+    SECTION_OFFSETS, SECTION_SORTED_KEYS, SECTION_VALUES, SECTION_VALUE_CHECKSUMS, SINGLE_FILE_VERSION,
+};
+use crate::error::{Error, Result};
+use crate::verify::section_corruptions;
+use crate::upgrade::is_baseline_index;
+use crate::writer::data_slot_path;
+use cacheline_ef::CachelineEfVec;
 use epserde::prelude::*;
 use memmap2::Mmap;
-use ptr_hash::{bucket_fn::CubicEps, PtrHash, PtrHashParams};
+use ptr_hash::{bucket_fn::CubicEps, PtrHash};
 use std::path::Path;
+use xxhash_rust::xxh3::{xxh3_128, xxh3_64, xxh3_64_with_seed};
 use std::{
+    borrow::Cow,
+    fmt,
     fs::File,
-    io::{self, Seek, SeekFrom, Write},
+    io,
+    ops::Range,
     sync::Arc,
 };
 
+// Default key width; `Database<N>` stores N-byte keys
 pub const KEY_SIZE: usize = 16;
-pub type Key = [u8; KEY_SIZE];
+pub type Key<const N: usize = KEY_SIZE> = [u8; N];
+
+// Key width of databases with variable-length keys (see `VarDatabase`)
+pub const VAR_KEY_SIZE: usize = 0;
+
+// Size of the digest the MPHF hashes in place of variable-length keys
+pub(crate) const DIGEST_SIZE: usize = 16;
 
 // Type alias for PtrHash with default type parameters
 // We only specify Key and BucketFn, letting the other parameters use defaults
-pub type KeyPtrHash = PtrHash<Key, CubicEps>;
+pub type KeyPtrHash<const N: usize = KEY_SIZE> = PtrHash<Key<N>, CubicEps>;
+
+// Version of the index file layout written by `write_database`
+pub const INDEX_VERSION: u32 = 14;
+
+// Alignment of the keys and offsets sections in the index file, so they can be
+// viewed in place as `&[Key]` and `&[u64]`
+pub(crate) const SECTION_ALIGN: u64 = 8;
+
+// Alignment of the MPHF section; epserde aligns fields relative to the start of
+// the buffer and checks the absolute address, and the cacheline-sized
+// Elias-Fano blocks inside `KeyPtrHash` need 64 bytes
+pub(crate) const MPHF_ALIGN: u64 = 64;
+
+// Offsets held by each 64-byte line of a `CachelineEfVec`
+pub(crate) const EF_VALUES_PER_LINE: usize = 44;
 
-pub struct Database {
+// Number of keys hashed and prefetched together by `get_many`
+const GET_MANY_BATCH: usize = 64;
+
+// Lookahead used by PtrHash when streaming a batch through the MPHF
+const PTR_HASH_STREAM: usize = 16;
+
+// ε-copy view of a `KeyPtrHash` borrowing from the index mapping
+type KeyPtrHashView<const N: usize> = <KeyPtrHash<N> as DeserializeInner>::DeserType<'static>;
+
+// ε-copy view of Elias-Fano encoded value offsets borrowing from the index mapping
+type EfOffsetsView = <CachelineEfVec as DeserializeInner>::DeserType<'static>;
+
+// A database with N-byte keys, or variable-length keys when N is `VAR_KEY_SIZE`.
+// The key width is recorded in `DabaHeader::key_size` and checked by `open`.
+pub struct Database<const N: usize = KEY_SIZE> {
     header: DabaHeader,
-    mmap_data: Arc<Mmap>,                   // mmap of data file (values)
-    offsets: Arc<Vec<u64>>,                 // offsets array in memory
-    keys: Arc<Vec<Key>>,                    // keys array for validation
-    mphf: MemCase<<KeyPtrHash as DeserializeInner>::DeserType<'static>>,  // minimal perfect hash of keys with epserde wrapper
+    pub(crate) index_header: IndexHeader,
+    // NOTE: `mphf` and `ef_offsets` borrow from `mmap_index` and must be declared (and thus dropped) before it
+    mphf: Mphf<N>,                          // minimal perfect hash of keys, ε-copy deserialized in place
+    ef_offsets: Option<EfOffsetsView>,      // value offsets, when Elias-Fano encoded
+    pub(crate) mmap_data: Arc<Mmap>,        // mmap of data file (values)
+    pub(crate) mmap_index: Arc<Mmap>,       // mmap of index file (MPHF, keys and offsets are read in place)
+    keys: KeyStorage,                       // how slots are checked against the queried key
+    value_width: Option<usize>,             // width of every value, when the index has no value offsets
+    pub(crate) sections: Sections,          // byte ranges of every section within the mmaps
+}
+
+// Sizes of the sections implied by the headers, derived once in `from_sections`
+struct SectionSizes {
+    keys: u64,
+    offsets: u64,
+    values: u64,
+    sorted_keys: u64,
+}
+
+// Byte ranges of the sections of a database, found through the headers of the
+// two-file layout or through the section directory of a single file. The
+// values range is within the data mapping, all others within the index mapping
+// (the same mapping for a single file).
+pub(crate) struct Sections {
+    pub(crate) mphf: Range<usize>,
+    pub(crate) keys: Range<usize>,
+    pub(crate) key_heap: Range<usize>,
+    pub(crate) offsets: Range<usize>,
+    pub(crate) value_checksums: Range<usize>,
+    pub(crate) sorted_keys: Range<usize>,
+    pub(crate) values: Range<usize>,
+}
+
+pub type VarDatabase = Database<VAR_KEY_SIZE>;
+
+// The MPHF hashes N-byte keys directly, or a digest of variable-length keys
+enum Mphf<const N: usize> {
+    Keys(KeyPtrHashView<N>),
+    Digests(KeyPtrHashView<DIGEST_SIZE>),
+}
+
+// What the index stores per slot to reject keys that are not in the database.
+// Byte ranges are within the index mmap.
+enum KeyStorage {
+    // Fast path: one N-byte key per slot
+    Fixed { keys: Range<usize> },
+    // Keys of any length concatenated in a heap, delimited by `num_keys + 1` offsets
+    Variable { offsets: Range<usize>, heap: Range<usize> },
+    // One fingerprint of `width` bytes per slot
+    Fingerprints { width: usize, fingerprints: Range<usize> },
+    // Nothing: every query resolves to some slot
+    Trusted,
+}
+
+// How `get` verifies that a queried key is actually in the database, chosen at
+// build time and recorded in `IndexHeader::key_check`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum KeyCheck {
+    // Store every key and compare it exactly: no false positives
+    #[default]
+    Keys,
+    // Store an 8-, 16- or 32-bit fingerprint per key. An absent key is wrongly
+    // reported present (returning another key's value) with probability 2^-bits,
+    // i.e. about 1/256, 1/65536 or 1/4.3e9.
+    Fingerprint { bits: u32 },
+    // Store nothing and verify nothing: every query returns some value, so it
+    // must only be used for keys known to be in the database
+    Trusted,
+}
+
+impl KeyCheck {
+    // Encoding in `IndexHeader::key_check`: 0 = keys, 1 = trusted, otherwise fingerprint bits
+    pub(crate) fn to_code(self) -> u64 {
+        match self {
+            KeyCheck::Keys => 0,
+            KeyCheck::Trusted => 1,
+            KeyCheck::Fingerprint { bits } => bits as u64,
+        }
+    }
+
+    pub(crate) fn from_code(code: u64) -> Option<Self> {
+        match code {
+            0 => Some(KeyCheck::Keys),
+            1 => Some(KeyCheck::Trusted),
+            8 | 16 | 32 => Some(KeyCheck::Fingerprint { bits: code as u32 }),
+            _ => None,
+        }
+    }
+
+    // Bytes stored per slot for fingerprints
+    pub(crate) fn fingerprint_width(self) -> Option<usize> {
+        match self {
+            KeyCheck::Fingerprint { bits } => Some(bits as usize / 8),
+            _ => None,
+        }
+    }
+
+    // Size of the keys section for `num_keys` keys of width `key_size`
+    // (`VAR_KEY_SIZE` for variable-length keys, whose bytes live in the key heap)
+    fn keys_section_size(self, key_size: usize, num_keys: u64) -> u64 {
+        match self {
+            KeyCheck::Keys if key_size == VAR_KEY_SIZE => num_keys.saturating_add(1).saturating_mul(8),
+            KeyCheck::Keys => num_keys.saturating_mul(key_size as u64),
+            KeyCheck::Fingerprint { bits } => num_keys.saturating_mul(bits as u64 / 8),
+            KeyCheck::Trusted => 0,
+        }
+    }
+}
+
+// How a build lays out values, recorded in `DabaHeader::value_width`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ValueWidth {
+    // Store fixed-width values when every stored value turns out to have the
+    // same length, and an offset per slot otherwise
+    #[default]
+    Detect,
+    // Always store an offset per slot
+    Variable,
+    // Every value must be exactly this many bytes; other lengths are rejected
+    // with `Error::InvalidValueLength`
+    Fixed(usize),
+}
+
+// Merges the values of a key inserted more than once: called with the key, the
+// value kept so far and the next value in insertion order
+pub type MergeFn = Arc<dyn Fn(&[u8], &[u8], &[u8]) -> Vec<u8> + Send + Sync>;
+
+// What a build does with a key inserted more than once
+#[derive(Clone, Default)]
+pub enum DuplicatePolicy {
+    // Fail with `Error::DuplicateKey`
+    #[default]
+    Error,
+    // Keep the value inserted first
+    KeepFirst,
+    // Keep the value inserted last
+    KeepLast,
+    // Store the values folded with the closure, in insertion order
+    Merge(MergeFn),
+}
+
+impl fmt::Debug for DuplicatePolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DuplicatePolicy::Error => write!(f, "Error"),
+            DuplicatePolicy::KeepFirst => write!(f, "KeepFirst"),
+            DuplicatePolicy::KeepLast => write!(f, "KeepLast"),
+            DuplicatePolicy::Merge(_) => write!(f, "Merge(..)"),
+        }
+    }
+}
+
+// Options for `Database::write_database_with_options` and `Database::write_database_single`
+#[derive(Debug, Clone, Default)]
+pub struct WriteOptions {
+    pub key_check: KeyCheck,
+    // Also checksum the values in blocks of this many bytes, so `verify` can
+    // narrow corruption down to the slots of one block
+    pub value_block_size: Option<u64>,
+    // Threads used to build the MPHF and lay out keys and values; all
+    // available cores when None
+    pub threads: Option<usize>,
+    // What to do with keys inserted more than once
+    pub duplicates: DuplicatePolicy,
+    // Also store the slots in key order, for `Database::range` and
+    // `Database::prefix`; costs 8 bytes per key and needs `KeyCheck::Keys`
+    pub sorted_keys: bool,
+    // Whether values are stored at a fixed width, without the 8-byte offset per slot
+    pub value_width: ValueWidth,
+    // Encode the value offsets with cacheline Elias-Fano: about 12 bits per key
+    // instead of 64, still read with a single cache miss. Needs every 44
+    // consecutive values to span at most 21504 bytes (about 488 bytes per
+    // value); plain offsets are stored otherwise, see `Database::has_compressed_offsets`.
+    pub compress_offsets: bool,
+    // Store each distinct value once, found by hashing the values and comparing
+    // the ones with equal hashes, and let every slot holding it point at that
+    // copy. Each slot then has a 16-byte (offset, length) pair instead of an
+    // offset, so it pays off when values repeat often; cannot be combined with
+    // `ValueWidth::Fixed` or `compress_offsets`. The build still reads every
+    // copy from the spill file, but holds no more of them in memory than
+    // `DatabaseBuilder::with_buffer_size` allows.
+    pub dedup_values: bool,
+}
+
+// Options for `Database::open_with_options` and `Database::open_single_with_options`
+#[derive(Debug, Clone, Default)]
+pub struct OpenOptions {
+    // Check every section checksum before returning, which reads the whole
+    // database; without it only the header checksum is checked
+    pub verify_on_open: bool,
+}
+
+/* Index file layout (all integers little-endian):
+
++------------------------------+
+| IndexHeader                  |
+| padding to MPHF_ALIGN        |
+| MPHF (mphf_size bytes)       |  <- mphf_offset
+| padding to SECTION_ALIGN     |
+| key 0 (N bytes)              |  <- keys_offset
+| key 1 (N bytes)              |
+| ...                          |
+| padding to SECTION_ALIGN     |
+| offset 0 (u64)               |  <- offsets_offset
+| offset 1 (u64)               |
+| ...                          |
+| key heap (if any)            |  <- key_heap_offset
+| padding to SECTION_ALIGN     |
+| value block checksums (u64)  |  <- value_checksums_offset
+| padding to SECTION_ALIGN     |
+| sorted slots (u64)           |  <- sorted_keys_offset
++------------------------------+
+
+Every section, the values in the data file and the two headers are covered by
+xxh3 checksums stored in the IndexHeader (see `Database::verify`). It also
+records the size of the values, so a data file of any other length fails to
+open without reading it.
+
+With INDEX_FLAG_VARIABLE_KEYS the keys section holds `num_keys + 1` u64 key
+offsets instead, and the key bytes follow the value offsets:
+
++------------------------------+
+| key offset 0 (u64)           |  <- keys_offset
+| ...                          |
+| key offset num_keys (u64)    |
+| offset 0 (u64)               |  <- offsets_offset
+| ...                          |
+| key bytes                    |  <- key_heap_offset
++------------------------------+
+
+With `KeyCheck::Fingerprint` the keys section holds one little-endian
+fingerprint per slot instead (and there is no key heap); with
+`KeyCheck::Trusted` it is empty.
+
+With INDEX_FLAG_EF_OFFSETS the offsets section holds the offsets as an
+epserde-serialized `CachelineEfVec`, aligned to MPHF_ALIGN like the MPHF.
+
+With INDEX_FLAG_SHARED_VALUES (see `WriteOptions::dedup_values`) the offsets
+section holds an (offset, length) pair of u64 per slot. Values are not in slot
+order and several slots may point at the same one.
+
+With INDEX_FLAG_FIXED_VALUES every value is `DabaHeader::value_width` bytes,
+the value of slot i starts at i * value_width and the offsets section is empty.
+
+With INDEX_FLAG_SORTED_KEYS (see `WriteOptions::sorted_keys`) the sorted keys
+section holds the slot of every key in ascending key order; otherwise it is
+empty.
+
+Single-file layout (see `Database::open_single`):
+
++------------------------------+
+| FileHeader (16 bytes)        |
+| SectionEntry (24 bytes) × n  |  <- section directory: kind, offset, size
+| sections, each aligned to 64 |  <- metadata (DabaHeader + IndexHeader), MPHF,
+|                              |     keys, key heap, offsets, value block
+|                              |     checksums, sorted keys, values
++------------------------------+ */
+
+// Seed of the fingerprint hash, so fingerprints are independent of the MPHF hash
+const FINGERPRINT_SEED: u64 = 0x6b76_6661_7374_6670;
+
+// 128-bit digest of a variable-length key, which is what the MPHF hashes in that layout
+pub(crate) fn key_digest(key: &[u8]) -> Key<DIGEST_SIZE> {
+    xxh3_128(key).to_le_bytes()
 }
 
-/* +--------------------+
-| key 0 (KEY_SIZE)   |
-| offset 0 (u64)     |
-| key 1 (KEY_SIZE)   |
-| offset 1 (u64)     |
-| ...                |
-+--------------------+ */
-
-impl Database {
-    pub fn open<P: AsRef<Path>>(data_file: P, index_file: P) -> io::Result<Self> {
-        // Open and mmap the data file
-        let file = File::open(data_file)?;
-        let mmap_data = unsafe { Mmap::map(&file)? };
-
-        let header_size = std::mem::size_of::<DabaHeader>();
-        let header = DabaHeader::from_bytes(&mmap_data[0..header_size])
-            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Invalid DABA header"))?;
-
-        let num_keys = header.num_keys as usize;
-
-        // Open and mmap the index file
-        let idx_file = File::open(index_file)?;
-        let index_mmap = unsafe { Mmap::map(&idx_file)? };
-
-        // Parse index header
-        let index_header = IndexHeader::from_bytes(&index_mmap[0..40])
-            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Invalid index header"))?;
-
-        if index_header.num_keys != header.num_keys {
-            return Err(io::Error::new(
-                io::ErrorKind::InvalidData,
-                "Key count mismatch between data and index files",
-            ));
-        }
-
-        // Deserialize MPHF from index file using epserde
-        // We need to create a temporary file because epserde expects a file path
-        let mphf_start = 40;
-        let mphf_end = mphf_start + index_header.mphf_size as usize;
-        let mphf_bytes = &index_mmap[mphf_start..mphf_end];
-
-        // Write MPHF bytes to a temporary file
-        let mut temp_mphf_file = tempfile::NamedTempFile::new()?;
-        temp_mphf_file.write_all(mphf_bytes)?;
-        temp_mphf_file.flush()?;
-
-        // Load MPHF using epserde's mmap-based deserialization
-        let mphf = <KeyPtrHash as Deserialize>::mmap(temp_mphf_file.path(), epserde::deser::Flags::empty())
-            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("Failed to deserialize MPHF: {:?}", e)))?;
-
-        // Parse keys from index file
-        let keys_start = index_header.keys_offset as usize;
-        let mut keys = Vec::with_capacity(num_keys);
-
-        for i in 0..num_keys {
-            let offset = keys_start + i * KEY_SIZE;
-            let key_bytes: Key = index_mmap[offset..offset + KEY_SIZE]
-                .try_into()
-                .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Invalid key in index"))?;
-            keys.push(key_bytes);
-        }
-
-        // Parse offsets from index file
-        let offsets_start = index_header.offsets_offset as usize;
-        let mut offsets = Vec::with_capacity(num_keys);
-
-        for i in 0..num_keys {
-            let offset = offsets_start + i * 8;
-            let value = u64::from_le_bytes(
-                index_mmap[offset..offset + 8]
-                    .try_into()
-                    .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Invalid offset in index"))?
-            );
-            offsets.push(value);
+// Fingerprint of `key`, truncated to `width` bytes when stored
+pub(crate) fn fingerprint(key: &[u8]) -> u32 {
+    xxh3_64_with_seed(key, FINGERPRINT_SEED) as u32
+}
+
+pub(crate) fn align_up(value: u64, align: u64) -> u64 {
+    value.div_ceil(align) * align
+}
+
+// Returns the byte range `[start, start + len)` of `mmap`, or an error if it is out of bounds
+fn section_range(mmap: &Mmap, start: u64, len: u64, section: &'static str) -> Result<Range<usize>> {
+    let end = start
+        .checked_add(len)
+        .filter(|&end| end <= mmap.len() as u64)
+        .ok_or(Error::Truncated { section })?;
+    Ok(start as usize..end as usize)
+}
+
+// Reads the headers of a single-file database and locates its sections from the directory
+fn single_file_layout(mmap: &Mmap) -> Result<(DabaHeader, IndexHeader, Sections)> {
+    let file_header = parse_header(mmap, FileHeader::SIZE, "KVDB header", FileHeader::from_bytes)?;
+    if file_header.version != SINGLE_FILE_VERSION {
+        return Err(Error::UnsupportedVersion { section: "KVDB header", version: file_header.version });
+    }
+
+    // Read the section directory
+    let directory_size = file_header.num_sections as u64 * SectionEntry::SIZE as u64;
+    let directory = section_range(mmap, FileHeader::SIZE as u64, directory_size, "section directory")?;
+    let entries: Vec<SectionEntry> = mmap[directory]
+        .chunks_exact(SectionEntry::SIZE)
+        .filter_map(SectionEntry::from_bytes)
+        .collect();
+    let find = |kind: u32, section: &'static str| -> Result<Range<usize>> {
+        match entries.iter().find(|entry| entry.kind == kind) {
+            Some(entry) => section_range(mmap, entry.offset, entry.size, section),
+            None => Err(Error::InvalidHeader(format!("{} missing from directory", section))),
+        }
+    };
+
+    // The metadata section holds the headers of the two-file layout
+    let meta = &mmap[find(SECTION_META, "metadata section")?];
+    let header = parse_header(meta, DabaHeader::SIZE, "DABA header", DabaHeader::from_bytes)?;
+    let index_header = parse_header(
+        meta.get(DabaHeader::SIZE..).unwrap_or_default(),
+        IndexHeader::SIZE,
+        "index header",
+        IndexHeader::from_bytes,
+    )?;
+
+    // The directory gives every section's size, checked against the headers
+    let sections = Sections {
+        mphf: find(SECTION_MPHF, "MPHF section")?,
+        keys: find(SECTION_KEYS, "keys section")?,
+        key_heap: find(SECTION_KEY_HEAP, "key heap section")?,
+        offsets: find(SECTION_OFFSETS, "offsets section")?,
+        value_checksums: find(SECTION_VALUE_CHECKSUMS, "value checksums section")?,
+        sorted_keys: find(SECTION_SORTED_KEYS, "sorted keys section")?,
+        values: find(SECTION_VALUES, "values section")?,
+    };
+    Ok((header, index_header, sections))
+}
+
+// Checks that a section has the size implied by the headers
+fn check_section_size(section: &Range<usize>, expected: u64, name: &'static str) -> Result<()> {
+    if section.len() as u64 != expected {
+        return Err(Error::SectionSizeMismatch { section: name, size: section.len() as u64, expected });
+    }
+    Ok(())
+}
+
+// ε-copy deserializes the MPHF stored in `bytes`
+fn deserialize_mphf<const N: usize>(bytes: &'static [u8]) -> Result<KeyPtrHashView<N>> {
+    <KeyPtrHash<N> as Deserialize>::deserialize_eps(bytes)
+        .map_err(|e| Error::Mphf(format!("Failed to deserialize MPHF: {:?}", e)))
+}
+
+// Data file of the two-file database at `data_file` that the index at
+// `index_file` was written with (see `write_two_files`), with the index mapped
+fn map_published_pair(data_file: &Path, index_file: &Path) -> Result<(Mmap, Mmap)> {
+    let index_mmap = unsafe { Mmap::map(&File::open(index_file)?)? };
+    if is_baseline_index(&index_mmap) {
+        return Err(Error::Unsupported("database in the baseline layout, convert it with `kvfast upgrade`"));
+    }
+    let index_header = parse_header(&index_mmap, IndexHeader::SIZE, "index header", IndexHeader::from_bytes)?;
+    if index_header.data_slot > 1 {
+        return Err(Error::InvalidHeader(format!("unknown data file slot {}", index_header.data_slot)));
+    }
+    let data_mmap = unsafe { Mmap::map(&File::open(data_slot_path(data_file, index_header.data_slot))?)? };
+    Ok((data_mmap, index_mmap))
+}
+
+// `map_published_pair`, retried once if the data file is gone: a rewrite that
+// published a new index after this one was read removes the old data file
+fn map_pair(data_file: &Path, index_file: &Path) -> Result<(Mmap, Mmap)> {
+    match map_published_pair(data_file, index_file) {
+        Err(Error::Io(e)) if e.kind() == io::ErrorKind::NotFound => map_published_pair(data_file, index_file),
+        result => result,
+    }
+}
+
+// Key width recorded in the data file of a database, VAR_KEY_SIZE for
+// variable-length keys; picks the `N` to open it with when it is not known
+pub fn stored_key_size<P: AsRef<Path>>(data_file: P, index_file: P) -> Result<usize> {
+    let (mmap, _) = map_pair(data_file.as_ref(), index_file.as_ref())?;
+    let header = parse_header(&mmap, DabaHeader::SIZE, "DABA header", DabaHeader::from_bytes)?;
+    Ok(header.key_size as usize)
+}
+
+// `stored_key_size` of a single-file database
+pub fn stored_key_size_single<P: AsRef<Path>>(path: P) -> Result<usize> {
+    let mmap = unsafe { Mmap::map(&File::open(path)?)? };
+    Ok(single_file_layout(&mmap)?.0.key_size as usize)
+}
+
+impl<const N: usize> Database<N> {
+    pub fn open<P: AsRef<Path>>(data_file: P, index_file: P) -> Result<Self> {
+        Self::open_with_options(data_file, index_file, &OpenOptions::default())
+    }
+
+    pub fn open_with_options<P: AsRef<Path>>(data_file: P, index_file: P, options: &OpenOptions) -> Result<Self> {
+        // Open and mmap the index and the data file it was written with
+        let (mmap_data, index_mmap) = map_pair(data_file.as_ref(), index_file.as_ref())?;
+
+        let header = parse_header(&mmap_data, DabaHeader::SIZE, "DABA header", DabaHeader::from_bytes)?;
+        let index_header = parse_header(&index_mmap, IndexHeader::SIZE, "index header", IndexHeader::from_bytes)?;
+
+        // Locate the sections from the header offsets and the sizes implied by the headers
+        let (mmap_data, index_mmap) = (Arc::new(mmap_data), Arc::new(index_mmap));
+        let locate = |sizes: &SectionSizes| -> Result<Sections> {
+            let section = |offset, size, name| section_range(&index_mmap, offset, size, name);
+            Ok(Sections {
+                mphf: section(index_header.mphf_offset, index_header.mphf_size, "MPHF section")?,
+                keys: section(index_header.keys_offset, sizes.keys, "keys section")?,
+                key_heap: section(index_header.key_heap_offset, index_header.key_heap_size, "key heap section")?,
+                offsets: section(index_header.offsets_offset, sizes.offsets, "offsets section")?,
+                value_checksums: section(
+                    index_header.value_checksums_offset,
+                    index_header.value_checksums_size,
+                    "value checksums section",
+                )?,
+                sorted_keys: section(
+                    index_header.sorted_keys_offset,
+                    index_header.sorted_keys_size,
+                    "sorted keys section",
+                )?,
+                values: header.values_start.min(mmap_data.len())..mmap_data.len(),
+            })
+        };
+        Self::from_sections(header, index_header, mmap_data.clone(), index_mmap.clone(), locate, options, false)
+    }
+
+    // Opens a single-file database written by `write_database_single`
+    pub fn open_single<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Self::open_single_with_options(path, &OpenOptions::default())
+    }
+
+    pub fn open_single_with_options<P: AsRef<Path>>(path: P, options: &OpenOptions) -> Result<Self> {
+        Self::open_single_as(path, options, false)
+    }
+
+    // Opens a single file holding a database, or a static set if `keys_only`
+    // (see `StaticSet`); either fails on a file holding the other
+    pub(crate) fn open_single_as<P: AsRef<Path>>(path: P, options: &OpenOptions, keys_only: bool) -> Result<Self> {
+        let file = File::open(path)?;
+        let mmap = unsafe { Mmap::map(&file)? };
+        let (header, index_header, sections) = single_file_layout(&mmap)?;
+
+        let mmap = Arc::new(mmap);
+        Self::from_sections(header, index_header, mmap.clone(), mmap, |_| Ok(sections), options, keys_only)
+    }
+
+    // Validates the headers shared by both layouts, derives the section sizes
+    // they imply, locates the sections with `locate` and checks their sizes,
+    // then sets up in-place views over them
+    fn from_sections(
+        header: DabaHeader,
+        index_header: IndexHeader,
+        mmap_data: Arc<Mmap>,
+        mmap_index: Arc<Mmap>,
+        locate: impl FnOnce(&SectionSizes) -> Result<Sections>,
+        options: &OpenOptions,
+        keys_only: bool,
+    ) -> Result<Self> {
+        let num_keys = header.num_keys;
+
+        if header.key_size != N as u64 {
+            return Err(Error::KeySizeMismatch { stored: header.key_size, requested: N });
+        }
+
+        if index_header.version != INDEX_VERSION {
+            return Err(Error::UnsupportedVersion { section: "index header", version: index_header.version });
+        }
+
+        if index_header.compute_checksum(&header) != index_header.header_checksum {
+            return Err(Error::ChecksumMismatch { section: "headers" });
+        }
+
+        if index_header.num_keys != num_keys {
+            return Err(Error::KeyCountMismatch { data: num_keys, index: index_header.num_keys });
+        }
+
+        if index_header.has_variable_keys() != (N == VAR_KEY_SIZE) {
+            return Err(Error::InvalidHeader("key layout mismatch between data and index headers".to_string()));
+        }
+
+        match (index_header.is_keys_only(), keys_only) {
+            (true, false) => {
+                return Err(Error::InvalidHeader("file holds a static set, open it with StaticSet".to_string()));
+            }
+            (false, true) => return Err(Error::InvalidHeader("file holds a database, not a static set".to_string())),
+            _ => {}
         }
 
+        let key_check = KeyCheck::from_code(index_header.key_check)
+            .ok_or_else(|| Error::InvalidHeader(format!("unknown key check mode {}", index_header.key_check)))?;
+        let value_width = index_header.has_fixed_values().then_some(header.value_width);
+        let sizes = SectionSizes {
+            keys: key_check.keys_section_size(N, num_keys),
+            offsets: if keys_only || value_width.is_some() {
+                0
+            } else if index_header.has_ef_offsets() {
+                index_header.offsets_size
+            } else if index_header.has_shared_values() {
+                num_keys.saturating_mul(16)
+            } else {
+                num_keys.saturating_mul(8)
+            },
+            values: index_header.values_size,
+            sorted_keys: if index_header.has_sorted_keys() { num_keys.saturating_mul(8) } else { 0 },
+        };
+
+        let expected_values = if keys_only { Some(0) } else { value_width.map(|width| num_keys.saturating_mul(width)) };
+        if expected_values.is_some_and(|expected| expected != sizes.values) {
+            return Err(Error::InvalidHeader("values size does not match the value width".to_string()));
+        }
+
+        // A data file cut short, or with bytes appended, fails here rather than serve wrong values
+        let sections = locate(&sizes)?;
+        check_section_size(&sections.keys, sizes.keys, "keys")?;
+        check_section_size(&sections.offsets, sizes.offsets, "offsets")?;
+        check_section_size(&sections.values, sizes.values, "values")?;
+        let num_blocks = match index_header.value_block_size {
+            0 => 0,
+            block_size => (sections.values.len() as u64).div_ceil(block_size),
+        };
+        check_section_size(&sections.value_checksums, num_blocks * 8, "value checksums")?;
+        check_section_size(&sections.sorted_keys, sizes.sorted_keys, "sorted keys")?;
+
+        // Check the section checksums before anything is read from them
+        if options.verify_on_open {
+            let corruptions = section_corruptions(&index_header, &mmap_data, &mmap_index, &sections);
+            if let Some(corruption) = corruptions.into_iter().next() {
+                return Err(Error::Corrupt(corruption));
+            }
+        }
+
+        // Lookups trust the MPHF blindly, so it is always checked before use
+        let mphf_bytes = &mmap_index[sections.mphf.clone()];
+        if xxh3_64(mphf_bytes) != index_header.mphf_checksum {
+            return Err(Error::ChecksumMismatch { section: "MPHF section" });
+        }
+
+        // ε-copy deserialize the MPHF straight from the index mapping
+        if !(mphf_bytes.as_ptr() as u64).is_multiple_of(MPHF_ALIGN) {
+            return Err(Error::Misaligned { section: "MPHF" });
+        }
+        // SAFETY: the mapped pages live as long as `mmap_index`, and `mphf` is
+        // dropped before it (see the field order of `Database`)
+        let mphf_bytes: &'static [u8] = unsafe { std::slice::from_raw_parts(mphf_bytes.as_ptr(), mphf_bytes.len()) };
+
+        let mphf = if N == VAR_KEY_SIZE {
+            Mphf::Digests(deserialize_mphf(mphf_bytes)?)
+        } else {
+            Mphf::Keys(deserialize_mphf(mphf_bytes)?)
+        };
+
+        // Compressed offsets are decoded without bounds checks, so like the MPHF
+        // they are checked and deserialized in place
+        let ef_offsets = if index_header.has_ef_offsets() {
+            let bytes = &mmap_index[sections.offsets.clone()];
+            if xxh3_64(bytes) != index_header.offsets_checksum {
+                return Err(Error::ChecksumMismatch { section: "offsets section" });
+            }
+            if !(bytes.as_ptr() as u64).is_multiple_of(MPHF_ALIGN) {
+                return Err(Error::Misaligned { section: "offsets" });
+            }
+            // SAFETY: as for the MPHF above
+            let bytes: &'static [u8] = unsafe { std::slice::from_raw_parts(bytes.as_ptr(), bytes.len()) };
+            let ef = <CachelineEfVec as Deserialize>::deserialize_eps(bytes)
+                .map_err(|e| Error::InvalidHeader(format!("Failed to deserialize offsets: {:?}", e)))?;
+            if ef.len() as u64 != num_keys || ef.size_in_bytes() != (ef.len().div_ceil(EF_VALUES_PER_LINE) * 64) {
+                return Err(Error::InvalidHeader("compressed offsets do not match the key count".to_string()));
+            }
+            Some(ef)
+        } else {
+            None
+        };
+
+        // Keys and offsets are read in place by `get`
+        let keys = match key_check {
+            KeyCheck::Keys if N == VAR_KEY_SIZE => {
+                cast_offsets(&mmap_index[sections.keys.clone()], "key offsets")?;
+                KeyStorage::Variable { offsets: sections.keys.clone(), heap: sections.key_heap.clone() }
+            }
+            KeyCheck::Keys => KeyStorage::Fixed { keys: sections.keys.clone() },
+            KeyCheck::Fingerprint { bits } => {
+                KeyStorage::Fingerprints { width: bits as usize / 8, fingerprints: sections.keys.clone() }
+            }
+            KeyCheck::Trusted => KeyStorage::Trusted,
+        };
+        cast_offsets(&mmap_index[sections.offsets.clone()], "offsets")?;
+        cast_offsets(&mmap_index[sections.sorted_keys.clone()], "sorted keys")?;
+
         Ok(Self {
-            mmap_data: Arc::new(mmap_data),
-            offsets: Arc::new(offsets),
-            keys: Arc::new(keys),
+            mmap_data,
+            mmap_index,
             mphf,
+            ef_offsets,
+            keys,
+            value_width: value_width.map(|width| width as usize),
+            sections,
             header,
+            index_header,
         })
     }
 
-    pub fn get(&self, key: &Key) -> Option<&[u8]> {
-        // PtrHash uses index() method which returns the hash index
-        let idx = self.mphf.index(key) as usize;
-
-        // Validate the key matches to prevent false positives
-        if idx >= self.keys.len() || &self.keys[idx] != key {
+    // MPHF slot of `key`: the MPHF hashes the key itself in the fixed layout
+    // (None if it has the wrong length), its digest in the variable layout
+    #[inline]
+    pub(crate) fn slot(&self, key: &[u8]) -> Option<usize> {
+        // The MPHF of an empty database (e.g. an empty shard) cannot be queried
+        if self.is_empty() {
             return None;
         }
+        match &self.mphf {
+            Mphf::Keys(mphf) => Some(mphf.index(&Key::<N>::try_from(key).ok()?)),
+            Mphf::Digests(mphf) => Some(mphf.index(&key_digest(key))),
+        }
+    }
 
-        let start = self.offsets[idx] as usize;
-        let end = self
-            .offsets
-            .get(idx + 1)
-            .map(|&v| v as usize)
-            .unwrap_or(self.mmap_data.len() - self.header.values_start);
-
-        Some(&self.mmap_data[self.header.values_start + start..self.header.values_start + end])
-    }
-
-    pub fn write_database<K, V, PK, PV, P>(
-        path_data: P,
-        path_index: P,
-        keys_iter: K,
-        values_iter: V,
-        version: u32,
-    ) -> io::Result<()>
-    where
-        K: Iterator<Item = PK>,
-        PK: AsRef<[u8]>,
-        V: Iterator<Item = PV>,
-        PV: AsRef<[u8]>,
-        P: AsRef<Path>,
-    {
-        // TODO: evaluate the use of iterators. The limitation rn is the mphf.
-        let mut data_file = File::create(&path_data)?;
-        let header_size = std::mem::size_of::<DabaHeader>();
-        data_file.write_all(&vec![0u8; header_size])?;
-
-        let mut keys_vec = Vec::new();
-        let mut values_vec = Vec::new();
-
-        for (k, v) in keys_iter.zip(values_iter) {
-            let key_bytes = k.as_ref();
-            assert_eq!(key_bytes.len(), KEY_SIZE, "Key must be {} bytes", KEY_SIZE);
-
-            let mut key: Key = [0u8; KEY_SIZE];
-            key.copy_from_slice(key_bytes);
-            keys_vec.push(key);
-            values_vec.push(v.as_ref().to_vec());
-        }
-
-        let num_keys = keys_vec.len() as u64;
-
-        // Build PtrHash with default parameters
-        let mphf: KeyPtrHash = PtrHash::new(&keys_vec, PtrHashParams::default());
-
-        // Create mapping from MPHF index to original index
-        let mut mphf_to_original = vec![0usize; keys_vec.len()];
-        for (original_idx, key) in keys_vec.iter().enumerate() {
-            let mphf_idx = mphf.index(key) as usize;
-            mphf_to_original[mphf_idx] = original_idx;
-        }
-
-        // Write values in the order determined by MPHF
-        for mphf_idx in 0..keys_vec.len() {
-            let original_idx = mphf_to_original[mphf_idx];
-            let val_bytes = &values_vec[original_idx];
-            data_file.write_all(val_bytes)?;
-        }
-
-        // Serialize MPHF using epserde
-        let mut mphf_bytes = Vec::new();
-        mphf.serialize(&mut mphf_bytes)
-            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("Failed to serialize MPHF: {:?}", e)))?;
-
-        // Calculate offsets for index file sections
-        let index_header_size = 40u64;
-        let mphf_size = mphf_bytes.len() as u64;
-        let keys_offset = index_header_size + mphf_size;
-        let offsets_offset = keys_offset + (num_keys * KEY_SIZE as u64);
-
-        // Create index header
-        let index_header = IndexHeader {
-            magic: *b"KIDX",
-            version: 1,
-            num_keys,
-            mphf_size,
-            keys_offset,
-            offsets_offset,
+    // Streams a batch of keys through the MPHF (which prefetches its own
+    // pilots), calling `f` with the slot of each key in order. Keys of the
+    // wrong length hash a placeholder and are then rejected by `key_matches`.
+    #[inline]
+    fn slots_batch<K: AsRef<[u8]>>(&self, batch: &[K], f: impl FnMut(usize)) {
+        // The stream only supports internal iteration, hence `for_each`
+        match &self.mphf {
+            Mphf::Keys(mphf) => {
+                let keys = batch.iter().map(|key| Key::<N>::try_from(key.as_ref()).unwrap_or([0; N]));
+                mphf.index_stream::<PTR_HASH_STREAM, true, _>(keys).for_each(f)
+            }
+            Mphf::Digests(mphf) => {
+                let keys = batch.iter().map(|key| key_digest(key.as_ref()));
+                mphf.index_stream::<PTR_HASH_STREAM, true, _>(keys).for_each(f)
+            }
+        }
+    }
+
+    // Key stored at MPHF slot `idx`, read in place from the index mapping
+    // (None if the index only stores fingerprints or nothing)
+    #[inline]
+    pub(crate) fn stored_key(&self, idx: usize) -> Option<&[u8]> {
+        match &self.keys {
+            KeyStorage::Fixed { keys } => {
+                let keys = self.mmap_index[keys.clone()].as_chunks::<N>().0;
+                keys.get(idx).map(|key| &key[..])
+            }
+            KeyStorage::Variable { offsets, heap } => {
+                // Alignment and endianness were checked in `open`
+                let offsets = cast_offsets(&self.mmap_index[offsets.clone()], "key offsets").unwrap_or_default();
+                let start = *offsets.get(idx)? as usize;
+                let end = *offsets.get(idx + 1)? as usize;
+                self.mmap_index[heap.clone()].get(start..end)
+            }
+            KeyStorage::Fingerprints { .. } | KeyStorage::Trusted => None,
+        }
+    }
+
+    // Whether `key` may be the key stored at slot `idx`; exact unless the
+    // index stores fingerprints or nothing. Keys of the wrong length never
+    // match, since batches hash a placeholder for them (see `slots_batch`).
+    #[inline]
+    fn key_matches(&self, idx: usize, key: &[u8]) -> bool {
+        if N != VAR_KEY_SIZE && key.len() != N {
+            return false;
+        }
+        match &self.keys {
+            KeyStorage::Fixed { .. } | KeyStorage::Variable { .. } => self.stored_key(idx) == Some(key),
+            KeyStorage::Fingerprints { width, fingerprints } => {
+                let start = fingerprints.start + idx * width;
+                let Some(stored) = self.mmap_index.get(start..start + width) else {
+                    return false;
+                };
+                stored == &fingerprint(key).to_le_bytes()[..*width]
+            }
+            KeyStorage::Trusted => idx < self.header.num_keys as usize,
+        }
+    }
+
+    // Prefetches whatever `key_matches(idx, ..)` reads first
+    #[inline]
+    fn prefetch_key(&self, idx: usize) {
+        let (section, width) = match &self.keys {
+            KeyStorage::Fixed { keys } => (keys, N),
+            KeyStorage::Variable { offsets, .. } => (offsets, 8),
+            KeyStorage::Fingerprints { width, fingerprints } => (fingerprints, *width),
+            KeyStorage::Trusted => return,
         };
+        if let Some(slot) = self.mmap_index[section.clone()].get(idx * width) {
+            prefetch(slot);
+        }
+    }
 
-        // Write index file
-        let mut index_file = File::create(&path_index)?;
+    // Plain value offsets in MPHF slot order, viewed in place over the index
+    // mapping (empty if they are compressed or values have a fixed width).
+    // With shared values these are (offset, length) pairs.
+    fn offsets(&self) -> &[u64] {
+        if self.ef_offsets.is_some() {
+            return &[];
+        }
+        // Alignment and endianness were checked in `open`
+        cast_offsets(&self.mmap_index[self.sections.offsets.clone()], "offsets").unwrap_or_default()
+    }
 
-        // Write index header
-        index_file.write_all(&index_header.to_bytes())?;
+    // Value offset of slot `idx`, plain or compressed
+    #[inline]
+    fn offset(&self, idx: usize) -> Option<u64> {
+        match &self.ef_offsets {
+            Some(ef) => (idx < ef.len()).then(|| ef.index(idx)),
+            None => self.offsets().get(idx).copied(),
+        }
+    }
 
-        // Write serialized MPHF
-        index_file.write_all(&mphf_bytes)?;
+    // Prefetches whatever `offset(idx)` reads
+    #[inline]
+    fn prefetch_offset(&self, idx: usize) {
+        match &self.ef_offsets {
+            Some(ef) if idx < ef.len() => ef.prefetch(idx),
+            Some(_) => {}
+            None => {
+                let idx = if self.index_header.has_shared_values() { 2 * idx } else { idx };
+                if let Some(offset) = self.offsets().get(idx) {
+                    prefetch(offset);
+                }
+            }
+        }
+    }
 
-        // Write keys in MPHF order
-        for mphf_idx in 0..keys_vec.len() {
-            let original_idx = mphf_to_original[mphf_idx];
-            let key = &keys_vec[original_idx];
-            index_file.write_all(key)?;
+    // All value offsets in MPHF slot order, decoded if compressed (empty if
+    // values have a fixed width)
+    pub(crate) fn all_offsets(&self) -> Cow<'_, [u64]> {
+        match &self.ef_offsets {
+            Some(ef) => Cow::Owned((0..ef.len()).map(|idx| ef.index(idx)).collect()),
+            None => Cow::Borrowed(self.offsets()),
         }
+    }
+
+    // Whether the value offsets are stored Elias-Fano encoded (see `WriteOptions::compress_offsets`)
+    pub fn has_compressed_offsets(&self) -> bool {
+        self.ef_offsets.is_some()
+    }
 
-        // Write offsets in MPHF order
-        let mut cursor = 0u64;
-        for mphf_idx in 0..keys_vec.len() {
-            let original_idx = mphf_to_original[mphf_idx];
-            index_file.write_all(&cursor.to_le_bytes())?;
-            cursor += values_vec[original_idx].len() as u64;
+    // Whether slots may share a stored value (see `WriteOptions::dedup_values`)
+    pub fn has_shared_values(&self) -> bool {
+        self.index_header.has_shared_values()
+    }
+
+    // Slots in ascending key order, viewed in place over the index mapping
+    // (empty unless the database has a sorted keys section)
+    pub(crate) fn sorted_slots(&self) -> &[u64] {
+        cast_offsets(&self.mmap_index[self.sections.sorted_keys.clone()], "sorted keys").unwrap_or_default()
+    }
+
+    pub fn get(&self, key: &[u8]) -> Option<&[u8]> {
+        // PtrHash uses index() method which returns the hash index
+        let idx = self.slot(key)?;
+        self.resolve(idx, key)
+    }
+
+    // Batched lookup: hashes a whole batch of keys first, prefetches their key
+    // slots, offsets and values, then resolves them, so that the cache misses of
+    // different keys overlap instead of being paid one after the other.
+    pub fn get_many<K: AsRef<[u8]>>(&self, keys: &[K]) -> Vec<Option<&[u8]>> {
+        let mut out = Vec::with_capacity(keys.len());
+        self.get_many_into(keys, &mut out);
+        out
+    }
+
+    // Same as `get_many`, but appends the results to `out` so callers can reuse the buffer
+    pub fn get_many_into<'a, K: AsRef<[u8]>>(&'a self, keys: &[K], out: &mut Vec<Option<&'a [u8]>>) {
+        let values = &self.mmap_data[self.sections.values.clone()];
+        let mut slots = [0usize; GET_MANY_BATCH];
+
+        out.reserve(keys.len());
+        if self.is_empty() {
+            out.extend(keys.iter().map(|_| None));
+            return;
+        }
+        for batch in keys.chunks(GET_MANY_BATCH) {
+            // Hash the batch and prefetch the key slots and offsets
+            let mut slots_iter = slots.iter_mut();
+            self.slots_batch(batch, |idx| {
+                if let Some(slot) = slots_iter.next() {
+                    *slot = idx;
+                }
+                self.prefetch_key(idx);
+                self.prefetch_offset(idx);
+            });
+
+            // Prefetch the first cache line of every value
+            for &idx in &slots[..batch.len()] {
+                if let Some(value) = self.value_range(idx).and_then(|range| values.get(range.start)) {
+                    prefetch(value);
+                }
+            }
+
+            out.extend(batch.iter().zip(&slots).map(|(key, &idx)| self.resolve(idx, key.as_ref())));
         }
+    }
 
-        // Write data file header
-        let header = DabaHeader {
-            magic: *b"DABA",
-            version,
-            num_keys,
-            key_size: KEY_SIZE as u64,
-            values_start: header_size as usize,
-        };
-        data_file.seek(SeekFrom::Start(0))?;
-        data_file.write_all(&header.to_bytes())?;
+    // Whether `key` is in the database: exact when the index stores keys, with
+    // the false positive rate of `KeyCheck::Fingerprint` otherwise, and always
+    // true with `KeyCheck::Trusted`
+    pub fn contains(&self, key: &[u8]) -> bool {
+        self.slot(key).is_some_and(|idx| self.key_matches(idx, key))
+    }
 
-        Ok(())
+    // Batched `contains`, hashing and prefetching a batch of keys at a time like `get_many`
+    pub fn contains_many<K: AsRef<[u8]>>(&self, keys: &[K]) -> Vec<bool> {
+        let mut out = Vec::with_capacity(keys.len());
+        if self.is_empty() {
+            out.resize(keys.len(), false);
+            return out;
+        }
+        let mut slots = [0usize; GET_MANY_BATCH];
+        for batch in keys.chunks(GET_MANY_BATCH) {
+            let mut slots_iter = slots.iter_mut();
+            self.slots_batch(batch, |idx| {
+                if let Some(slot) = slots_iter.next() {
+                    *slot = idx;
+                }
+                self.prefetch_key(idx);
+            });
+
+            out.extend(batch.iter().zip(&slots).map(|(key, &idx)| self.key_matches(idx, key.as_ref())));
+        }
+        out
+    }
+
+    // Validates that `key` is stored at MPHF slot `idx` and returns its value
+    #[inline]
+    fn resolve(&self, idx: usize, key: &[u8]) -> Option<&[u8]> {
+        // Validate the key matches to prevent false positives
+        if !self.key_matches(idx, key) {
+            return None;
+        }
+
+        self.value_at(idx)
+    }
+
+    // Value stored at MPHF slot `idx`
+    #[inline]
+    pub(crate) fn value_at(&self, idx: usize) -> Option<&[u8]> {
+        let range = self.value_range(idx)?;
+        self.mmap_data[self.sections.values.clone()].get(range)
+    }
+
+    // Byte range of the value of slot `idx` within the values section:
+    // computed from the slot with fixed-width values, read from the slot's
+    // (offset, length) pair with shared values, and from the offsets otherwise
+    #[inline]
+    pub(crate) fn value_range(&self, idx: usize) -> Option<Range<usize>> {
+        if let Some(width) = self.value_width {
+            return (idx < self.len()).then(|| idx * width..(idx + 1) * width);
+        }
+        if self.index_header.has_shared_values() {
+            let pair = self.offsets().get(2 * idx..2 * idx + 2)?;
+            let start = pair[0] as usize;
+            return Some(start..start.checked_add(pair[1] as usize)?);
+        }
+        let start = self.offset(idx)? as usize;
+        let end = self.offset(idx + 1).map_or(self.sections.values.len(), |end| end as usize);
+        Some(start..end)
+    }
+
+    // Width of every value if the database stores fixed-width values
+    pub fn value_width(&self) -> Option<usize> {
+        self.value_width
+    }
+
+    // Codec the values are encoded with and the hash of their type, from the data header
+    pub(crate) fn value_codec(&self) -> (u64, u64) {
+        (self.header.value_codec, self.header.value_type)
+    }
+
+    // Number of keys in the database
+    pub fn len(&self) -> usize {
+        self.header.num_keys as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // Whether the index stores the keys themselves, rather than fingerprints or nothing
+    pub fn has_keys(&self) -> bool {
+        matches!(self.keys, KeyStorage::Fixed { .. } | KeyStorage::Variable { .. })
     }
+
+    // Whether the index has a sorted keys section, for `range` and `prefix`
+    pub fn has_sorted_keys(&self) -> bool {
+        self.index_header.has_sorted_keys()
+    }
+}
+
+// Hints the CPU to pull the cache line holding `value` into L1
+#[inline(always)]
+fn prefetch<T>(value: &T) {
+    #[cfg(target_arch = "x86_64")]
+    unsafe {
+        std::arch::x86_64::_mm_prefetch(value as *const T as *const i8, std::arch::x86_64::_MM_HINT_T0);
+    }
+    #[cfg(not(target_arch = "x86_64"))]
+    let _ = value;
+}
+
+// Views a byte section as `&[u64]`, which requires 8-byte alignment and a little-endian host
+fn cast_offsets<'a>(bytes: &'a [u8], section: &'static str) -> Result<&'a [u64]> {
+    // SAFETY: every bit pattern is a valid u64
+    let (prefix, offsets, suffix) = unsafe { bytes.align_to::<u64>() };
+    if !prefix.is_empty() || !suffix.is_empty() {
+        return Err(Error::Misaligned { section });
+    }
+    if cfg!(target_endian = "big") {
+        return Err(Error::Unsupported("index offsets require a little-endian host"));
+    }
+    Ok(offsets)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::header::VALUE_CODEC_BINCODE;
+    use std::io;
     use tempfile::NamedTempFile;
 
     #[test]
@@ -257,7 +991,7 @@ mod tests {
 
         let values: Vec<Vec<u8>> = vec![b"hello".to_vec(), b"world".to_vec(), b"rustlang".to_vec()];
 
-        Database::write_database(
+        Database::<KEY_SIZE>::write_database(
             data_file.path(),
             index_file.path(),
             keys.iter(), // &Key
@@ -265,7 +999,7 @@ mod tests {
             1,
         )?;
 
-        let db = Database::open(data_file.path(), index_file.path())?;
+        let db = Database::<KEY_SIZE>::open(data_file.path(), index_file.path())?;
 
         for (key, value) in keys.iter().zip(values.iter()) {
             let retrieved = db.get(key).expect("Value should exist");
@@ -286,6 +1020,10 @@ mod tests {
             num_keys: 12345,
             key_size: KEY_SIZE as u64,
             values_start: 32,
+            value_width: 0,
+            value_codec: VALUE_CODEC_BINCODE,
+            value_type: 7,
+            build_id: 0x1234_5678_9abc_def0,
         };
 
         let bytes = header.to_bytes();
@@ -296,6 +1034,8 @@ mod tests {
         assert_eq!(header.num_keys, parsed.num_keys);
         assert_eq!(header.key_size, parsed.key_size);
         assert_eq!(header.values_start, parsed.values_start);
+        assert_eq!((header.value_codec, header.value_type), (parsed.value_codec, parsed.value_type));
+        assert_eq!(header.build_id, parsed.build_id);
     }
 
     #[test]
@@ -310,7 +1050,7 @@ mod tests {
 
         let values: Vec<Vec<u8>> = vec![b"value1".to_vec(), b"value2".to_vec()];
 
-        Database::write_database(
+        Database::<KEY_SIZE>::write_database(
             data_file.path(),
             index_file.path(),
             keys.iter(),
@@ -318,7 +1058,7 @@ mod tests {
             1,
         )?;
 
-        let db = Database::open(data_file.path(), index_file.path())?;
+        let db = Database::<KEY_SIZE>::open(data_file.path(), index_file.path())?;
 
         // Test that valid keys return their values
         for (key, value) in keys.iter().zip(values.iter()) {
@@ -336,6 +1076,36 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_get_many_rejects_wrong_length_keys() -> io::Result<()> {
+        let dir = tempfile::TempDir::new()?;
+        let keys: Vec<Key> = (0..2000u128).map(|i| i.to_le_bytes()).collect();
+        // Wrong-length keys, including the zero-padded placeholder batches hash for them
+        let queries: Vec<Vec<u8>> = keys
+            .iter()
+            .flat_map(|key| [key.to_vec(), key[..KEY_SIZE - 1].to_vec(), [&key[..], b"!"].concat(), Vec::new()])
+            .collect();
+
+        let key_checks = [
+            KeyCheck::Keys,
+            KeyCheck::Fingerprint { bits: 8 },
+            KeyCheck::Fingerprint { bits: 32 },
+            KeyCheck::Trusted,
+        ];
+        for key_check in key_checks {
+            let path = dir.path().join(format!("{:?}.kvdb", key_check));
+            let options = WriteOptions { key_check, ..Default::default() };
+            Database::<KEY_SIZE>::write_database_single(&path, keys.iter(), keys.iter(), 1, &options)?;
+            let db = Database::<KEY_SIZE>::open_single(&path)?;
+
+            let expected: Vec<Option<&[u8]>> = queries.iter().map(|key| db.get(key)).collect();
+            assert_eq!(db.get_many(&queries), expected, "{:?}", key_check);
+            assert!(expected.iter().zip(&queries).all(|(value, key)| value.is_some() == (key.len() == KEY_SIZE)));
+        }
+
+        Ok(())
+    }
+
     #[test]
     fn test_last_value_retrieval() -> io::Result<()> {
         // Test with single key-value pair
@@ -345,7 +1115,7 @@ mod tests {
         let keys: Vec<Key> = vec![*b"onlykey000000001"];
         let values: Vec<Vec<u8>> = vec![b"single_value".to_vec()];
 
-        Database::write_database(
+        Database::<KEY_SIZE>::write_database(
             data_file.path(),
             index_file.path(),
             keys.iter(),
@@ -353,7 +1123,7 @@ mod tests {
             1,
         )?;
 
-        let db = Database::open(data_file.path(), index_file.path())?;
+        let db = Database::<KEY_SIZE>::open(data_file.path(), index_file.path())?;
         let retrieved = db.get(&keys[0]).expect("Should retrieve single value");
         assert_eq!(retrieved, b"single_value");
 
@@ -372,7 +1142,7 @@ mod tests {
             b"last_value_here".to_vec(),
         ];
 
-        Database::write_database(
+        Database::<KEY_SIZE>::write_database(
             data_file2.path(),
             index_file2.path(),
             keys2.iter(),
@@ -380,7 +1150,7 @@ mod tests {
             1,
         )?;
 
-        let db2 = Database::open(data_file2.path(), index_file2.path())?;
+        let db2 = Database::<KEY_SIZE>::open(data_file2.path(), index_file2.path())?;
 
         // Verify all values, especially the last one
         for (key, value) in keys2.iter().zip(values2.iter()) {
@@ -390,4 +1160,625 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn test_truncated_index_is_rejected() -> io::Result<()> {
+        let data_file = NamedTempFile::new()?;
+        let index_file = NamedTempFile::new()?;
+
+        let keys: Vec<Key> = vec![*b"key0000000000001", *b"key0000000000002"];
+        let values: Vec<Vec<u8>> = vec![b"a".to_vec(), b"b".to_vec()];
+
+        Database::<KEY_SIZE>::write_database(
+            data_file.path(),
+            index_file.path(),
+            keys.iter(),
+            values.iter().map(|v| v.as_slice()),
+            1,
+        )?;
+
+        // Chop off the end of the keys section, the last one since the
+        // values have a fixed width and need no offsets
+        let len = std::fs::metadata(index_file.path())?.len();
+        let index = std::fs::OpenOptions::new().write(true).open(index_file.path())?;
+        index.set_len(len - 4)?;
+
+        let err = Database::<KEY_SIZE>::open(data_file.path(), index_file.path()).err().expect("Open should fail");
+        assert!(matches!(err, Error::Truncated { .. }));
+
+        // Shorter than the index header
+        index.set_len(10)?;
+        let err = Database::<KEY_SIZE>::open(data_file.path(), index_file.path()).err().expect("Open should fail");
+        assert!(matches!(err, Error::Truncated { section: "index header" }));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_truncated_data_file_is_rejected() -> io::Result<()> {
+        let data_file = NamedTempFile::new()?;
+        let index_file = NamedTempFile::new()?;
+
+        let keys: Vec<String> = (0..50).map(|i| format!("k{}", i)).collect();
+        let values: Vec<Vec<u8>> = (0..50).map(|i| vec![i as u8; 40]).collect();
+        VarDatabase::write_database(data_file.path(), index_file.path(), keys.iter(), values.iter(), 1)?;
+
+        // Values of variable length, so only the recorded values size can tell
+        let len = std::fs::metadata(data_file.path())?.len();
+        let data = std::fs::OpenOptions::new().write(true).open(data_file.path())?;
+        data.set_len(len - 5)?;
+        let err = VarDatabase::open(data_file.path(), index_file.path()).err().expect("Open should fail");
+        assert!(matches!(err, Error::SectionSizeMismatch { section: "values", .. }), "{}", err);
+
+        // Bytes appended to the data file are caught as well
+        data.set_len(len + 5)?;
+        let err = VarDatabase::open(data_file.path(), index_file.path()).err().expect("Open should fail");
+        assert!(matches!(err, Error::SectionSizeMismatch { section: "values", .. }), "{}", err);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_get_many_matches_get() -> io::Result<()> {
+        let data_file = NamedTempFile::new()?;
+        let index_file = NamedTempFile::new()?;
+
+        // More keys than one prefetch batch, so several batches are resolved
+        let keys: Vec<Key> = (0..200u32)
+            .map(|i| {
+                let mut key = *b"key0000000000000";
+                key[12..].copy_from_slice(&i.to_le_bytes());
+                key
+            })
+            .collect();
+        let values: Vec<Vec<u8>> = (0..200u32).map(|i| format!("value-{}", i).into_bytes()).collect();
+
+        Database::<KEY_SIZE>::write_database(
+            data_file.path(),
+            index_file.path(),
+            keys.iter(),
+            values.iter().map(|v| v.as_slice()),
+            1,
+        )?;
+
+        let db = Database::<KEY_SIZE>::open(data_file.path(), index_file.path())?;
+
+        let mut queries = keys.clone();
+        queries.push(*b"missing000000001");
+        queries.reverse();
+
+        let results = db.get_many(&queries);
+        assert_eq!(results.len(), queries.len());
+        for (key, result) in queries.iter().zip(&results) {
+            assert_eq!(*result, db.get(key));
+        }
+        assert!(results[0].is_none());
+
+        let mut out = vec![None];
+        db.get_many_into(&queries[..3], &mut out);
+        assert_eq!(out.len(), 4);
+        assert_eq!(&out[1..], &results[..3]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_variable_length_keys() -> io::Result<()> {
+        let data_file = NamedTempFile::new()?;
+        let index_file = NamedTempFile::new()?;
+
+        let keys: Vec<&[u8]> = vec![
+            b"https://example.com/",
+            b"https://example.com/a/much/longer/path?with=query",
+            b"",
+            b"key0000000000001",
+            b"user:42",
+        ];
+        let values: Vec<Vec<u8>> = (0..keys.len()).map(|i| format!("value-{}", i).into_bytes()).collect();
+
+        VarDatabase::write_database(
+            data_file.path(),
+            index_file.path(),
+            keys.iter(),
+            values.iter().map(|v| v.as_slice()),
+            1,
+        )?;
+
+        let db = VarDatabase::open(data_file.path(), index_file.path())?;
+
+        for (key, value) in keys.iter().zip(values.iter()) {
+            let retrieved = db.get(key).expect("Value should exist");
+            assert_eq!(retrieved, value.as_slice());
+        }
+
+        assert!(db.get(b"https://example.com").is_none());
+        assert!(db.get(b"user:4").is_none());
+
+        let results = db.get_many(&[&b"user:42"[..], b"missing"]);
+        assert_eq!(results, vec![Some(&b"value-4"[..]), None]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_fixed_layout_rejects_wrong_length_keys() -> io::Result<()> {
+        let data_file = NamedTempFile::new()?;
+        let index_file = NamedTempFile::new()?;
+
+        let keys: Vec<Key> = vec![*b"key0000000000001", *b"key0000000000002"];
+        let values: Vec<Vec<u8>> = vec![b"a".to_vec(), b"b".to_vec()];
+
+        Database::<KEY_SIZE>::write_database(
+            data_file.path(),
+            index_file.path(),
+            keys.iter(),
+            values.iter().map(|v| v.as_slice()),
+            1,
+        )?;
+
+        let db = Database::<KEY_SIZE>::open(data_file.path(), index_file.path())?;
+        assert_eq!(db.get(b"key0000000000001"), Some(&b"a"[..]));
+        assert!(db.get(b"key000000000000").is_none());
+        assert_eq!(db.get_many(&[&b"key00"[..], b"key0000000000002"]), vec![None, Some(&b"b"[..])]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_generic_key_widths() -> io::Result<()> {
+        fn round_trip<const N: usize>(keys: &[Key<N>]) -> io::Result<()> {
+            let data_file = NamedTempFile::new()?;
+            let index_file = NamedTempFile::new()?;
+            let values: Vec<Vec<u8>> = (0..keys.len()).map(|i| format!("value-{}", i).into_bytes()).collect();
+
+            Database::<N>::write_database(
+                data_file.path(),
+                index_file.path(),
+                keys.iter(),
+                values.iter().map(|v| v.as_slice()),
+                1,
+            )?;
+
+            // Keys are stored at their own width
+            let index_len = std::fs::metadata(index_file.path())?.len();
+            assert!(index_len < (IndexHeader::SIZE + keys.len() * (N + 8)) as u64 + 1024);
+
+            let db = Database::<N>::open(data_file.path(), index_file.path())?;
+            for (key, value) in keys.iter().zip(values.iter()) {
+                assert_eq!(db.get(key), Some(value.as_slice()));
+            }
+            assert!(db.get(&[0xff; N]).is_none());
+            Ok(())
+        }
+
+        let u64_keys: Vec<Key<8>> = (1..100u64).map(|i| i.to_le_bytes()).collect();
+        round_trip(&u64_keys)?;
+
+        let odd_keys: Vec<Key<5>> = (1..100u8).map(|i| [i, i, 0, 1, 2]).collect();
+        round_trip(&odd_keys)?;
+
+        let digests: Vec<Key<32>> = (1..100u8).map(|i| [i; 32]).collect();
+        round_trip(&digests)
+    }
+
+    #[test]
+    fn test_key_size_validated_on_open() -> io::Result<()> {
+        let data_file = NamedTempFile::new()?;
+        let index_file = NamedTempFile::new()?;
+
+        let keys: Vec<Key<8>> = vec![1u64.to_le_bytes(), 2u64.to_le_bytes()];
+        Database::<8>::write_database(data_file.path(), index_file.path(), keys.iter(), keys.iter(), 1)?;
+
+        let err = Database::<KEY_SIZE>::open(data_file.path(), index_file.path()).err().expect("Open should fail");
+        assert!(matches!(err, Error::KeySizeMismatch { stored: 8, requested: KEY_SIZE }));
+        let err = VarDatabase::open(data_file.path(), index_file.path()).err().expect("Open should fail");
+        assert!(matches!(err, Error::KeySizeMismatch { stored: 8, requested: VAR_KEY_SIZE }));
+        assert!(Database::<8>::open(data_file.path(), index_file.path()).is_ok());
+
+        // The width can be read before picking `N`
+        assert_eq!(stored_key_size(data_file.path(), index_file.path())?, 8);
+        let single_file = NamedTempFile::new()?;
+        let options = WriteOptions::default();
+        VarDatabase::write_database_single(single_file.path(), [b"key"].iter(), [b"value"].iter(), 1, &options)?;
+        assert_eq!(stored_key_size_single(single_file.path())?, VAR_KEY_SIZE);
+        assert!(stored_key_size(single_file.path(), single_file.path()).is_err());
+
+        // Keys of the wrong width are rejected when writing
+        let err = Database::<8>::write_database(
+            data_file.path(),
+            index_file.path(),
+            [&b"short"[..]].iter(),
+            [&b"value"[..]].iter(),
+            1,
+        )
+        .expect_err("Write should fail");
+        assert!(matches!(err, Error::InvalidKeyLength { expected: 8, got: 5 }));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_fingerprint_and_trusted_modes() -> io::Result<()> {
+        fn write_and_open<const N: usize>(
+            keys: &[Vec<u8>],
+            key_check: KeyCheck,
+        ) -> Result<(Database<N>, u64, NamedTempFile, NamedTempFile)> {
+            let data_file = NamedTempFile::new()?;
+            let index_file = NamedTempFile::new()?;
+            let options = WriteOptions { key_check, ..Default::default() };
+            Database::<N>::write_database_with_options(
+                data_file.path(),
+                index_file.path(),
+                keys.iter(),
+                keys.iter().map(|k| [b"v-", k.as_slice()].concat()),
+                1,
+                &options,
+            )?;
+            let index_len = std::fs::metadata(index_file.path())?.len();
+            let db = Database::<N>::open(data_file.path(), index_file.path())?;
+            Ok((db, index_len, data_file, index_file))
+        }
+
+        let fixed_keys: Vec<Vec<u8>> = (0..1000u64).map(|i| [i.to_le_bytes(), [7; 8]].concat()).collect();
+        let var_keys: Vec<Vec<u8>> = (0..1000u64).map(|i| format!("user:{}", i).into_bytes()).collect();
+
+        let (_, full_len, ..) = write_and_open::<KEY_SIZE>(&fixed_keys, KeyCheck::Keys)?;
+        for bits in [8, 16, 32] {
+            let (db, len, ..) = write_and_open::<KEY_SIZE>(&fixed_keys, KeyCheck::Fingerprint { bits })?;
+            assert!(len < full_len);
+            for key in &fixed_keys {
+                assert_eq!(db.get(key), Some(&[b"v-", key.as_slice()].concat()[..]));
+            }
+
+            let (db, ..) = write_and_open::<VAR_KEY_SIZE>(&var_keys, KeyCheck::Fingerprint { bits })?;
+            for key in &var_keys {
+                assert_eq!(db.get(key), Some(&[b"v-", key.as_slice()].concat()[..]));
+            }
+
+            // Absent keys are rejected up to the documented false-positive rate
+            let false_positives = (0..10_000u32)
+                .filter(|i| db.get(format!("absent:{}", i).as_bytes()).is_some())
+                .count();
+            assert!(false_positives <= 10_000 >> (bits - 4), "{} false positives at {} bits", false_positives, bits);
+        }
+
+        let (db, len, ..) = write_and_open::<VAR_KEY_SIZE>(&var_keys, KeyCheck::Trusted)?;
+        assert!(len < full_len);
+        for key in &var_keys {
+            assert_eq!(db.get(key), Some(&[b"v-", key.as_slice()].concat()[..]));
+        }
+
+        let err = write_and_open::<KEY_SIZE>(&fixed_keys, KeyCheck::Fingerprint { bits: 12 })
+            .err()
+            .expect("Write should fail");
+        assert!(matches!(err, Error::InvalidArgument(_)));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_single_file_round_trip() -> io::Result<()> {
+        let fixed_file = NamedTempFile::new()?;
+        let keys: Vec<Key> = (0..300u128).map(|i| i.to_le_bytes()).collect();
+        let values: Vec<Vec<u8>> = (0..300u32).map(|i| format!("value-{}", i).into_bytes()).collect();
+        Database::<KEY_SIZE>::write_database_single(
+            fixed_file.path(),
+            keys.iter(),
+            values.iter(),
+            1,
+            &WriteOptions::default(),
+        )?;
+
+        let db = Database::<KEY_SIZE>::open_single(fixed_file.path())?;
+        assert_eq!(db.len(), 300);
+        for (key, value) in keys.iter().zip(&values) {
+            assert_eq!(db.get(key), Some(value.as_slice()));
+        }
+        assert_eq!(db.get(&[0xff; KEY_SIZE]), None);
+
+        let var_file = NamedTempFile::new()?;
+        let var_keys: Vec<Vec<u8>> = (0..300u32).map(|i| format!("user:{}", i).into_bytes()).collect();
+        VarDatabase::write_database_single(var_file.path(), var_keys.iter(), values.iter(), 1, &WriteOptions::default())?;
+
+        let db = VarDatabase::open_single(var_file.path())?;
+        for (key, value) in var_keys.iter().zip(&values) {
+            assert_eq!(db.get(key), Some(value.as_slice()));
+        }
+        assert_eq!(db.iter()?.count(), 300);
+
+        // The key width is still validated against the metadata section
+        assert!(Database::<KEY_SIZE>::open_single(var_file.path()).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_single_file_rejects_corrupt_directory() -> io::Result<()> {
+        let file = NamedTempFile::new()?;
+        let keys: Vec<Key> = (0..10u128).map(|i| i.to_le_bytes()).collect();
+        Database::<KEY_SIZE>::write_database_single(file.path(), keys.iter(), keys.iter(), 1, &WriteOptions::default())?;
+        let bytes = std::fs::read(file.path())?;
+
+        // Bad magic
+        let mut corrupt = bytes.clone();
+        corrupt[0] = b'X';
+        std::fs::write(file.path(), &corrupt)?;
+        let err = Database::<KEY_SIZE>::open_single(file.path()).err().expect("Open should fail");
+        assert!(matches!(err, Error::BadMagic { section: "KVDB header" }));
+
+        // A section pointing past the end of the file
+        let mut corrupt = bytes.clone();
+        let entry = FileHeader::SIZE + SectionEntry::SIZE;
+        corrupt[entry + 8..entry + 16].copy_from_slice(&u64::MAX.to_le_bytes());
+        std::fs::write(file.path(), &corrupt)?;
+        let err = Database::<KEY_SIZE>::open_single(file.path()).err().expect("Open should fail");
+        assert!(matches!(err, Error::Truncated { .. }));
+
+        // Truncated in the middle of the values
+        std::fs::write(file.path(), &bytes[..bytes.len() - 4])?;
+        assert!(Database::<KEY_SIZE>::open_single(file.path()).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_duplicate_keys_are_reported() -> io::Result<()> {
+        let file = NamedTempFile::new()?;
+        let keys: Vec<&[u8]> = vec![b"alpha", b"beta", b"gamma", b"beta"];
+        let err = VarDatabase::write_database_single(file.path(), keys.iter(), keys.iter(), 1, &WriteOptions::default())
+            .expect_err("Write should fail");
+        assert!(matches!(err, Error::DuplicateKey { key } if key == b"beta"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_damaged_files_are_errors_not_panics() -> io::Result<()> {
+        let file = NamedTempFile::new()?;
+        let keys: Vec<Vec<u8>> = (0..50u32).map(|i| format!("key-{}", i).into_bytes()).collect();
+        VarDatabase::write_database_single(file.path(), keys.iter(), keys.iter(), 1, &WriteOptions::default())?;
+        let bytes = std::fs::read(file.path())?;
+
+        // Every prefix of the file, and the file with each header byte flipped
+        for len in (0..bytes.len()).step_by(7) {
+            std::fs::write(file.path(), &bytes[..len])?;
+            assert!(VarDatabase::open_single(file.path()).is_err());
+        }
+        for at in 0..1024.min(bytes.len()) {
+            let mut corrupt = bytes.clone();
+            corrupt[at] ^= 0x5a;
+            std::fs::write(file.path(), &corrupt)?;
+            if let Ok(db) = VarDatabase::open_single(file.path()) {
+                db.verify();
+                for key in &keys {
+                    db.get(key);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_index_is_bound_to_its_data_file() -> io::Result<()> {
+        let dir = tempfile::TempDir::new()?;
+        let (path_data, path_index) = (dir.path().join("db.data"), dir.path().join("db.index"));
+        let keys: Vec<Key> = (0..100u128).map(|i| i.to_le_bytes()).collect();
+        let old_values: Vec<[u8; 8]> = (0..100u64).map(|i| i.to_le_bytes()).collect();
+        let new_values: Vec<[u8; 8]> = (0..100u64).map(|i| (i + 1000).to_le_bytes()).collect();
+
+        // Two rewrites with the same shape: key count, widths and version
+        Database::<KEY_SIZE>::write_database(&path_data, &path_index, keys.iter(), old_values.iter(), 1)?;
+        let old_data = std::fs::read(&path_data)?;
+        Database::<KEY_SIZE>::write_database(&path_data, &path_index, keys.iter(), new_values.iter(), 1)?;
+        assert_eq!(Database::<KEY_SIZE>::open(&path_data, &path_index)?.get(&keys[5]), Some(&new_values[5][..]));
+
+        // The index of the second next to the data of the first is rejected
+        std::fs::write(data_slot_path(&path_data, 1), &old_data)?;
+        let err = Database::<KEY_SIZE>::open(&path_data, &path_index).err().expect("Open should fail");
+        assert!(matches!(err, Error::ChecksumMismatch { section: "headers" }), "{}", err);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_rewrites_replace_databases_atomically() -> io::Result<()> {
+        let dir = tempfile::TempDir::new()?;
+        let (path_data, path_index) = (dir.path().join("db.data"), dir.path().join("db.index"));
+        let path_single = dir.path().join("db.kvdb");
+        let options = WriteOptions::default();
+        let keys: Vec<Key> = (0..100u128).map(|i| i.to_le_bytes()).collect();
+        let old_values: Vec<[u8; 4]> = (0..100u32).map(|i| i.to_le_bytes()).collect();
+        let new_values: Vec<[u8; 8]> = (0..100u64).map(|i| (i * 3).to_le_bytes()).collect();
+
+        Database::<KEY_SIZE>::write_database(&path_data, &path_index, keys.iter(), old_values.iter(), 1)?;
+        Database::<KEY_SIZE>::write_database_single(&path_single, keys.iter(), old_values.iter(), 1, &options)?;
+        let old = Database::<KEY_SIZE>::open(&path_data, &path_index)?;
+        let old_single = Database::<KEY_SIZE>::open_single(&path_single)?;
+
+        // A failed build leaves the published database untouched
+        let duplicates = [keys[0], keys[0]];
+        let result = Database::<KEY_SIZE>::write_database(&path_data, &path_index, duplicates.iter(), keys.iter(), 2);
+        assert!(result.is_err());
+        assert_eq!(Database::<KEY_SIZE>::open(&path_data, &path_index)?.get(&keys[7]), Some(&old_values[7][..]));
+
+        // Readers of the old files keep seeing them after a rewrite
+        Database::<KEY_SIZE>::write_database(&path_data, &path_index, keys.iter(), new_values.iter(), 2)?;
+        Database::<KEY_SIZE>::write_database_single(&path_single, keys.iter(), new_values.iter(), 2, &options)?;
+        let new = Database::<KEY_SIZE>::open(&path_data, &path_index)?;
+        let new_single = Database::<KEY_SIZE>::open_single(&path_single)?;
+        for (i, key) in keys.iter().enumerate() {
+            assert_eq!(old.get(key), Some(&old_values[i][..]));
+            assert_eq!(old_single.get(key), Some(&old_values[i][..]));
+            assert_eq!(new.get(key), Some(&new_values[i][..]));
+            assert_eq!(new_single.get(key), Some(&new_values[i][..]));
+        }
+
+        // No temporary files are left behind
+        let mut names: Vec<_> =
+            std::fs::read_dir(dir.path())?.map(|e| e.map(|e| e.file_name())).collect::<io::Result<_>>()?;
+        names.sort();
+        assert_eq!(names, ["db.data.alt", "db.index", "db.kvdb"]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_crash_before_the_index_is_published_keeps_the_old_database() -> io::Result<()> {
+        let dir = tempfile::TempDir::new()?;
+        let (path_data, path_index) = (dir.path().join("db.data"), dir.path().join("db.index"));
+        let keys: Vec<Key> = (0..100u128).map(|i| i.to_le_bytes()).collect();
+        let values: Vec<Vec<[u8; 8]>> =
+            (0..3u64).map(|round| (0..100u64).map(|i| (i + round * 1000).to_le_bytes()).collect()).collect();
+
+        Database::<KEY_SIZE>::write_database(&path_data, &path_index, keys.iter(), values[0].iter(), 1)?;
+        let old_index = std::fs::read(&path_index)?;
+
+        // A second rewrite that crashed after writing its data file but before
+        // renaming its index leaves that data file in the free slot
+        let (other_data, other_index) = (dir.path().join("other.data"), dir.path().join("other.index"));
+        Database::<KEY_SIZE>::write_database(&other_data, &other_index, keys.iter(), values[1].iter(), 1)?;
+        std::fs::rename(&other_data, data_slot_path(&path_data, 1))?;
+        std::fs::remove_file(&other_index)?;
+        assert_eq!(std::fs::read(&path_index)?, old_index);
+
+        // The old database still opens, with its own values
+        let db = Database::<KEY_SIZE>::open(&path_data, &path_index)?;
+        for (key, value) in keys.iter().zip(&values[0]) {
+            assert_eq!(db.get(key), Some(&value[..]));
+        }
+        assert_eq!(stored_key_size(&path_data, &path_index)?, KEY_SIZE);
+
+        // The next rewrite replaces the leftover and publishes normally
+        Database::<KEY_SIZE>::write_database(&path_data, &path_index, keys.iter(), values[2].iter(), 1)?;
+        let new = Database::<KEY_SIZE>::open(&path_data, &path_index)?;
+        for (i, key) in keys.iter().enumerate() {
+            assert_eq!(db.get(key), Some(&values[0][i][..]));
+            assert_eq!(new.get(key), Some(&values[2][i][..]));
+        }
+        assert!(!path_data.exists());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_fixed_width_values_need_no_offsets() -> io::Result<()> {
+        let dir = tempfile::TempDir::new()?;
+        let keys: Vec<Key> = (0..1000u128).map(|i| i.to_le_bytes()).collect();
+        let embeddings: Vec<[u8; 64]> = (0..1000u32).map(|i| [i as u8; 64]).collect();
+        let write = |name: &str, value_width: ValueWidth| -> Result<(Database, u64)> {
+            let (data, index) = (dir.path().join(format!("{}.data", name)), dir.path().join(format!("{}.index", name)));
+            let options = WriteOptions { value_width, value_block_size: Some(4096), ..Default::default() };
+            let values = embeddings.iter();
+            Database::<KEY_SIZE>::write_database_with_options(&data, &index, keys.iter(), values, 1, &options)?;
+            Ok((Database::open(&data, &index)?, std::fs::metadata(&index)?.len()))
+        };
+
+        // Detected by default, or given explicitly; the index shrinks by the offsets
+        let (detected, detected_len) = write("detected", ValueWidth::Detect)?;
+        let (fixed, _) = write("fixed", ValueWidth::Fixed(64))?;
+        let (variable, variable_len) = write("variable", ValueWidth::Variable)?;
+        assert_eq!((detected.value_width(), fixed.value_width(), variable.value_width()), (Some(64), Some(64), None));
+        assert_eq!(variable_len - detected_len, 8 * keys.len() as u64);
+        for db in [&detected, &fixed] {
+            assert!(db.verify().is_empty());
+            assert_eq!(db.get(&keys[123]), Some(&embeddings[123][..]));
+            assert_eq!(db.get_many(&keys), embeddings.iter().map(|v| Some(&v[..])).collect::<Vec<_>>());
+            assert_eq!(db.values().count(), 1000);
+        }
+
+        // Values of another width are rejected up front
+        let path = dir.path().join("counters.kvdb");
+        let options = WriteOptions { value_width: ValueWidth::Fixed(8), ..Default::default() };
+        let err = Database::<KEY_SIZE>::write_database_single(&path, keys.iter(), keys.iter(), 1, &options)
+            .expect_err("Write should fail");
+        assert!(matches!(err, Error::InvalidValueLength { expected: 8, got: KEY_SIZE }));
+
+        // Empty values take no space at all
+        let empty: Vec<&[u8]> = vec![b""; keys.len()];
+        Database::<KEY_SIZE>::write_database_single(&path, keys.iter(), empty.iter(), 1, &WriteOptions::default())?;
+        let db = Database::<KEY_SIZE>::open_single(&path)?;
+        assert_eq!((db.value_width(), db.get(&keys[5])), (Some(0), Some(&b""[..])));
+        assert_eq!(db.get(&[0xff; KEY_SIZE]), None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_compressed_offsets() -> io::Result<()> {
+        let dir = tempfile::TempDir::new()?;
+        let keys: Vec<Vec<u8>> = (0..20_000u32).map(|i| format!("key-{}", i).into_bytes()).collect();
+        let values: Vec<Vec<u8>> = (0..20_000u32).map(|i| vec![i as u8; i as usize % 300]).collect();
+        let (data, index) = (dir.path().join("db.data"), dir.path().join("db.index"));
+        let write = |compress_offsets: bool, values: &[Vec<u8>]| -> io::Result<VarDatabase> {
+            let options = WriteOptions { compress_offsets, ..Default::default() };
+            VarDatabase::write_database_with_options(&data, &index, keys.iter(), values.iter(), 1, &options)?;
+            Ok(VarDatabase::open(&data, &index)?)
+        };
+
+        let plain = write(false, &values)?;
+        let plain_len = std::fs::metadata(&index)?.len();
+        let compressed = write(true, &values)?;
+        let compressed_len = std::fs::metadata(&index)?.len();
+        assert!(!plain.has_compressed_offsets() && compressed.has_compressed_offsets());
+        assert!(plain_len - compressed_len > 6 * keys.len() as u64, "{} vs {} bytes", compressed_len, plain_len);
+        assert!(compressed.verify().is_empty());
+        let expected: Vec<Option<&[u8]>> = values.iter().map(|v| Some(v.as_slice())).collect();
+        assert_eq!(compressed.get_many(&keys), expected);
+        assert_eq!(compressed.get(b"key-299"), Some(&values[299][..]));
+
+        // Values too large for the encoding keep plain offsets
+        let large: Vec<Vec<u8>> = (0..20_000u32).map(|i| vec![0; 1000 + i as usize % 2]).collect();
+        let db = write(true, &large)?;
+        assert!(!db.has_compressed_offsets());
+        assert_eq!(db.get(b"key-7"), Some(&large[7][..]));
+
+        // Damaged compressed offsets are caught on open
+        write(true, &values)?;
+        let offsets_start = compressed.sections.offsets.start;
+        let mut bytes = std::fs::read(&index)?;
+        bytes[offsets_start + 100] ^= 0x10;
+        std::fs::write(&index, &bytes)?;
+        let err = VarDatabase::open(&data, &index).err().expect("Open should fail");
+        assert!(matches!(err, Error::ChecksumMismatch { section: "offsets section" }));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_deduplicated_values() -> io::Result<()> {
+        let dir = tempfile::TempDir::new()?;
+        let path = dir.path().join("db.kvdb");
+        // 20000 keys sharing 50 distinct values of different lengths
+        let keys: Vec<Vec<u8>> = (0..20_000u32).map(|i| format!("user-{}", i).into_bytes()).collect();
+        let templates: Vec<Vec<u8>> = (0..50u32).map(|i| vec![i as u8; 200 + i as usize]).collect();
+        let values: Vec<&[u8]> = (0..20_000).map(|i| &templates[i % 50][..]).collect();
+
+        let options = WriteOptions { dedup_values: true, value_block_size: Some(1024), ..Default::default() };
+        // A small buffer so the values are reordered through the scratch file
+        let mut builder = VarDatabase::builder(dir.path(), options)?.with_buffer_size(4096);
+        for (key, value) in keys.iter().zip(&values) {
+            builder.insert(key, value)?;
+        }
+        builder.finish_single(&path, 1)?;
+
+        let db = VarDatabase::open_single(&path)?;
+        assert!(db.has_shared_values());
+        assert_eq!(db.sections.values.len(), templates.iter().map(Vec::len).sum::<usize>());
+        assert!(db.verify().is_empty());
+        assert_eq!(db.get(b"user-1234"), Some(values[1234]));
+        assert_eq!(db.get_many(&keys), values.iter().map(|&v| Some(v)).collect::<Vec<_>>());
+        assert_eq!(db.get(b"user-20000"), None);
+
+        // The pairs cannot be compressed, and every value would be its own width
+        for options in [
+            WriteOptions { dedup_values: true, compress_offsets: true, ..Default::default() },
+            WriteOptions { dedup_values: true, value_width: ValueWidth::Fixed(8), ..Default::default() },
+        ] {
+            let err = VarDatabase::builder(dir.path(), options).err().expect("Builder should fail");
+            assert!(matches!(err, Error::InvalidArgument(_)));
+        }
+
+        Ok(())
+    }
 }
@@ -0,0 +1,188 @@
+use crate::database::Database;
+use crate::error::Result;
+use crate::overlay::Delta;
+use rayon::prelude::*;
+use std::fmt;
+use std::path::Path;
+
+// A key that differs between two databases, with the sizes of its values
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Change<'a> {
+    Added { key: &'a [u8], new_size: usize },
+    Removed { key: &'a [u8], old_size: usize },
+    Changed { key: &'a [u8], old_size: usize, new_size: usize },
+}
+
+impl<'a> Change<'a> {
+    pub fn key(&self) -> &'a [u8] {
+        match *self {
+            Change::Added { key, .. } | Change::Removed { key, .. } | Change::Changed { key, .. } => key,
+        }
+    }
+}
+
+// Counts of a diff, and the value bytes involved
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DiffSummary {
+    pub added: usize,
+    pub removed: usize,
+    pub changed: usize,
+    pub unchanged: usize,
+    pub added_bytes: u64,       // values of added keys
+    pub removed_bytes: u64,     // values of removed keys
+    pub changed_old_bytes: u64, // old values of changed keys
+    pub changed_new_bytes: u64, // new values of changed keys
+}
+
+impl fmt::Display for DiffSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} added ({} bytes), {} removed ({} bytes), {} changed ({} -> {} bytes), {} unchanged",
+            self.added,
+            self.added_bytes,
+            self.removed,
+            self.removed_bytes,
+            self.changed,
+            self.changed_old_bytes,
+            self.changed_new_bytes,
+            self.unchanged
+        )
+    }
+}
+
+// Differences between an old and a new version of a database, from `diff`
+pub struct Diff<'a, const N: usize> {
+    new: &'a Database<N>,
+    changes: Vec<Change<'a>>,
+    unchanged: usize,
+}
+
+// Compares every key of `old` and `new`. Both databases must store their keys
+// (see `Database::has_keys`); they are scanned in parallel on the current
+// thread pool, and only the changed keys are kept.
+pub fn diff<'a, const N: usize>(old: &'a Database<N>, new: &'a Database<N>) -> Result<Diff<'a, N>> {
+    let num_chunks = rayon::current_num_threads() * 4;
+
+    // Keys of `old` that were removed or changed; every other key of `old` is unchanged
+    let old_changes: Vec<Change<'a>> = old
+        .par_chunks(num_chunks)?
+        .into_par_iter()
+        .flat_map_iter(|chunk| {
+            chunk.filter_map(|(key, old_value)| match new.get(&key) {
+                None => Some(Change::Removed { key: key.as_bytes(), old_size: old_value.len() }),
+                Some(new_value) if new_value != old_value => {
+                    Some(Change::Changed { key: key.as_bytes(), old_size: old_value.len(), new_size: new_value.len() })
+                }
+                Some(_) => None,
+            })
+        })
+        .collect();
+    let unchanged = old.len() - old_changes.len();
+
+    // Keys only in `new`
+    let added = new
+        .par_chunks(num_chunks)?
+        .into_par_iter()
+        .flat_map_iter(|chunk| {
+            chunk
+                .filter(|&(key, _)| old.get(&key).is_none())
+                .map(|(key, value)| Change::Added { key: key.as_bytes(), new_size: value.len() })
+        })
+        .collect::<Vec<_>>();
+
+    let mut changes: Vec<Change<'a>> = old_changes.into_iter().chain(added).collect();
+    changes.par_sort_unstable_by(|a, b| a.key().cmp(b.key()));
+    Ok(Diff { new, changes, unchanged })
+}
+
+impl<'a, const N: usize> Diff<'a, N> {
+    // Changed keys, in key order
+    pub fn changes(&self) -> &[Change<'a>] {
+        &self.changes
+    }
+
+    pub fn summary(&self) -> DiffSummary {
+        let mut summary = DiffSummary { unchanged: self.unchanged, ..Default::default() };
+        for change in &self.changes {
+            match *change {
+                Change::Added { new_size, .. } => {
+                    summary.added += 1;
+                    summary.added_bytes += new_size as u64;
+                }
+                Change::Removed { old_size, .. } => {
+                    summary.removed += 1;
+                    summary.removed_bytes += old_size as u64;
+                }
+                Change::Changed { old_size, new_size, .. } => {
+                    summary.changed += 1;
+                    summary.changed_old_bytes += old_size as u64;
+                    summary.changed_new_bytes += new_size as u64;
+                }
+            }
+        }
+        summary
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.changes.is_empty()
+    }
+
+    // Writes the diff as a delta file (see `OverlayDatabase`) that turns the old
+    // database into the new one: added and changed keys are upserted with their
+    // new value, removed keys are deleted
+    pub fn write_delta<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let entries = self.changes.iter().map(|change| match *change {
+            Change::Removed { key, .. } => (key, None),
+            Change::Added { key, .. } | Change::Changed { key, .. } => (key, self.new.get(key)),
+        });
+        Delta::write(path, N, entries)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::{VarDatabase, WriteOptions};
+    use crate::overlay::OverlayDatabase;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_diff_and_delta_turn_old_into_new() -> Result<()> {
+        let dir = TempDir::new()?;
+        let write = |name: &str, entries: &[(String, String)]| -> Result<VarDatabase> {
+            let path = dir.path().join(name);
+            let (keys, values): (Vec<_>, Vec<_>) = entries.iter().cloned().unzip();
+            VarDatabase::write_database_single(&path, keys.iter(), values.iter(), 1, &WriteOptions::default())?;
+            VarDatabase::open_single(&path)
+        };
+        // Keys 0..1000 become 100..1100, and every 7th value grows
+        let old_entries: Vec<_> = (0..1000).map(|i| (format!("k{}", i), format!("v{}", i))).collect();
+        let new_entries: Vec<_> = (100..1100)
+            .map(|i| (format!("k{}", i), if i % 7 == 0 { format!("v{}-new", i) } else { format!("v{}", i) }))
+            .collect();
+        let old = write("old.kvdb", &old_entries)?;
+        let new = write("new.kvdb", &new_entries)?;
+
+        let diff = diff(&old, &new)?;
+        let summary = diff.summary();
+        let changed = (100..1000).filter(|i| i % 7 == 0).count();
+        assert_eq!((summary.added, summary.removed, summary.changed), (100, 100, changed));
+        assert_eq!(summary.unchanged, 900 - changed);
+        assert_eq!(summary.changed_new_bytes - summary.changed_old_bytes, 4 * changed as u64);
+        assert!(diff.changes().windows(2).all(|pair| pair[0].key() < pair[1].key()));
+        assert!(diff.changes().contains(&Change::Changed { key: b"k700", old_size: 4, new_size: 8 }));
+        assert!(super::diff(&new, &new)?.is_empty());
+
+        // The old database with the delta on top reads like the new one
+        let delta_path = dir.path().join("old-to-new.kvdl");
+        diff.write_delta(&delta_path)?;
+        let patched = OverlayDatabase::open(VarDatabase::open_single(dir.path().join("old.kvdb"))?, &delta_path)?;
+        assert_eq!(patched.delta().len(), 200 + changed);
+        for (key, _) in old_entries.iter().chain(&new_entries) {
+            assert_eq!(patched.get(key.as_bytes()), new.get(key.as_bytes()));
+        }
+
+        Ok(())
+    }
+}
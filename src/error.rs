@@ -0,0 +1,120 @@
+use crate::verify::Corruption;
+use std::{fmt, io};
+
+// Errors returned by the public APIs of this crate
+#[derive(Debug)]
+pub enum Error {
+    // Reading or writing a file failed
+    Io(io::Error),
+    // A header does not start with its magic bytes
+    BadMagic { section: &'static str },
+    // A header has a version this build cannot read
+    UnsupportedVersion { section: &'static str, version: u32 },
+    // The data and index headers disagree on the number of keys
+    KeyCountMismatch { data: u64, index: u64 },
+    // The database was opened with a different key width than it was built with
+    KeySizeMismatch { stored: u64, requested: usize },
+    // A header or section extends past the end of its file
+    Truncated { section: &'static str },
+    // A section does not have the size implied by the headers
+    SectionSizeMismatch { section: &'static str, size: u64, expected: u64 },
+    // A section cannot be viewed in place at its offset
+    Misaligned { section: &'static str },
+    // A header field has a value that is not valid for this format
+    InvalidHeader(String),
+    // A checksum stored in a header does not match
+    ChecksumMismatch { section: &'static str },
+    // `OpenOptions::verify_on_open` found a corrupt section
+    Corrupt(Corruption),
+    // A key does not have the width of the database
+    InvalidKeyLength { expected: usize, got: usize },
+    // A value does not have the width given by `WriteOptions::value_width`
+    InvalidValueLength { expected: usize, got: usize },
+    // The same key was given more than once
+    DuplicateKey { key: Vec<u8> },
+    // Different numbers of keys and values were given
+    LengthMismatch { keys: usize, values: usize },
+    // A typed database was opened for another value type or codec
+    ValueTypeMismatch { requested: &'static str },
+    // Encoding or decoding a typed value failed
+    Codec(String),
+    // Building, serializing or deserializing the MPHF failed
+    Mphf(String),
+    // An option or argument is out of range
+    InvalidArgument(String),
+    // The operation needs something this database does not store, or this host does not support
+    Unsupported(&'static str),
+}
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "I/O error: {}", e),
+            Error::BadMagic { section } => write!(f, "Bad magic in {}", section),
+            Error::UnsupportedVersion { section, version } => write!(f, "Unsupported {} version {}", section, version),
+            Error::KeyCountMismatch { data, index } => {
+                write!(f, "Key count mismatch: data file has {} keys, index file has {}", data, index)
+            }
+            Error::KeySizeMismatch { stored, requested } => write!(
+                f,
+                "Key size mismatch: database has {}-byte keys, opened with {}-byte keys",
+                stored, requested
+            ),
+            Error::Truncated { section } => write!(f, "Truncated {}", section),
+            Error::SectionSizeMismatch { section, size, expected } => {
+                write!(f, "Database {} section has {} bytes, expected {}", section, size, expected)
+            }
+            Error::Misaligned { section } => write!(f, "Misaligned {} section", section),
+            Error::InvalidHeader(reason) => write!(f, "Invalid header: {}", reason),
+            Error::ChecksumMismatch { section } => write!(f, "Checksum mismatch in {}", section),
+            Error::Corrupt(corruption) => write!(f, "Corrupt database: {}", corruption),
+            Error::InvalidKeyLength { expected, got } => write!(f, "Key must be {} bytes, got {}", expected, got),
+            Error::InvalidValueLength { expected, got } => write!(f, "Value must be {} bytes, got {}", expected, got),
+            Error::DuplicateKey { key } => write!(f, "Duplicate key {:?}", String::from_utf8_lossy(key)),
+            Error::LengthMismatch { keys, values } => write!(f, "Got {} keys but {} values", keys, values),
+            Error::ValueTypeMismatch { requested } => {
+                write!(f, "Value type mismatch: database does not hold bincode-encoded {} values", requested)
+            }
+            Error::Codec(reason) => write!(f, "Value codec error: {}", reason),
+            Error::Mphf(reason) => write!(f, "MPHF error: {}", reason),
+            Error::InvalidArgument(reason) => write!(f, "Invalid argument: {}", reason),
+            Error::Unsupported(reason) => write!(f, "Unsupported: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+// Lets callers working with `io::Result` use `?` on this crate's results
+impl From<Error> for io::Error {
+    fn from(e: Error) -> Self {
+        if let Error::Io(e) = e {
+            return e;
+        }
+        let kind = match &e {
+            Error::InvalidKeyLength { .. }
+            | Error::InvalidValueLength { .. }
+            | Error::DuplicateKey { .. }
+            | Error::LengthMismatch { .. }
+            | Error::InvalidArgument(_) => io::ErrorKind::InvalidInput,
+            Error::Unsupported(_) => io::ErrorKind::Unsupported,
+            _ => io::ErrorKind::InvalidData,
+        };
+        io::Error::new(kind, e)
+    }
+}
@@ -0,0 +1,492 @@
+use crate::database::{Database, OpenOptions, KEY_SIZE};
+use crate::error::{Error, Result};
+use crate::writer::spill_dir;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, PoisonError, RwLock, Weak};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, SystemTime};
+use std::fs;
+
+// Files a `DatabaseHandle` loads its database from
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DatabaseFiles {
+    TwoFiles { data: PathBuf, index: PathBuf },
+    SingleFile(PathBuf),
+}
+
+impl DatabaseFiles {
+    fn open<const N: usize>(&self, options: &OpenOptions) -> Result<Database<N>> {
+        match self {
+            DatabaseFiles::TwoFiles { data, index } => Database::open_with_options(data, index, options),
+            DatabaseFiles::SingleFile(path) => Database::open_single_with_options(path, options),
+        }
+    }
+
+    fn paths(&self) -> Vec<&Path> {
+        match self {
+            DatabaseFiles::TwoFiles { data, index } => vec![data, index],
+            DatabaseFiles::SingleFile(path) => vec![path],
+        }
+    }
+}
+
+// A database that can be replaced while it is in use. `load` returns the
+// current generation and `reload` opens a new one and swaps it in. Readers
+// holding an earlier generation keep its mappings alive until they drop it, so
+// lookups in flight are never affected by a reload.
+pub struct DatabaseHandle<const N: usize = KEY_SIZE> {
+    current: RwLock<Arc<Database<N>>>,
+    // Held for the whole of a reload, so reloads are applied in order. Along
+    // with the files, their stamp from just before they were opened.
+    files: Mutex<(DatabaseFiles, Vec<FileStamp>)>,
+    options: OpenOptions,
+    generation: AtomicU64,
+    // Change notifications of the running watchers, pointed at the new files on every reload
+    watchers: Mutex<Vec<Weak<Changes>>>,
+}
+
+impl<const N: usize> DatabaseHandle<N> {
+    pub fn open(files: DatabaseFiles, options: OpenOptions) -> Result<Self> {
+        let stamp = files_stamp(&files);
+        let db = files.open(&options)?;
+        Ok(DatabaseHandle {
+            current: RwLock::new(Arc::new(db)),
+            files: Mutex::new((files, stamp)),
+            options,
+            generation: AtomicU64::new(0),
+            watchers: Mutex::new(Vec::new()),
+        })
+    }
+
+    // Current generation of the database
+    pub fn load(&self) -> Arc<Database<N>> {
+        self.current.read().unwrap_or_else(PoisonError::into_inner).clone()
+    }
+
+    // Number of reloads so far
+    pub fn generation(&self) -> u64 {
+        self.generation.load(Ordering::Acquire)
+    }
+
+    // Files the current generation was loaded from
+    pub fn files(&self) -> DatabaseFiles {
+        self.loaded().0
+    }
+
+    fn loaded(&self) -> (DatabaseFiles, Vec<FileStamp>) {
+        self.files.lock().unwrap_or_else(PoisonError::into_inner).clone()
+    }
+
+    // Opens the two-file database at `data` and `index` and makes it current.
+    // Returns the new generation; on failure the current one is kept.
+    pub fn reload<P: AsRef<Path>>(&self, data: P, index: P) -> Result<u64> {
+        let (data, index) = (data.as_ref().to_path_buf(), index.as_ref().to_path_buf());
+        self.reload_from(DatabaseFiles::TwoFiles { data, index })
+    }
+
+    // Same as `reload`, for a single-file database
+    pub fn reload_single<P: AsRef<Path>>(&self, path: P) -> Result<u64> {
+        self.reload_from(DatabaseFiles::SingleFile(path.as_ref().to_path_buf()))
+    }
+
+    // Reopens the files the current generation was loaded from
+    pub fn refresh(&self) -> Result<u64> {
+        self.reload_from(self.files())
+    }
+
+    fn reload_from(&self, files: DatabaseFiles) -> Result<u64> {
+        let mut current_files = self.files.lock().unwrap_or_else(PoisonError::into_inner);
+        // A change racing with the open leaves the files looking changed to the watchers
+        let stamp = files_stamp(&files);
+        let db = Arc::new(files.open(&self.options)?);
+        // Watching again also picks up directories that were replaced since they were watched
+        let mut watchers = self.watchers.lock().unwrap_or_else(PoisonError::into_inner);
+        watchers.retain(|changes| changes.upgrade().inspect(|changes| changes.watch(&files)).is_some());
+        drop(watchers);
+        *current_files = (files, stamp);
+
+        let mut current = self.current.write().unwrap_or_else(PoisonError::into_inner);
+        let previous = std::mem::replace(&mut *current, db);
+        let generation = self.generation.fetch_add(1, Ordering::AcqRel) + 1;
+        drop(current);
+        // The previous generation is unmapped here unless a reader still holds it
+        drop(previous);
+        Ok(generation)
+    }
+
+    // Starts a background thread that reloads the database whenever its files
+    // are replaced, e.g. by a new build being published over them. Changes are
+    // picked up through inotify on Linux, in the directories of the files of
+    // the latest reload, and by checking the files every `interval` everywhere.
+    pub fn watch(self: &Arc<Self>, interval: Duration) -> Result<Watcher> {
+        let stop = Arc::new(AtomicBool::new(false));
+        let last_error = Arc::new(Mutex::new(None));
+        let files = self.files.lock().unwrap_or_else(PoisonError::into_inner);
+        let changes = Arc::new(Changes::new(&files.0));
+        self.watchers.lock().unwrap_or_else(PoisonError::into_inner).push(Arc::downgrade(&changes));
+        drop(files);
+
+        let handle = self.clone();
+        let (thread_stop, thread_error, thread_changes) = (stop.clone(), last_error.clone(), changes.clone());
+        let thread = thread::Builder::new().name("kvfast-watcher".to_string()).spawn(move || {
+            while !thread_stop.load(Ordering::Acquire) {
+                thread_changes.wait(interval);
+                // Compared with the stamp of the last reload, from this thread or any other
+                let (files, loaded) = handle.loaded();
+                if files_stamp(&files) == loaded || thread_stop.load(Ordering::Acquire) {
+                    continue;
+                }
+                if let Err(e) = handle.reload_from(files) {
+                    *thread_error.lock().unwrap_or_else(PoisonError::into_inner) = Some(e);
+                }
+            }
+        })?;
+        Ok(Watcher { stop, last_error, changes, thread: Some(thread) })
+    }
+}
+
+// Background reloading started by `DatabaseHandle::watch`; stops when dropped
+pub struct Watcher {
+    stop: Arc<AtomicBool>,
+    last_error: Arc<Mutex<Option<Error>>>,
+    changes: Arc<Changes>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Watcher {
+    // Error of the last failed reload, if any. A reload fails for instance when
+    // the files were replaced by ones that do not open as a database; the
+    // watcher keeps the current generation and retries on the next change.
+    pub fn take_error(&self) -> Option<Error> {
+        self.last_error.lock().unwrap_or_else(PoisonError::into_inner).take()
+    }
+}
+
+impl Drop for Watcher {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Release);
+        if let Some(thread) = self.thread.take() {
+            self.changes.wake(&thread);
+            let _ = thread.join();
+        }
+    }
+}
+
+// Identity of a file: replacing it by a rename changes the inode, rewriting it
+// in place changes the length or modification time
+type FileStamp = Option<(u64, u64, Option<SystemTime>)>;
+
+fn files_stamp(files: &DatabaseFiles) -> Vec<FileStamp> {
+    files
+        .paths()
+        .into_iter()
+        .map(|path| {
+            let metadata = fs::metadata(path).ok()?;
+            #[cfg(unix)]
+            let inode = std::os::unix::fs::MetadataExt::ino(&metadata);
+            #[cfg(not(unix))]
+            let inode = 0;
+            Some((inode, metadata.len(), metadata.modified().ok()))
+        })
+        .collect()
+}
+
+// Wakes the watcher early when something changes in the directories of the
+// watched files; without inotify it just sleeps for the interval
+struct Changes {
+    #[cfg(target_os = "linux")]
+    inotify: Option<inotify::Inotify>,
+}
+
+impl Changes {
+    fn new(files: &DatabaseFiles) -> Self {
+        #[cfg(target_os = "linux")]
+        let changes = Changes { inotify: inotify::Inotify::new().ok() };
+        #[cfg(not(target_os = "linux"))]
+        let changes = Changes {};
+        changes.watch(files);
+        changes
+    }
+
+    // Watches the directories of `files` instead of those watched so far
+    fn watch(&self, files: &DatabaseFiles) {
+        let mut dirs: Vec<&Path> = files.paths().into_iter().map(spill_dir).collect();
+        dirs.dedup();
+        #[cfg(target_os = "linux")]
+        if let Some(inotify) = &self.inotify {
+            inotify.watch(&dirs);
+        }
+        #[cfg(not(target_os = "linux"))]
+        let _ = dirs;
+    }
+
+    fn wait(&self, timeout: Duration) {
+        #[cfg(target_os = "linux")]
+        if let Some(inotify) = &self.inotify {
+            return inotify.wait(timeout);
+        }
+        thread::park_timeout(timeout);
+    }
+
+    // Ends the current or next `wait` of the watcher `thread`
+    fn wake(&self, thread: &JoinHandle<()>) {
+        #[cfg(target_os = "linux")]
+        if let Some(inotify) = &self.inotify {
+            inotify.wake();
+        }
+        thread.thread().unpark();
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod inotify {
+    use std::ffi::CString;
+    use std::io;
+    use std::os::unix::ffi::OsStrExt;
+    use std::path::{Path, PathBuf};
+    use std::sync::{Mutex, PoisonError};
+    use std::time::Duration;
+
+    // How often a watched directory that does not exist is looked for
+    const REWATCH_INTERVAL: Duration = Duration::from_millis(100);
+
+    pub(super) struct Inotify {
+        fd: libc::c_int,
+        // Eventfd that `wake` signals to end a `wait`
+        wake_fd: libc::c_int,
+        // Watched directories with their watch descriptors, None while a directory cannot be watched
+        watches: Mutex<Vec<(PathBuf, Option<libc::c_int>)>>,
+    }
+
+    impl Inotify {
+        pub(super) fn new() -> io::Result<Self> {
+            // SAFETY: plain syscalls; the descriptors are owned by the returned value
+            let fd = unsafe { libc::inotify_init1(libc::IN_NONBLOCK | libc::IN_CLOEXEC) };
+            if fd < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let wake_fd = unsafe { libc::eventfd(0, libc::EFD_NONBLOCK | libc::EFD_CLOEXEC) };
+            if wake_fd < 0 {
+                let error = io::Error::last_os_error();
+                unsafe { libc::close(fd) };
+                return Err(error);
+            }
+            Ok(Inotify { fd, wake_fd, watches: Mutex::new(Vec::new()) })
+        }
+
+        // Watches `dirs` instead of the directories watched so far, and wakes
+        // the watcher: changes made before the watches were in place are only
+        // seen by looking at the files
+        pub(super) fn watch(&self, dirs: &[&Path]) {
+            let mut watches = self.watches.lock().unwrap_or_else(PoisonError::into_inner);
+            for (_, watch) in watches.drain(..) {
+                if let Some(wd) = watch {
+                    self.remove_watch(wd);
+                }
+            }
+            watches.extend(dirs.iter().map(|dir| (dir.to_path_buf(), self.add_watch(dir))));
+            drop(watches);
+            self.wake();
+        }
+
+        // Waits up to `timeout` for a change in the watched directories. Each
+        // directory is first watched again at its path: that keeps the watch of
+        // a directory still in place, and watches anew one that was replaced,
+        // or removed and created again, or whose watch the kernel dropped
+        // (`IN_IGNORED`). Such a directory may have changed while it was not
+        // watched, so the wait then ends at once; one that does not exist is
+        // looked for every REWATCH_INTERVAL. The watcher only needs to know that
+        // it should look at the files again, so the events are discarded.
+        pub(super) fn wait(&self, timeout: Duration) {
+            let (rewatched, missing) = self.rewatch();
+            if rewatched {
+                return;
+            }
+            let timeout = if missing { timeout.min(REWATCH_INTERVAL) } else { timeout };
+            let timeout = timeout.as_millis().min(i32::MAX as u128) as i32;
+            let mut pollfds = [self.fd, self.wake_fd].map(|fd| libc::pollfd { fd, events: libc::POLLIN, revents: 0 });
+            let mut buffer = [0u8; 4096];
+            // SAFETY: `pollfds` and `buffer` outlive the calls that write to them
+            unsafe {
+                if libc::poll(pollfds.as_mut_ptr(), 2, timeout) > 0 {
+                    while libc::read(self.fd, buffer.as_mut_ptr().cast(), buffer.len()) > 0 {}
+                    libc::read(self.wake_fd, buffer.as_mut_ptr().cast(), 8);
+                }
+            }
+        }
+
+        // Ends the current or next `wait`
+        pub(super) fn wake(&self) {
+            // SAFETY: writes the 8 bytes of a local
+            unsafe { libc::write(self.wake_fd, (&1u64 as *const u64).cast(), 8) };
+        }
+
+        // Watches each directory again at its path; returns whether one got a
+        // new watch and whether some cannot be watched
+        fn rewatch(&self) -> (bool, bool) {
+            let mut watches = self.watches.lock().unwrap_or_else(PoisonError::into_inner);
+            let (mut rewatched, mut missing) = (false, false);
+            for (dir, watch) in watches.iter_mut() {
+                let new = self.add_watch(dir);
+                if new != *watch {
+                    if let Some(wd) = *watch {
+                        self.remove_watch(wd);
+                    }
+                    *watch = new;
+                    rewatched |= new.is_some();
+                }
+                missing |= new.is_none();
+            }
+            (rewatched, missing)
+        }
+
+        // Watches `dir` for files being created, renamed into it, finished
+        // writing or removed, and for the directory itself going away
+        fn add_watch(&self, dir: &Path) -> Option<libc::c_int> {
+            let dir = CString::new(dir.as_os_str().as_bytes()).ok()?;
+            let mask = libc::IN_CREATE
+                | libc::IN_MOVED_TO
+                | libc::IN_CLOSE_WRITE
+                | libc::IN_DELETE
+                | libc::IN_DELETE_SELF
+                | libc::IN_MOVE_SELF;
+            // SAFETY: `dir` is a valid C string that outlives the call
+            let wd = unsafe { libc::inotify_add_watch(self.fd, dir.as_ptr(), mask) };
+            (wd >= 0).then_some(wd)
+        }
+
+        fn remove_watch(&self, wd: libc::c_int) {
+            // SAFETY: plain syscall; fails harmlessly on a watch the kernel already dropped
+            unsafe { libc::inotify_rm_watch(self.fd, wd) };
+        }
+    }
+
+    impl Drop for Inotify {
+        fn drop(&mut self) {
+            // SAFETY: the descriptors are owned by this value
+            unsafe {
+                libc::close(self.fd);
+                libc::close(self.wake_fd);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::{Key, WriteOptions};
+    use std::time::Instant;
+    use tempfile::TempDir;
+
+    fn write(path: &Path, keys: &[Key], value: &[u8]) -> Result<()> {
+        let values = keys.iter().map(|_| value);
+        Database::<KEY_SIZE>::write_database_single(path, keys.iter(), values, 1, &WriteOptions::default())?;
+        Ok(())
+    }
+
+    // Waits for the watcher of `handle` to reach `generation`
+    fn wait_for_generation(handle: &DatabaseHandle<KEY_SIZE>, generation: u64) {
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while handle.generation() < generation && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(5));
+        }
+        assert_eq!(handle.generation(), generation);
+    }
+
+    #[test]
+    fn test_reload_keeps_old_generations_alive() -> Result<()> {
+        let dir = TempDir::new()?;
+        let keys: Vec<Key> = (0..100u128).map(|i| i.to_le_bytes()).collect();
+        let (data, index) = (dir.path().join("db.data"), dir.path().join("db.index"));
+        Database::<KEY_SIZE>::write_database(&data, &index, keys.iter(), keys.iter().map(|_| b"one"), 1)?;
+
+        let handle = DatabaseHandle::<KEY_SIZE>::open(
+            DatabaseFiles::TwoFiles { data: data.clone(), index: index.clone() },
+            OpenOptions::default(),
+        )?;
+        let old = handle.load();
+        let in_flight = old.get(&keys[5]);
+
+        Database::<KEY_SIZE>::write_database(&data, &index, keys.iter(), keys.iter().map(|_| b"two"), 2)?;
+        assert_eq!(handle.reload(&data, &index)?, 1);
+        assert_eq!(in_flight, Some(&b"one"[..]));
+        assert_eq!(handle.load().get(&keys[5]), Some(&b"two"[..]));
+
+        // A failed reload keeps the current generation
+        let single = dir.path().join("missing.kvdb");
+        assert!(matches!(handle.reload_single(&single), Err(Error::Io(_))));
+        assert_eq!(handle.generation(), 1);
+        assert_eq!(handle.load().get(&keys[5]), Some(&b"two"[..]));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_watcher_reloads_published_databases() -> Result<()> {
+        let dir = TempDir::new()?;
+        let path = dir.path().join("db.kvdb");
+        let keys: Vec<Key> = (0..100u128).map(|i| i.to_le_bytes()).collect();
+        write(&path, &keys, b"v1")?;
+
+        let files = DatabaseFiles::SingleFile(path.clone());
+        let handle = Arc::new(DatabaseHandle::<KEY_SIZE>::open(files, OpenOptions::default())?);
+        let watcher = handle.watch(Duration::from_millis(20))?;
+
+        for (generation, value) in [(1, b"v2"), (2, b"v3")] {
+            write(&path, &keys, value)?;
+            wait_for_generation(&handle, generation);
+            assert_eq!(handle.load().get(&keys[0]), Some(&value[..]));
+        }
+        assert!(watcher.take_error().is_none());
+        drop(watcher);
+
+        Ok(())
+    }
+
+    // Only inotify wakes a watcher with this interval within the test
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_watcher_follows_reloads_and_replaced_directories() -> Result<()> {
+        let dir = TempDir::new()?;
+        let (first, second) = (dir.path().join("first"), dir.path().join("second"));
+        fs::create_dir(&first)?;
+        fs::create_dir(&second)?;
+        let keys: Vec<Key> = (0..100u128).map(|i| i.to_le_bytes()).collect();
+        write(&first.join("db.kvdb"), &keys, b"v0")?;
+
+        let files = DatabaseFiles::SingleFile(first.join("db.kvdb"));
+        let handle = Arc::new(DatabaseHandle::<KEY_SIZE>::open(files, OpenOptions::default())?);
+        let watcher = handle.watch(Duration::from_secs(3600))?;
+
+        // A reload to files in another directory moves the watch there
+        write(&second.join("db.kvdb"), &keys, b"v1")?;
+        assert_eq!(handle.reload_single(second.join("db.kvdb"))?, 1);
+        write(&second.join("db.kvdb"), &keys, b"v2")?;
+        wait_for_generation(&handle, 2);
+        assert_eq!(handle.load().get(&keys[0]), Some(&b"v2"[..]));
+
+        // The directory is replaced by another one, then removed and created
+        // again; each time the new directory is watched
+        let staged = dir.path().join("staged");
+        fs::create_dir(&staged)?;
+        write(&staged.join("db.kvdb"), &keys, b"v3")?;
+        fs::rename(&second, dir.path().join("replaced"))?;
+        fs::rename(&staged, &second)?;
+        wait_for_generation(&handle, 3);
+        write(&second.join("db.kvdb"), &keys, b"v4")?;
+        wait_for_generation(&handle, 4);
+
+        fs::remove_dir_all(&second)?;
+        fs::create_dir(&second)?;
+        write(&second.join("db.kvdb"), &keys, b"v5")?;
+        wait_for_generation(&handle, 5);
+        write(&second.join("db.kvdb"), &keys, b"v6")?;
+        wait_for_generation(&handle, 6);
+        assert_eq!(handle.load().get(&keys[0]), Some(&b"v6"[..]));
+        drop(watcher);
+
+        Ok(())
+    }
+}
@@ -1,4 +1,19 @@
+use crate::error::{Error, Result};
 use serde::{Deserialize, Serialize};
+use xxhash_rust::xxh3::xxh3_64;
+
+// Parses a header with `parse`, telling a file too short to hold it from one with bad magic
+pub(crate) fn parse_header<T>(
+    bytes: &[u8],
+    size: usize,
+    section: &'static str,
+    parse: fn(&[u8]) -> Option<T>,
+) -> Result<T> {
+    if bytes.len() < size {
+        return Err(Error::Truncated { section });
+    }
+    parse(bytes).ok_or(Error::BadMagic { section })
+}
 
 #[repr(C)]
 #[derive(Debug, Clone, Copy, Serialize, Deserialize)]
@@ -9,9 +24,20 @@ pub struct DabaHeader {
     pub num_keys: u64,       // 8 bytes
     pub key_size: u64,       // 8 bytes
     pub values_start: usize, // 8 bytes
+    pub value_width: u64,    // 8 bytes: width of every value with INDEX_FLAG_FIXED_VALUES, else 0
+    pub value_codec: u64,    // 8 bytes: VALUE_CODEC_* the values are encoded with
+    pub value_type: u64,     // 8 bytes: xxh3 of the `TypedBuilder::with_type_name` name, 0 for none
+    pub build_id: u64,       // 8 bytes: random per build, ties the index to this data file
 }
 
+// Values are raw bytes
+pub const VALUE_CODEC_RAW: u64 = 0;
+// Values are `V` serialized with bincode 1's default options (see `TypedDatabase`)
+pub const VALUE_CODEC_BINCODE: u64 = 1;
+
 impl DabaHeader {
+    pub const SIZE: usize = 64;
+
     pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
         if bytes.len() < std::mem::size_of::<Self>() {
             return None;
@@ -22,6 +48,10 @@ impl DabaHeader {
         let num_keys = u64::from_le_bytes(bytes[8..16].try_into().ok()?);
         let key_size = u64::from_le_bytes(bytes[16..24].try_into().ok()?);
         let values_start = u64::from_le_bytes(bytes[24..32].try_into().ok()?) as usize;
+        let value_width = u64::from_le_bytes(bytes[32..40].try_into().ok()?);
+        let value_codec = u64::from_le_bytes(bytes[40..48].try_into().ok()?);
+        let value_type = u64::from_le_bytes(bytes[48..56].try_into().ok()?);
+        let build_id = u64::from_le_bytes(bytes[56..64].try_into().ok()?);
         if &magic != b"DABA" {
             return None;
         }
@@ -31,20 +61,41 @@ impl DabaHeader {
             num_keys,
             key_size,
             values_start,
+            value_width,
+            value_codec,
+            value_type,
+            build_id,
         })
     }
 
-    pub fn to_bytes(&self) -> [u8; 32] {
-        let mut bytes = [0u8; 32];
+    pub fn to_bytes(self) -> [u8; Self::SIZE] {
+        let mut bytes = [0u8; Self::SIZE];
         bytes[0..4].copy_from_slice(&self.magic);
         bytes[4..8].copy_from_slice(&self.version.to_le_bytes());
         bytes[8..16].copy_from_slice(&self.num_keys.to_le_bytes());
         bytes[16..24].copy_from_slice(&self.key_size.to_le_bytes());
         bytes[24..32].copy_from_slice(&(self.values_start as u64).to_le_bytes());
+        bytes[32..40].copy_from_slice(&self.value_width.to_le_bytes());
+        bytes[40..48].copy_from_slice(&self.value_codec.to_le_bytes());
+        bytes[48..56].copy_from_slice(&self.value_type.to_le_bytes());
+        bytes[56..64].copy_from_slice(&self.build_id.to_le_bytes());
         bytes
     }
 }
 
+// Keys are variable-length and stored in a key heap instead of a fixed-width array
+pub const INDEX_FLAG_VARIABLE_KEYS: u64 = 1 << 0;
+// The index has a sorted keys section: the slots of all keys in key order
+pub const INDEX_FLAG_SORTED_KEYS: u64 = 1 << 1;
+// A static set: the index has no value offsets and the database no values
+pub const INDEX_FLAG_KEYS_ONLY: u64 = 1 << 2;
+// Every value is `DabaHeader::value_width` bytes, so the index has no value offsets
+pub const INDEX_FLAG_FIXED_VALUES: u64 = 1 << 3;
+// The offsets section holds the value offsets Elias-Fano encoded, `offsets_size` bytes
+pub const INDEX_FLAG_EF_OFFSETS: u64 = 1 << 4;
+// The offsets section holds an (offset, length) pair of u64 per slot, and slots may share a value
+pub const INDEX_FLAG_SHARED_VALUES: u64 = 1 << 5;
+
 #[repr(C)]
 #[derive(Debug, Clone, Copy)]
 pub struct IndexHeader {
@@ -54,11 +105,34 @@ pub struct IndexHeader {
     pub mphf_size: u64,        // Size of serialized MPHF
     pub keys_offset: u64,      // Offset to keys section
     pub offsets_offset: u64,   // Offset to offsets section
+    pub mphf_offset: u64,      // Offset to MPHF section (64-byte aligned for epserde)
+    pub flags: u64,            // INDEX_FLAG_* bits
+    pub key_heap_offset: u64,  // Offset to key heap section (variable-length keys only)
+    pub key_heap_size: u64,    // Size of key heap section
+    pub key_check: u64,        // How keys are verified: 0 = full keys, 1 = trusted, 8/16/32 = fingerprint bits
+    pub value_block_size: u64, // Bytes of values covered by each value block checksum (0 = none)
+    pub value_checksums_offset: u64, // Offset to value block checksums section
+    pub value_checksums_size: u64,   // Size of value block checksums section
+    pub mphf_checksum: u64,    // xxh3 of the MPHF section
+    pub keys_checksum: u64,    // xxh3 of the keys section
+    pub key_heap_checksum: u64, // xxh3 of the key heap section
+    pub offsets_checksum: u64, // xxh3 of the offsets section
+    pub values_checksum: u64,  // xxh3 of all values in the data file
+    pub value_checksums_checksum: u64, // xxh3 of the value block checksums section
+    pub sorted_keys_offset: u64, // Offset to sorted keys section (INDEX_FLAG_SORTED_KEYS only)
+    pub sorted_keys_size: u64, // Size of sorted keys section
+    pub sorted_keys_checksum: u64, // xxh3 of the sorted keys section
+    pub offsets_size: u64,     // Size of offsets section
+    pub values_size: u64,      // Total size of the values, checked against the data file on open
+    pub data_slot: u64,        // Which data file a two-file index goes with, see `data_slot_path`
+    pub header_checksum: u64,  // xxh3 of the DabaHeader and all previous fields, see `compute_checksum`
 }
 
 impl IndexHeader {
+    pub const SIZE: usize = 208; // 4 + 4 + 25 * 8 bytes
+
     pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
-        if bytes.len() < 36 {  // 4 + 4 + 8 + 8 + 8 + 8 = 40 bytes
+        if bytes.len() < Self::SIZE {
             return None;
         }
 
@@ -68,6 +142,27 @@ impl IndexHeader {
         let mphf_size = u64::from_le_bytes(bytes[16..24].try_into().ok()?);
         let keys_offset = u64::from_le_bytes(bytes[24..32].try_into().ok()?);
         let offsets_offset = u64::from_le_bytes(bytes[32..40].try_into().ok()?);
+        let mphf_offset = u64::from_le_bytes(bytes[40..48].try_into().ok()?);
+        let flags = u64::from_le_bytes(bytes[48..56].try_into().ok()?);
+        let key_heap_offset = u64::from_le_bytes(bytes[56..64].try_into().ok()?);
+        let key_heap_size = u64::from_le_bytes(bytes[64..72].try_into().ok()?);
+        let key_check = u64::from_le_bytes(bytes[72..80].try_into().ok()?);
+        let value_block_size = u64::from_le_bytes(bytes[80..88].try_into().ok()?);
+        let value_checksums_offset = u64::from_le_bytes(bytes[88..96].try_into().ok()?);
+        let value_checksums_size = u64::from_le_bytes(bytes[96..104].try_into().ok()?);
+        let mphf_checksum = u64::from_le_bytes(bytes[104..112].try_into().ok()?);
+        let keys_checksum = u64::from_le_bytes(bytes[112..120].try_into().ok()?);
+        let key_heap_checksum = u64::from_le_bytes(bytes[120..128].try_into().ok()?);
+        let offsets_checksum = u64::from_le_bytes(bytes[128..136].try_into().ok()?);
+        let values_checksum = u64::from_le_bytes(bytes[136..144].try_into().ok()?);
+        let value_checksums_checksum = u64::from_le_bytes(bytes[144..152].try_into().ok()?);
+        let sorted_keys_offset = u64::from_le_bytes(bytes[152..160].try_into().ok()?);
+        let sorted_keys_size = u64::from_le_bytes(bytes[160..168].try_into().ok()?);
+        let sorted_keys_checksum = u64::from_le_bytes(bytes[168..176].try_into().ok()?);
+        let offsets_size = u64::from_le_bytes(bytes[176..184].try_into().ok()?);
+        let values_size = u64::from_le_bytes(bytes[184..192].try_into().ok()?);
+        let data_slot = u64::from_le_bytes(bytes[192..200].try_into().ok()?);
+        let header_checksum = u64::from_le_bytes(bytes[200..208].try_into().ok()?);
 
         if &magic != b"KIDX" {
             return None;
@@ -80,17 +175,305 @@ impl IndexHeader {
             mphf_size,
             keys_offset,
             offsets_offset,
+            mphf_offset,
+            flags,
+            key_heap_offset,
+            key_heap_size,
+            key_check,
+            value_block_size,
+            value_checksums_offset,
+            value_checksums_size,
+            mphf_checksum,
+            keys_checksum,
+            key_heap_checksum,
+            offsets_checksum,
+            values_checksum,
+            value_checksums_checksum,
+            sorted_keys_offset,
+            sorted_keys_size,
+            sorted_keys_checksum,
+            offsets_size,
+            values_size,
+            data_slot,
+            header_checksum,
         })
     }
 
-    pub fn to_bytes(&self) -> [u8; 40] {
-        let mut bytes = [0u8; 40];
+    pub fn to_bytes(self) -> [u8; Self::SIZE] {
+        let mut bytes = [0u8; Self::SIZE];
         bytes[0..4].copy_from_slice(&self.magic);
         bytes[4..8].copy_from_slice(&self.version.to_le_bytes());
         bytes[8..16].copy_from_slice(&self.num_keys.to_le_bytes());
         bytes[16..24].copy_from_slice(&self.mphf_size.to_le_bytes());
         bytes[24..32].copy_from_slice(&self.keys_offset.to_le_bytes());
         bytes[32..40].copy_from_slice(&self.offsets_offset.to_le_bytes());
+        bytes[40..48].copy_from_slice(&self.mphf_offset.to_le_bytes());
+        bytes[48..56].copy_from_slice(&self.flags.to_le_bytes());
+        bytes[56..64].copy_from_slice(&self.key_heap_offset.to_le_bytes());
+        bytes[64..72].copy_from_slice(&self.key_heap_size.to_le_bytes());
+        bytes[72..80].copy_from_slice(&self.key_check.to_le_bytes());
+        bytes[80..88].copy_from_slice(&self.value_block_size.to_le_bytes());
+        bytes[88..96].copy_from_slice(&self.value_checksums_offset.to_le_bytes());
+        bytes[96..104].copy_from_slice(&self.value_checksums_size.to_le_bytes());
+        bytes[104..112].copy_from_slice(&self.mphf_checksum.to_le_bytes());
+        bytes[112..120].copy_from_slice(&self.keys_checksum.to_le_bytes());
+        bytes[120..128].copy_from_slice(&self.key_heap_checksum.to_le_bytes());
+        bytes[128..136].copy_from_slice(&self.offsets_checksum.to_le_bytes());
+        bytes[136..144].copy_from_slice(&self.values_checksum.to_le_bytes());
+        bytes[144..152].copy_from_slice(&self.value_checksums_checksum.to_le_bytes());
+        bytes[152..160].copy_from_slice(&self.sorted_keys_offset.to_le_bytes());
+        bytes[160..168].copy_from_slice(&self.sorted_keys_size.to_le_bytes());
+        bytes[168..176].copy_from_slice(&self.sorted_keys_checksum.to_le_bytes());
+        bytes[176..184].copy_from_slice(&self.offsets_size.to_le_bytes());
+        bytes[184..192].copy_from_slice(&self.values_size.to_le_bytes());
+        bytes[192..200].copy_from_slice(&self.data_slot.to_le_bytes());
+        bytes[200..208].copy_from_slice(&self.header_checksum.to_le_bytes());
+        bytes
+    }
+
+    // Checksum stored in `header_checksum`: covers the data file header and
+    // every other field of this header, so a flipped bit in either is caught on
+    // open. The data header holds a random build id, so a data file written by
+    // another build is caught too, even one with the same shape.
+    pub fn compute_checksum(&self, data_header: &DabaHeader) -> u64 {
+        let index_bytes = self.to_bytes();
+        xxh3_64(&[&data_header.to_bytes()[..], &index_bytes[..Self::SIZE - 8]].concat())
+    }
+
+    pub fn has_variable_keys(&self) -> bool {
+        self.flags & INDEX_FLAG_VARIABLE_KEYS != 0
+    }
+
+    pub fn has_sorted_keys(&self) -> bool {
+        self.flags & INDEX_FLAG_SORTED_KEYS != 0
+    }
+
+    pub fn is_keys_only(&self) -> bool {
+        self.flags & INDEX_FLAG_KEYS_ONLY != 0
+    }
+
+    pub fn has_fixed_values(&self) -> bool {
+        self.flags & INDEX_FLAG_FIXED_VALUES != 0
+    }
+
+    pub fn has_ef_offsets(&self) -> bool {
+        self.flags & INDEX_FLAG_EF_OFFSETS != 0
+    }
+
+    pub fn has_shared_values(&self) -> bool {
+        self.flags & INDEX_FLAG_SHARED_VALUES != 0
+    }
+}
+
+// Version of the single-file layout
+pub const SINGLE_FILE_VERSION: u32 = 1;
+
+// Section kinds of a single-file database
+pub const SECTION_META: u32 = 1;     // DabaHeader followed by IndexHeader
+pub const SECTION_MPHF: u32 = 2;     // serialized MPHF
+pub const SECTION_KEYS: u32 = 3;     // keys, key offsets or fingerprints
+pub const SECTION_KEY_HEAP: u32 = 4; // variable-length key bytes
+pub const SECTION_OFFSETS: u32 = 5;  // value offsets
+pub const SECTION_VALUES: u32 = 6;   // values
+pub const SECTION_VALUE_CHECKSUMS: u32 = 7; // value block checksums
+pub const SECTION_SORTED_KEYS: u32 = 8; // slots in key order
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+// Header of a single-file database, followed by `num_sections` section entries
+pub struct FileHeader {
+    pub magic: [u8; 4],        // "KVDB"
+    pub version: u32,          // Version number
+    pub num_sections: u32,     // Number of entries in the section directory
+}
+
+impl FileHeader {
+    pub const SIZE: usize = 16; // 4 + 4 + 4 bytes, plus 4 reserved
+
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() < Self::SIZE {
+            return None;
+        }
+
+        let magic = bytes[0..4].try_into().ok()?;
+        let version = u32::from_le_bytes(bytes[4..8].try_into().ok()?);
+        let num_sections = u32::from_le_bytes(bytes[8..12].try_into().ok()?);
+
+        if &magic != b"KVDB" {
+            return None;
+        }
+
+        Some(Self {
+            magic,
+            version,
+            num_sections,
+        })
+    }
+
+    pub fn to_bytes(self) -> [u8; Self::SIZE] {
+        let mut bytes = [0u8; Self::SIZE];
+        bytes[0..4].copy_from_slice(&self.magic);
+        bytes[4..8].copy_from_slice(&self.version.to_le_bytes());
+        bytes[8..12].copy_from_slice(&self.num_sections.to_le_bytes());
+        bytes
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+// Entry of the section directory of a single-file database
+pub struct SectionEntry {
+    pub kind: u32,             // SECTION_* constant
+    pub offset: u64,           // Offset of the section from the start of the file
+    pub size: u64,             // Size of the section in bytes
+}
+
+impl SectionEntry {
+    pub const SIZE: usize = 24; // 4 bytes, 4 reserved, 8 + 8 bytes
+
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() < Self::SIZE {
+            return None;
+        }
+
+        let kind = u32::from_le_bytes(bytes[0..4].try_into().ok()?);
+        let offset = u64::from_le_bytes(bytes[8..16].try_into().ok()?);
+        let size = u64::from_le_bytes(bytes[16..24].try_into().ok()?);
+
+        Some(Self { kind, offset, size })
+    }
+
+    pub fn to_bytes(self) -> [u8; Self::SIZE] {
+        let mut bytes = [0u8; Self::SIZE];
+        bytes[0..4].copy_from_slice(&self.kind.to_le_bytes());
+        bytes[8..16].copy_from_slice(&self.offset.to_le_bytes());
+        bytes[16..24].copy_from_slice(&self.size.to_le_bytes());
+        bytes
+    }
+}
+
+// Version of the shard manifest layout
+pub const MANIFEST_VERSION: u32 = 1;
+
+// Hash functions routing keys to shards
+pub const SHARD_HASH_XXH3: u32 = 1; // xxh3_64 with the manifest seed, scaled to the shard count
+
+// Layouts of the shards of a sharded database
+pub const SHARD_LAYOUT_TWO_FILES: u32 = 1; // DABA data file and KIDX index file
+pub const SHARD_LAYOUT_SINGLE_FILE: u32 = 2; // KVDB single file
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+// Manifest of a sharded database
+pub struct ManifestHeader {
+    pub magic: [u8; 4],        // "KVSM"
+    pub version: u32,          // Version number
+    pub num_shards: u32,       // Number of shards
+    pub hash: u32,             // SHARD_HASH_* constant
+    pub layout: u32,           // SHARD_LAYOUT_* constant
+    pub key_size: u64,         // Key width of every shard (0 = variable-length keys)
+    pub seed: u64,             // Seed of the shard hash
+    pub checksum: u64,         // xxh3 of all previous bytes
+}
+
+impl ManifestHeader {
+    pub const SIZE: usize = 48; // 4 + 4 + 4 + 4 + 4 bytes, 4 reserved, 3 * 8 bytes
+
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() < Self::SIZE {
+            return None;
+        }
+
+        let magic = bytes[0..4].try_into().ok()?;
+        let version = u32::from_le_bytes(bytes[4..8].try_into().ok()?);
+        let num_shards = u32::from_le_bytes(bytes[8..12].try_into().ok()?);
+        let hash = u32::from_le_bytes(bytes[12..16].try_into().ok()?);
+        let layout = u32::from_le_bytes(bytes[16..20].try_into().ok()?);
+        let key_size = u64::from_le_bytes(bytes[24..32].try_into().ok()?);
+        let seed = u64::from_le_bytes(bytes[32..40].try_into().ok()?);
+        let checksum = u64::from_le_bytes(bytes[40..48].try_into().ok()?);
+
+        if &magic != b"KVSM" {
+            return None;
+        }
+
+        Some(Self {
+            magic,
+            version,
+            num_shards,
+            hash,
+            layout,
+            key_size,
+            seed,
+            checksum,
+        })
+    }
+
+    pub fn to_bytes(self) -> [u8; Self::SIZE] {
+        let mut bytes = [0u8; Self::SIZE];
+        bytes[0..4].copy_from_slice(&self.magic);
+        bytes[4..8].copy_from_slice(&self.version.to_le_bytes());
+        bytes[8..12].copy_from_slice(&self.num_shards.to_le_bytes());
+        bytes[12..16].copy_from_slice(&self.hash.to_le_bytes());
+        bytes[16..20].copy_from_slice(&self.layout.to_le_bytes());
+        bytes[24..32].copy_from_slice(&self.key_size.to_le_bytes());
+        bytes[32..40].copy_from_slice(&self.seed.to_le_bytes());
+        bytes[40..48].copy_from_slice(&self.checksum.to_le_bytes());
+        bytes
+    }
+
+    // Checksum stored in `checksum`
+    pub fn compute_checksum(&self) -> u64 {
+        xxh3_64(&self.to_bytes()[..Self::SIZE - 8])
+    }
+}
+
+// Version of the overlay delta layout
+pub const DELTA_VERSION: u32 = 2;
+
+// Operations recorded in an overlay delta file
+pub const DELTA_OP_UPSERT: u8 = 1; // key and value
+pub const DELTA_OP_DELETE: u8 = 2; // key only; the value length is 0
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+// Header of an overlay delta file, followed by its records. Each record is an
+// op byte, the key and value lengths (u32 each), the key, the value, and the
+// xxh3 of all previous bytes of the record.
+pub struct DeltaHeader {
+    pub magic: [u8; 4],        // "KVDL"
+    pub version: u32,          // Version number
+    pub key_size: u64,         // Key width of the base database (0 = variable-length keys)
+}
+
+impl DeltaHeader {
+    pub const SIZE: usize = 16; // 4 + 4 + 8 bytes
+
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() < Self::SIZE {
+            return None;
+        }
+
+        let magic = bytes[0..4].try_into().ok()?;
+        let version = u32::from_le_bytes(bytes[4..8].try_into().ok()?);
+        let key_size = u64::from_le_bytes(bytes[8..16].try_into().ok()?);
+
+        if &magic != b"KVDL" {
+            return None;
+        }
+
+        Some(Self {
+            magic,
+            version,
+            key_size,
+        })
+    }
+
+    pub fn to_bytes(self) -> [u8; Self::SIZE] {
+        let mut bytes = [0u8; Self::SIZE];
+        bytes[0..4].copy_from_slice(&self.magic);
+        bytes[4..8].copy_from_slice(&self.version.to_le_bytes());
+        bytes[8..16].copy_from_slice(&self.key_size.to_le_bytes());
         bytes
     }
 }
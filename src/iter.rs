@@ -0,0 +1,383 @@
+use crate::database::{Database, Key};
+use crate::error::{Error, Result};
+use std::{
+    fmt,
+    iter::FusedIterator,
+    ops::{Bound, Deref, Range, RangeBounds},
+};
+
+// A key yielded by `Iter` and `SortedIter`. In a database with N-byte keys it
+// always holds N bytes, and `key` returns it as a `Key<N>` without checking
+// the length again; with variable-length keys it is just the key's bytes.
+// Derefs to the bytes either way, so code generic over N can treat it as one.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct KeyRef<'a, const N: usize>(&'a [u8]);
+
+impl<'a, const N: usize> KeyRef<'a, N> {
+    // The key as a `Key<N>`, for databases with N-byte keys; an empty array
+    // with variable-length keys
+    pub fn key(self) -> &'a Key<N> {
+        // Fixed-width keys are only built from N-byte chunks of the keys section
+        self.0.first_chunk().unwrap_or(const { &[0; N] })
+    }
+
+    pub fn as_bytes(self) -> &'a [u8] {
+        self.0
+    }
+}
+
+impl<const N: usize> Deref for KeyRef<'_, N> {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        self.0
+    }
+}
+
+impl<const N: usize> AsRef<[u8]> for KeyRef<'_, N> {
+    fn as_ref(&self) -> &[u8] {
+        self.0
+    }
+}
+
+impl<const N: usize> PartialEq<[u8]> for KeyRef<'_, N> {
+    fn eq(&self, other: &[u8]) -> bool {
+        self.0 == other
+    }
+}
+
+impl<const N: usize> PartialEq<&[u8]> for KeyRef<'_, N> {
+    fn eq(&self, other: &&[u8]) -> bool {
+        self.0 == *other
+    }
+}
+
+impl<const N: usize> fmt::Debug for KeyRef<'_, N> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+// Iterator over `(key, value)` pairs of a slot range, in MPHF slot order
+pub struct Iter<'a, const N: usize> {
+    db: &'a Database<N>,
+    slots: Range<usize>,
+}
+
+impl<'a, const N: usize> Iter<'a, N> {
+    // Slots this iterator has not yielded yet
+    pub fn slots(&self) -> Range<usize> {
+        self.slots.clone()
+    }
+
+    // Splits the remaining slots in two halves, e.g. to hand one to another thread
+    pub fn split(self) -> (Self, Self) {
+        let mid = self.slots.start + self.slots.len() / 2;
+        (
+            Iter { db: self.db, slots: self.slots.start..mid },
+            Iter { db: self.db, slots: mid..self.slots.end },
+        )
+    }
+
+    fn entry(&self, idx: usize) -> (KeyRef<'a, N>, &'a [u8]) {
+        // Slots below `len()` always have a key and a value once `open` succeeded
+        let key = self.db.stored_key(idx).unwrap_or_default();
+        let value = self.db.value_at(idx).unwrap_or_default();
+        (KeyRef(key), value)
+    }
+}
+
+impl<'a, const N: usize> Iterator for Iter<'a, N> {
+    type Item = (KeyRef<'a, N>, &'a [u8]);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let idx = self.slots.next()?;
+        Some(self.entry(idx))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.slots.size_hint()
+    }
+
+    fn nth(&mut self, n: usize) -> Option<Self::Item> {
+        let idx = self.slots.nth(n)?;
+        Some(self.entry(idx))
+    }
+}
+
+impl<const N: usize> DoubleEndedIterator for Iter<'_, N> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        let idx = self.slots.next_back()?;
+        Some(self.entry(idx))
+    }
+}
+
+impl<const N: usize> ExactSizeIterator for Iter<'_, N> {}
+
+impl<const N: usize> FusedIterator for Iter<'_, N> {}
+
+// Iterator over `(key, value)` pairs in ascending key order, from `Database::range`
+// and `Database::prefix`. Walks a range of positions in the sorted keys section.
+pub struct SortedIter<'a, const N: usize> {
+    db: &'a Database<N>,
+    positions: Range<usize>,
+}
+
+impl<'a, const N: usize> SortedIter<'a, N> {
+    fn entry(&self, position: usize) -> (KeyRef<'a, N>, &'a [u8]) {
+        let slot = self.db.sorted_slots()[position] as usize;
+        let key = self.db.stored_key(slot).unwrap_or_default();
+        let value = self.db.value_at(slot).unwrap_or_default();
+        (KeyRef(key), value)
+    }
+}
+
+impl<'a, const N: usize> Iterator for SortedIter<'a, N> {
+    type Item = (KeyRef<'a, N>, &'a [u8]);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let position = self.positions.next()?;
+        Some(self.entry(position))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.positions.size_hint()
+    }
+
+    fn nth(&mut self, n: usize) -> Option<Self::Item> {
+        let position = self.positions.nth(n)?;
+        Some(self.entry(position))
+    }
+}
+
+impl<const N: usize> DoubleEndedIterator for SortedIter<'_, N> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        let position = self.positions.next_back()?;
+        Some(self.entry(position))
+    }
+}
+
+impl<const N: usize> ExactSizeIterator for SortedIter<'_, N> {}
+
+impl<const N: usize> FusedIterator for SortedIter<'_, N> {}
+
+impl<const N: usize> Database<N> {
+    // Iterates over all `(key, value)` pairs in slot order. Fails if the index
+    // only stores fingerprints or nothing, since the keys cannot be recovered.
+    pub fn iter(&self) -> Result<Iter<'_, N>> {
+        self.iter_range(0..self.len())
+    }
+
+    // Iterates over the `(key, value)` pairs of a range of slots
+    pub fn iter_range(&self, slots: Range<usize>) -> Result<Iter<'_, N>> {
+        if !self.has_keys() {
+            return Err(Error::Unsupported("database stores no keys (fingerprint or trusted mode)"));
+        }
+        if slots.start > slots.end || slots.end > self.len() {
+            return Err(Error::InvalidArgument(format!(
+                "Slot range {:?} out of bounds for {} keys",
+                slots,
+                self.len()
+            )));
+        }
+        Ok(Iter { db: self, slots })
+    }
+
+    // Iterates over all keys in slot order
+    pub fn keys(&self) -> Result<impl DoubleEndedIterator<Item = KeyRef<'_, N>> + ExactSizeIterator> {
+        Ok(self.iter()?.map(|(key, _)| key))
+    }
+
+    // Iterates over all values in slot order; works in every key check mode
+    pub fn values(&self) -> impl DoubleEndedIterator<Item = &[u8]> + ExactSizeIterator {
+        (0..self.len()).map(|idx| self.value_at(idx).unwrap_or_default())
+    }
+
+    // Iterates over the `(key, value)` pairs whose keys fall in `range`, in
+    // ascending byte order, e.g. `db.range(&b"a"[..]..&b"c"[..])`. Fails unless
+    // the database was written with `WriteOptions::sorted_keys`.
+    pub fn range<K, R>(&self, range: R) -> Result<SortedIter<'_, N>>
+    where
+        K: AsRef<[u8]> + ?Sized,
+        R: RangeBounds<K>,
+    {
+        let start = match range.start_bound() {
+            Bound::Included(lo) => self.sorted_partition(|key| key < lo.as_ref())?,
+            Bound::Excluded(lo) => self.sorted_partition(|key| key <= lo.as_ref())?,
+            Bound::Unbounded => self.sorted_partition(|_| false)?,
+        };
+        let end = match range.end_bound() {
+            Bound::Included(hi) => self.sorted_partition(|key| key <= hi.as_ref())?,
+            Bound::Excluded(hi) => self.sorted_partition(|key| key < hi.as_ref())?,
+            Bound::Unbounded => self.len(),
+        };
+        // An inverted range is empty
+        Ok(SortedIter { db: self, positions: start..end.max(start) })
+    }
+
+    // Iterates over the `(key, value)` pairs whose keys start with `prefix`, in
+    // ascending byte order; see `range`
+    pub fn prefix(&self, prefix: &[u8]) -> Result<SortedIter<'_, N>> {
+        // Keys with the prefix directly follow the keys below it
+        let start = self.sorted_partition(|key| key < prefix)?;
+        let end = self.sorted_partition(|key| key < prefix || key.starts_with(prefix))?;
+        Ok(SortedIter { db: self, positions: start..end })
+    }
+
+    // Number of keys in key order for which `before` holds, which must hold
+    // for a prefix of them
+    fn sorted_partition(&self, before: impl Fn(&[u8]) -> bool) -> Result<usize> {
+        if !self.has_sorted_keys() {
+            return Err(Error::Unsupported("database has no sorted key index (see WriteOptions::sorted_keys)"));
+        }
+        Ok(self.sorted_slots().partition_point(|&slot| before(self.stored_key(slot as usize).unwrap_or_default())))
+    }
+
+    // Splits the database into at most `num_chunks` contiguous slot ranges of
+    // nearly equal size, one iterator each, to scan them from separate threads
+    pub fn par_chunks(&self, num_chunks: usize) -> Result<Vec<Iter<'_, N>>> {
+        let len = self.len();
+        let num_chunks = num_chunks.clamp(1, len.max(1));
+        (0..num_chunks)
+            .map(|i| self.iter_range(len * i / num_chunks..len * (i + 1) / num_chunks))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::KeyRef;
+    use crate::database::{Database, Key, KeyCheck, VarDatabase, WriteOptions, KEY_SIZE, VAR_KEY_SIZE};
+    use crate::error::Error;
+    use std::collections::HashMap;
+    use std::io;
+    use tempfile::NamedTempFile;
+
+    #[test]
+    fn test_iterate_all_entries() -> io::Result<()> {
+        let data_file = NamedTempFile::new()?;
+        let index_file = NamedTempFile::new()?;
+
+        let keys: Vec<Vec<u8>> = (0..500u32).map(|i| format!("key-{}", i).into_bytes()).collect();
+        let values: Vec<Vec<u8>> = (0..500u32).map(|i| format!("value-{}", i).into_bytes()).collect();
+        VarDatabase::write_database(data_file.path(), index_file.path(), keys.iter(), values.iter(), 1)?;
+        let db = VarDatabase::open(data_file.path(), index_file.path())?;
+
+        let expected: HashMap<&[u8], &[u8]> =
+            keys.iter().map(|k| k.as_slice()).zip(values.iter().map(|v| v.as_slice())).collect();
+
+        let entries: HashMap<&[u8], &[u8]> = db.iter()?.map(|(key, value)| (key.as_bytes(), value)).collect();
+        assert_eq!(db.iter()?.len(), 500);
+        assert_eq!(entries, expected);
+
+        // Keys and values come out in the same slot order as `iter`
+        let keys_in_order: Vec<KeyRef<'_, VAR_KEY_SIZE>> = db.keys()?.collect();
+        let values_in_order: Vec<&[u8]> = db.values().collect();
+        let pairs: Vec<(KeyRef<'_, VAR_KEY_SIZE>, &[u8])> = keys_in_order.into_iter().zip(values_in_order).collect();
+        assert_eq!(pairs, db.iter()?.collect::<Vec<_>>());
+        assert_eq!(db.iter()?.next_back(), pairs.last().copied());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_par_chunks_cover_every_slot_once() -> io::Result<()> {
+        let data_file = NamedTempFile::new()?;
+        let index_file = NamedTempFile::new()?;
+
+        let keys: Vec<[u8; KEY_SIZE]> = (0..1000u128).map(|i| i.to_le_bytes()).collect();
+        Database::<KEY_SIZE>::write_database(data_file.path(), index_file.path(), keys.iter(), keys.iter(), 1)?;
+        let db = Database::<KEY_SIZE>::open(data_file.path(), index_file.path())?;
+
+        let chunks = db.par_chunks(7)?;
+        assert_eq!(chunks.len(), 7);
+        let total: usize = std::thread::scope(|scope| {
+            let handles: Vec<_> = chunks
+                .into_iter()
+                .map(|chunk| {
+                    scope.spawn(move || {
+                        chunk
+                            .map(|(key, value)| {
+                                // Fixed-width keys come out as `Key<N>`
+                                let key: &Key<KEY_SIZE> = key.key();
+                                assert_eq!(&key[..], value);
+                                1
+                            })
+                            .sum::<usize>()
+                    })
+                })
+                .collect();
+            handles.into_iter().map(|h| h.join().unwrap()).sum()
+        });
+        assert_eq!(total, 1000);
+
+        let (left, right) = db.iter_range(10..20)?.split();
+        assert_eq!((left.slots(), right.slots()), (10..15, 15..20));
+        assert!(db.iter_range(0..1001).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_range_and_prefix_in_key_order() -> io::Result<()> {
+        let dir = tempfile::TempDir::new()?;
+        let path = dir.path().join("sorted.kvdb");
+
+        let keys: Vec<String> = (0..1000u32).map(|i| format!("user/{}/name", i)).collect();
+        let options = WriteOptions { sorted_keys: true, ..Default::default() };
+        VarDatabase::write_database_single(&path, keys.iter(), keys.iter().map(|k| k.len().to_string()), 1, &options)?;
+        let db = VarDatabase::open_single(&path)?;
+        assert!(db.has_sorted_keys() && db.verify().is_empty());
+
+        let mut sorted: Vec<&[u8]> = keys.iter().map(|k| k.as_bytes()).collect();
+        sorted.sort();
+        let all: Vec<&[u8]> = db.range::<[u8], _>(..)?.map(|(key, _)| key.as_bytes()).collect();
+        assert_eq!(all, sorted);
+
+        // user/42/name, then user/420/name to user/429/name
+        let prefixed: Vec<&[u8]> = db.prefix(b"user/42")?.map(|(key, _)| key.as_bytes()).collect();
+        assert_eq!(prefixed.len(), 11);
+        assert_eq!(prefixed[0], b"user/42/name");
+        let entries: Vec<_> = db.prefix(b"user/42/")?.map(|(key, value)| (key.as_bytes(), value)).collect();
+        assert_eq!(entries, [(&b"user/42/name"[..], &b"12"[..])]);
+        assert_eq!(db.prefix(b"other")?.len(), 0);
+
+        let (lo, hi) = (&b"user/10/"[..], &b"user/11/name"[..]);
+        let in_range: Vec<&[u8]> = db.range(lo..hi)?.map(|(key, _)| key.as_bytes()).collect();
+        let expected: Vec<&[u8]> = sorted.iter().copied().filter(|&key| lo <= key && key < hi).collect();
+        assert_eq!(in_range, expected);
+        assert_eq!(db.range(lo..=hi)?.next_back().map(|(key, _)| key.as_bytes()), Some(hi));
+        assert_eq!(db.range(hi..lo)?.len(), 0);
+
+        // Databases written without the index cannot answer ordered queries
+        let plain = dir.path().join("plain.kvdb");
+        VarDatabase::write_database_single(&plain, keys.iter(), keys.iter(), 1, &WriteOptions::default())?;
+        let db = VarDatabase::open_single(&plain)?;
+        assert!(matches!(db.prefix(b"user"), Err(Error::Unsupported(_))));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_iteration_without_stored_keys() -> io::Result<()> {
+        let data_file = NamedTempFile::new()?;
+        let index_file = NamedTempFile::new()?;
+
+        let keys: Vec<[u8; KEY_SIZE]> = (0..10u128).map(|i| i.to_le_bytes()).collect();
+        let options = WriteOptions { key_check: KeyCheck::Fingerprint { bits: 16 }, ..Default::default() };
+        Database::<KEY_SIZE>::write_database_with_options(
+            data_file.path(),
+            index_file.path(),
+            keys.iter(),
+            keys.iter(),
+            1,
+            &options,
+        )?;
+        let db = Database::<KEY_SIZE>::open(data_file.path(), index_file.path())?;
+
+        assert!(matches!(db.iter(), Err(Error::Unsupported(_))));
+        assert_eq!(db.values().count(), 10);
+
+        Ok(())
+    }
+}
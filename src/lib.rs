@@ -1,3 +1,17 @@
+pub mod builder;
 pub mod database;
+pub mod diff;
+pub mod error;
+pub mod handle;
 mod header;
+pub mod iter;
+pub mod merge;
+pub mod overlay;
+pub mod verify;
+mod writer;
 pub mod protocol;
+pub mod set;
+pub mod sharded;
+pub mod typed;
+pub mod upgrade;
+pub use error::{Error, Result};
@@ -1,11 +1,243 @@
-use ptr_hash::{bucket_fn::CubicEps, PtrHash, PtrHashParams};
+use clap::{Parser, Subcommand, ValueEnum};
+use kvfast_lib::database::{
+    stored_key_size, stored_key_size_single, Database, DuplicatePolicy, WriteOptions, KEY_SIZE, VAR_KEY_SIZE,
+};
+use kvfast_lib::diff::diff;
+use kvfast_lib::upgrade::upgrade_into;
+use kvfast_lib::{Error, Result};
+use std::path::PathBuf;
+use std::process::ExitCode;
 
-fn main() {
-    let possible_objects = vec!["1", "2"];
-    let phf: PtrHash<&str, CubicEps> = PtrHash::new(&possible_objects, PtrHashParams::default());
+#[derive(Parser)]
+#[command(name = "kvfast", about = "Build and maintain kvfast databases")]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    Merge(MergeArgs),
+    Diff(DiffArgs),
+    Upgrade(UpgradeArgs),
+}
+
+/// Merge databases into a new one with a fresh MPHF
+///
+/// The inputs must all have the same key width, one of the widths `kvfast`
+/// handles: variable-length keys, or 8-, 16- or 32-byte keys.
+#[derive(clap::Args)]
+struct MergeArgs {
+    /// Databases in priority order, highest first: `db.kvdb` for a single
+    /// file, or `db.data,db.index` for a data and an index file
+    #[arg(required = true)]
+    inputs: Vec<String>,
+
+    /// Database to write, in the same form as the inputs
+    #[arg(short, long)]
+    output: String,
+
+    /// Value kept for a key present in several inputs
+    #[arg(long, value_enum, default_value_t = OnDuplicate::First)]
+    on_duplicate: OnDuplicate,
+
+    /// Version number recorded in the output
+    #[arg(long, default_value_t = 1)]
+    db_version: u32,
+
+    /// Build threads (all cores by default)
+    #[arg(long)]
+    threads: Option<usize>,
+
+    /// Also write a sorted key index, for range and prefix scans
+    #[arg(long)]
+    sorted_keys: bool,
+
+    /// Store value offsets Elias-Fano encoded when the values allow it
+    #[arg(long)]
+    compress_offsets: bool,
+
+    /// Store each distinct value once, shared by every key holding it
+    #[arg(long, conflicts_with = "compress_offsets")]
+    dedup_values: bool,
+}
+
+/// Report the keys added, removed and changed between two databases
+///
+/// Both databases must have the same key width, one of the widths `kvfast`
+/// handles: variable-length keys, or 8-, 16- or 32-byte keys.
+#[derive(clap::Args)]
+struct DiffArgs {
+    /// Old database, in the same form as the merge inputs
+    old: String,
+
+    /// New database
+    new: String,
+
+    /// Also write a delta file that turns the old database into the new one
+    #[arg(long)]
+    delta: Option<PathBuf>,
+}
+
+/// Convert a database written in the baseline layout of the first release
+#[derive(clap::Args)]
+struct UpgradeArgs {
+    /// Data and index files of the old database, as `db.data,db.index`
+    input: String,
+
+    /// Database to write, in the same form as the merge inputs
+    #[arg(short, long)]
+    output: String,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum OnDuplicate {
+    /// Keep the value of the highest-priority input
+    First,
+    /// Keep the value of the lowest-priority input
+    Last,
+    /// Fail
+    Error,
+}
+
+// Files of a database given on the command line
+enum Files {
+    Single(PathBuf),
+    Pair(PathBuf, PathBuf),
+}
+
+impl Files {
+    fn parse(arg: &str) -> Self {
+        match arg.split_once(',') {
+            Some((data, index)) => Files::Pair(data.into(), index.into()),
+            None => Files::Single(arg.into()),
+        }
+    }
+
+    // Key width recorded in the database's header
+    fn key_size(&self) -> Result<usize> {
+        match self {
+            Files::Single(path) => stored_key_size_single(path),
+            Files::Pair(data, index) => stored_key_size(data, index),
+        }
+    }
+
+    fn open<const N: usize>(&self) -> Result<Database<N>> {
+        match self {
+            Files::Single(path) => Database::open_single(path),
+            Files::Pair(data, index) => Database::open(data, index),
+        }
+    }
+}
+
+fn merge<const N: usize>(args: &MergeArgs) -> Result<()> {
+    let sources = args.inputs.iter().map(|input| Files::parse(input).open::<N>()).collect::<Result<Vec<_>>>()?;
+    let sources: Vec<&Database<N>> = sources.iter().collect();
+    let options = WriteOptions {
+        duplicates: match args.on_duplicate {
+            OnDuplicate::First => DuplicatePolicy::KeepFirst,
+            OnDuplicate::Last => DuplicatePolicy::KeepLast,
+            OnDuplicate::Error => DuplicatePolicy::Error,
+        },
+        threads: args.threads,
+        sorted_keys: args.sorted_keys,
+        compress_offsets: args.compress_offsets,
+        dedup_values: args.dedup_values,
+        ..Default::default()
+    };
+
+    let report = match Files::parse(&args.output) {
+        Files::Single(path) => Database::merge_single(&sources, path, args.db_version, &options)?,
+        Files::Pair(data, index) => Database::merge(&sources, data, index, args.db_version, &options)?,
+    };
+    println!(
+        "Wrote {} keys from {} inputs ({} keys in several inputs, {} values dropped)",
+        report.num_keys,
+        sources.len(),
+        report.duplicate_keys,
+        report.collisions
+    );
+    Ok(())
+}
+
+fn diff_databases<const N: usize>(args: &DiffArgs) -> Result<()> {
+    let old = Files::parse(&args.old).open::<N>()?;
+    let new = Files::parse(&args.new).open::<N>()?;
+    let diff = diff(&old, &new)?;
+    println!("{}", diff.summary());
+    if let Some(path) = &args.delta {
+        diff.write_delta(path)?;
+        println!("Wrote {} changes to {}", diff.changes().len(), path.display());
+    }
+    Ok(())
+}
+
+fn upgrade(args: &UpgradeArgs) -> Result<()> {
+    let Files::Pair(data, index) = Files::parse(&args.input) else {
+        return Err(Error::InvalidArgument("baseline databases are a data and an index file".to_string()));
+    };
+    let output = Files::parse(&args.output);
+    let spill_dir = match &output {
+        Files::Single(path) | Files::Pair(path, _) => path.parent().filter(|dir| !dir.as_os_str().is_empty()),
+    };
+    let mut builder = Database::<KEY_SIZE>::builder(spill_dir.unwrap_or(".".as_ref()), WriteOptions::default())?;
+    let version = upgrade_into(&data, &index, &mut builder)?;
+    let report = match output {
+        Files::Single(path) => builder.finish_single(path, version)?,
+        Files::Pair(data, index) => builder.finish(data, index, version)?,
+    };
+    println!("Upgraded {} keys", report.num_keys);
+    Ok(())
+}
+
+// Runs `run` with the key width stored in the `inputs` databases, which must all have the same one
+fn with_key_size<A>(inputs: &[&String], args: &A, run: [fn(&A) -> Result<()>; 4]) -> Result<()> {
+    let sizes = inputs.iter().map(|input| Files::parse(input).key_size()).collect::<Result<Vec<_>>>()?;
+    if let Some(other) = sizes.iter().position(|&size| size != sizes[0]) {
+        let describe = |size| match size {
+            VAR_KEY_SIZE => "variable-length keys".to_string(),
+            size => format!("{}-byte keys", size),
+        };
+        return Err(Error::InvalidArgument(format!(
+            "{} has {} but {} has {}",
+            inputs[0],
+            describe(sizes[0]),
+            inputs[other],
+            describe(sizes[other])
+        )));
+    }
+    match sizes.first().copied().unwrap_or(KEY_SIZE) {
+        VAR_KEY_SIZE => run[0](args),
+        8 => run[1](args),
+        KEY_SIZE => run[2](args),
+        32 => run[3](args),
+        key_size => Err(Error::InvalidArgument(format!(
+            "{} has {}-byte keys; supported are variable-length keys and 8-, 16- and 32-byte keys",
+            inputs[0], key_size
+        ))),
+    }
+}
 
-    for v in &possible_objects {
-        let idx = phf.index(v);
-        println!("Index for {}: {}", v, idx)
+fn main() -> ExitCode {
+    let cli = Cli::parse();
+    let result = match &cli.command {
+        Command::Merge(args) => with_key_size(
+            &args.inputs.iter().collect::<Vec<_>>(),
+            args,
+            [merge::<VAR_KEY_SIZE>, merge::<8>, merge::<KEY_SIZE>, merge::<32>],
+        ),
+        Command::Diff(args) => with_key_size(
+            &[&args.old, &args.new],
+            args,
+            [diff_databases::<VAR_KEY_SIZE>, diff_databases::<8>, diff_databases::<KEY_SIZE>, diff_databases::<32>],
+        ),
+        Command::Upgrade(args) => upgrade(args),
+    };
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("kvfast: {}", e);
+            ExitCode::FAILURE
+        }
     }
 }
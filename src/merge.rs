@@ -0,0 +1,130 @@
+use crate::builder::{BuildReport, DatabaseBuilder};
+use crate::database::{Database, DuplicatePolicy, WriteOptions};
+use crate::error::Result;
+use crate::writer::spill_dir;
+use std::path::Path;
+
+// Inserts every entry of `sources` into `builder`, in priority order: all of
+// the first source, then all of the second, and so on. Keys present in several
+// sources are then resolved by the builder's `WriteOptions::duplicates`, which
+// sees their values in that order: `KeepFirst` keeps the value of the
+// highest-priority source, `KeepLast` that of the lowest, and `Merge` folds
+// them starting from the highest. Values are streamed from the sources' mappings
+// into the builder's spill file, so they are never all held in memory.
+//
+// Every source must store its keys (see `Database::has_keys`).
+pub fn merge_into<const N: usize>(sources: &[&Database<N>], builder: &mut DatabaseBuilder<N>) -> Result<()> {
+    for source in sources {
+        for (key, value) in source.iter()? {
+            builder.insert(&key, value)?;
+        }
+    }
+    Ok(())
+}
+
+// Builder for `merge` and `merge_single`. When every source has the same value
+// codec, the merged database keeps it, so merging typed databases yields one.
+// Values folded by a `DuplicatePolicy::Merge` closure are whatever bytes it
+// returns, so then the merged database records raw values.
+fn merge_builder<const N: usize>(
+    sources: &[&Database<N>],
+    spill_dir: &Path,
+    options: &WriteOptions,
+) -> Result<DatabaseBuilder<N>> {
+    let builder = DatabaseBuilder::<N>::new(spill_dir, options.clone())?;
+    if matches!(options.duplicates, DuplicatePolicy::Merge(_)) {
+        return Ok(builder);
+    }
+    match sources.split_first() {
+        Some((first, rest)) if rest.iter().all(|source| source.value_codec() == first.value_codec()) => {
+            let (codec, value_type) = first.value_codec();
+            Ok(builder.value_codec(codec, value_type))
+        }
+        _ => Ok(builder),
+    }
+}
+
+impl<const N: usize> Database<N> {
+    // Merges `sources`, in priority order, into a new two-file database with a
+    // fresh MPHF; see `merge_into`
+    pub fn merge<P: AsRef<Path>>(
+        sources: &[&Database<N>],
+        path_data: P,
+        path_index: P,
+        version: u32,
+        options: &WriteOptions,
+    ) -> Result<BuildReport> {
+        let mut builder = merge_builder(sources, spill_dir(path_data.as_ref()), options)?;
+        merge_into(sources, &mut builder)?;
+        builder.finish(path_data, path_index, version)
+    }
+
+    // Same as `merge`, writing a single-file database
+    pub fn merge_single<P: AsRef<Path>>(
+        sources: &[&Database<N>],
+        path: P,
+        version: u32,
+        options: &WriteOptions,
+    ) -> Result<BuildReport> {
+        let mut builder = merge_builder(sources, spill_dir(path.as_ref()), options)?;
+        merge_into(sources, &mut builder)?;
+        builder.finish_single(path, version)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::builder::BuildReport;
+    use crate::database::{DuplicatePolicy, KeyCheck, VarDatabase, WriteOptions};
+    use crate::error::{Error, Result};
+    use std::sync::Arc;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_merge_in_priority_order() -> Result<()> {
+        let dir = TempDir::new()?;
+        let write = |name: &str, keys: std::ops::Range<u32>, tag: &str| -> Result<VarDatabase> {
+            let path = dir.path().join(name);
+            let keys: Vec<Vec<u8>> = keys.map(|i| format!("key-{}", i).into_bytes()).collect();
+            let values = keys.iter().map(|key| [key.as_slice(), b"@", tag.as_bytes()].concat());
+            VarDatabase::write_database_single(&path, keys.iter(), values, 1, &WriteOptions::default())?;
+            VarDatabase::open_single(&path)
+        };
+        let base = write("base.kvdb", 0..1000, "base")?;
+        let patch = write("patch.kvdb", 900..1100, "patch")?;
+        let hotfix = write("hotfix.kvdb", 950..960, "hotfix")?;
+        let sources = [&hotfix, &patch, &base];
+
+        let output = dir.path().join("merged.kvdb");
+        let options = WriteOptions { duplicates: DuplicatePolicy::KeepFirst, ..Default::default() };
+        let report = VarDatabase::merge_single(&sources, &output, 2, &options)?;
+        assert_eq!(report, BuildReport { num_keys: 1100, duplicate_keys: 100, collisions: 110 });
+        let merged = VarDatabase::open_single(&output)?;
+        assert_eq!(merged.get(b"key-5"), Some(&b"key-5@base"[..]));
+        assert_eq!(merged.get(b"key-920"), Some(&b"key-920@patch"[..]));
+        assert_eq!(merged.get(b"key-955"), Some(&b"key-955@hotfix"[..]));
+        assert_eq!(merged.get(b"key-1050"), Some(&b"key-1050@patch"[..]));
+
+        // A closure sees the values from the highest priority down
+        let concat = |_: &[u8], kept: &[u8], next: &[u8]| [kept, b"|", next].concat();
+        let options = WriteOptions { duplicates: DuplicatePolicy::Merge(Arc::new(concat)), ..Default::default() };
+        let (data, index) = (dir.path().join("merged.data"), dir.path().join("merged.index"));
+        VarDatabase::merge(&sources, &data, &index, 2, &options)?;
+        let merged = VarDatabase::open(&data, &index)?;
+        assert_eq!(merged.get(b"key-955"), Some(&b"key-955@hotfix|key-955@patch|key-955@base"[..]));
+
+        // Overlaps fail under the default policy, and sources must store their keys
+        let err = VarDatabase::merge_single(&sources, &output, 2, &WriteOptions::default())
+            .expect_err("Merge should fail");
+        assert!(matches!(err, Error::DuplicateKey { .. }));
+        let fingerprinted = dir.path().join("fingerprinted.kvdb");
+        let options = WriteOptions { key_check: KeyCheck::Fingerprint { bits: 16 }, ..Default::default() };
+        VarDatabase::write_database_single(&fingerprinted, [b"k"].iter(), [b"v"].iter(), 1, &options)?;
+        let fingerprinted = VarDatabase::open_single(&fingerprinted)?;
+        let err = VarDatabase::merge_single(&[&fingerprinted], &output, 2, &WriteOptions::default())
+            .expect_err("Merge should fail");
+        assert!(matches!(err, Error::Unsupported(_)));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,433 @@
+use crate::builder::{DatabaseBuilder, TempFile};
+use crate::database::{Database, KEY_SIZE, VAR_KEY_SIZE};
+use crate::error::{Error, Result};
+use crate::header::{parse_header, DeltaHeader, DELTA_OP_DELETE, DELTA_OP_UPSERT, DELTA_VERSION};
+use crate::writer::{publish, spill_dir, sync_dir, write_atomically};
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use xxhash_rust::xxh3::xxh3_64;
+
+// Bytes of a delta record before the key: op, key length, value length and a
+// checksum of those
+const RECORD_PREFIX_SIZE: usize = 13;
+
+// Upserts and deletes recorded in an append-only delta file. The file is
+// replayed into a sorted map when opened. A crash while a record was appended
+// can leave it cut short, or leave zeros or other garbage in its place, so
+// anything after the last valid record that no valid record follows is taken
+// for a torn append and dropped. An invalid record followed by a valid one
+// fails the open and leaves the file as it is.
+pub struct Delta {
+    path: PathBuf,
+    file: File,
+    // Length of the file up to the end of the last complete record
+    len: u64,
+    key_size: usize,
+    entries: BTreeMap<Vec<u8>, Option<Vec<u8>>>, // None marks a deleted key
+}
+
+impl Delta {
+    // Opens the delta file at `path` for keys of `key_size` bytes
+    // (`VAR_KEY_SIZE` for variable-length keys), creating it if it does not exist
+    pub fn open<P: AsRef<Path>>(path: P, key_size: usize) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        if !path.exists() {
+            let header = DeltaHeader { magic: *b"KVDL", version: DELTA_VERSION, key_size: key_size as u64 };
+            write_atomically(&path, &header.to_bytes())?;
+        }
+
+        let bytes = fs::read(&path)?;
+        let header = parse_header(&bytes, DeltaHeader::SIZE, "KVDL delta header", DeltaHeader::from_bytes)?;
+        if header.version != DELTA_VERSION {
+            return Err(Error::UnsupportedVersion { section: "KVDL delta header", version: header.version });
+        }
+        if header.key_size != key_size as u64 {
+            return Err(Error::KeySizeMismatch { stored: header.key_size, requested: key_size });
+        }
+
+        let mut entries = BTreeMap::new();
+        let mut valid_len = DeltaHeader::SIZE;
+        loop {
+            let (op, key, value, len) = match decode_record(&bytes[valid_len..]) {
+                Ok(Some(record)) => record,
+                Ok(None) => break,
+                Err(e) if has_valid_record(&bytes[valid_len + 1..]) => return Err(e),
+                Err(_) => break,
+            };
+            match op {
+                DELTA_OP_UPSERT => entries.insert(key.to_vec(), Some(value.to_vec())),
+                _ => entries.insert(key.to_vec(), None),
+            };
+            valid_len += len;
+        }
+
+        // Drop a torn tail so new records are appended after the last good one
+        let file = fs::OpenOptions::new().append(true).open(&path)?;
+        if valid_len < bytes.len() {
+            file.set_len(valid_len as u64)?;
+            file.sync_all()?;
+        }
+        Ok(Delta { path, file, len: valid_len as u64, key_size, entries })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    // Sets `key` to `value`
+    pub fn upsert(&mut self, key: &[u8], value: &[u8]) -> Result<()> {
+        self.append(DELTA_OP_UPSERT, key, value)?;
+        self.entries.insert(key.to_vec(), Some(value.to_vec()));
+        Ok(())
+    }
+
+    // Deletes `key`, whether it is in the base database or was upserted
+    pub fn delete(&mut self, key: &[u8]) -> Result<()> {
+        self.append(DELTA_OP_DELETE, key, &[])?;
+        self.entries.insert(key.to_vec(), None);
+        Ok(())
+    }
+
+    // What the delta says about `key`: None if nothing, Some(None) if it is
+    // deleted, Some(Some(value)) if it was upserted
+    pub fn get(&self, key: &[u8]) -> Option<Option<&[u8]>> {
+        self.entries.get(key).map(|value| value.as_deref())
+    }
+
+    // Keys with their latest upserted value, or None if deleted, in key order
+    pub fn entries(&self) -> impl Iterator<Item = (&[u8], Option<&[u8]>)> {
+        self.entries.iter().map(|(key, value)| (key.as_slice(), value.as_deref()))
+    }
+
+    // Number of keys upserted or deleted
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    // Flushes the records appended so far to disk
+    pub fn sync(&self) -> Result<()> {
+        self.file.sync_data()?;
+        Ok(())
+    }
+
+    // Writes a new delta file at `path` holding `entries` (a None value deletes
+    // the key), atomically replacing any file there
+    pub fn write<'a, P: AsRef<Path>>(
+        path: P,
+        key_size: usize,
+        entries: impl IntoIterator<Item = (&'a [u8], Option<&'a [u8]>)>,
+    ) -> Result<()> {
+        let path = path.as_ref();
+        let (temp, file) = TempFile::create_in(spill_dir(path), "delta")?;
+        let mut file = BufWriter::new(file);
+        let header = DeltaHeader { magic: *b"KVDL", version: DELTA_VERSION, key_size: key_size as u64 };
+        file.write_all(&header.to_bytes())?;
+        for (key, value) in entries {
+            let record = match value {
+                Some(value) => encode_record(key_size, DELTA_OP_UPSERT, key, value)?,
+                None => encode_record(key_size, DELTA_OP_DELETE, key, &[])?,
+            };
+            file.write_all(&record)?;
+        }
+        let file = file.into_inner().map_err(|e| e.into_error())?;
+        publish(temp, file, path)?;
+        sync_dir(spill_dir(path))?;
+        Ok(())
+    }
+
+    fn append(&mut self, op: u8, key: &[u8], value: &[u8]) -> Result<()> {
+        let record = encode_record(self.key_size, op, key, value)?;
+        // A single write, so a crash leaves at most one torn record at the end.
+        // A failed write may have left part of the record behind, which later
+        // appends would follow; it is cut off again.
+        if let Err(e) = self.file.write_all(&record) {
+            self.file.set_len(self.len)?;
+            return Err(e.into());
+        }
+        self.len += record.len() as u64;
+        Ok(())
+    }
+}
+
+// Encodes a delta record for a file of `key_size`-byte keys
+fn encode_record(key_size: usize, op: u8, key: &[u8], value: &[u8]) -> Result<Vec<u8>> {
+    if key_size != VAR_KEY_SIZE && key.len() != key_size {
+        return Err(Error::InvalidKeyLength { expected: key_size, got: key.len() });
+    }
+    let (Ok(key_len), Ok(value_len)) = (u32::try_from(key.len()), u32::try_from(value.len())) else {
+        return Err(Error::InvalidArgument("Delta keys and values must be shorter than 4 GiB".to_string()));
+    };
+
+    let mut record = Vec::with_capacity(RECORD_PREFIX_SIZE + key.len() + value.len() + 8);
+    record.push(op);
+    record.extend_from_slice(&key_len.to_le_bytes());
+    record.extend_from_slice(&value_len.to_le_bytes());
+    record.extend_from_slice(&(xxh3_64(&record) as u32).to_le_bytes());
+    record.extend_from_slice(key);
+    record.extend_from_slice(value);
+    record.extend_from_slice(&xxh3_64(&record).to_le_bytes());
+    Ok(record)
+}
+
+// A decoded delta record: op, key, value and record length
+type Record<'a> = (u8, &'a [u8], &'a [u8], usize);
+
+// Parses the record at the start of `bytes`: op, key, value and record length.
+// Ok(None) if `bytes` ends before the record does, as after a crash during an
+// append; an error if the record does not match its checksums. The prefix has
+// a checksum of its own, so a corrupt length is not taken for a torn record.
+fn decode_record(bytes: &[u8]) -> Result<Option<Record<'_>>> {
+    let corrupt = || Err(Error::ChecksumMismatch { section: "delta record" });
+    let Some(prefix) = bytes.get(..RECORD_PREFIX_SIZE) else {
+        return Ok(None);
+    };
+    let field = |range: Range<usize>| u32::from_le_bytes(prefix[range].try_into().unwrap_or_default());
+    let op = prefix[0];
+    if xxh3_64(&prefix[..9]) as u32 != field(9..13) || !matches!(op, DELTA_OP_UPSERT | DELTA_OP_DELETE) {
+        return corrupt();
+    }
+    let (key_len, value_len) = (field(1..5) as usize, field(5..9) as usize);
+    let body_end = RECORD_PREFIX_SIZE + key_len + value_len;
+    let Some(checksum) = bytes.get(body_end..body_end + 8) else {
+        return Ok(None);
+    };
+    if xxh3_64(&bytes[..body_end]).to_le_bytes() != checksum {
+        return corrupt();
+    }
+    let key = &bytes[RECORD_PREFIX_SIZE..RECORD_PREFIX_SIZE + key_len];
+    Ok(Some((op, key, &bytes[RECORD_PREFIX_SIZE + key_len..body_end], body_end + 8)))
+}
+
+// Whether a valid record starts anywhere in `bytes`; tells garbage left by a
+// torn append from a corrupt record in the middle of a delta file
+fn has_valid_record(bytes: &[u8]) -> bool {
+    (0..bytes.len()).any(|start| matches!(decode_record(&bytes[start..]), Ok(Some(_))))
+}
+
+// A read-only database with a small mutable layer on top: upserts and deletes
+// go to a `Delta` and are visible to `get` right away, which checks the delta
+// before the base database. `fold_into` writes the combined contents into a
+// builder, so the delta can be dropped at the next rebuild.
+pub struct OverlayDatabase<const N: usize = KEY_SIZE> {
+    base: Database<N>,
+    delta: Delta,
+}
+
+impl<const N: usize> OverlayDatabase<N> {
+    // Layers the delta file at `delta_path` over `base`, creating it if needed
+    pub fn open<P: AsRef<Path>>(base: Database<N>, delta_path: P) -> Result<Self> {
+        let delta = Delta::open(delta_path, N)?;
+        Ok(OverlayDatabase { base, delta })
+    }
+
+    pub fn base(&self) -> &Database<N> {
+        &self.base
+    }
+
+    pub fn delta(&self) -> &Delta {
+        &self.delta
+    }
+
+    pub fn get(&self, key: &[u8]) -> Option<&[u8]> {
+        match self.delta.get(key) {
+            Some(value) => value,
+            None => self.base.get(key),
+        }
+    }
+
+    pub fn upsert(&mut self, key: &[u8], value: &[u8]) -> Result<()> {
+        self.delta.upsert(key, value)
+    }
+
+    pub fn delete(&mut self, key: &[u8]) -> Result<()> {
+        self.delta.delete(key)
+    }
+
+    // Flushes the delta to disk, making the changes so far durable
+    pub fn sync(&self) -> Result<()> {
+        self.delta.sync()
+    }
+
+    // Inserts every live entry into `builder`: entries of the base database not
+    // changed by the delta, then the upserted ones. Needs a base database that
+    // stores its keys.
+    pub fn fold_into(&self, builder: &mut DatabaseBuilder<N>) -> Result<()> {
+        for (key, value) in self.base.iter()? {
+            if self.delta.get(&key).is_none() {
+                builder.insert(&key, value)?;
+            }
+        }
+        for (key, value) in self.delta.entries() {
+            if let Some(value) = value {
+                builder.insert(key, value)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::{VarDatabase, WriteOptions};
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_overlay_upserts_and_deletes() -> Result<()> {
+        let dir = TempDir::new()?;
+        let base_path = dir.path().join("base.kvdb");
+        let delta_path = dir.path().join("base.kvdl");
+        let keys: Vec<Vec<u8>> = (0..100u32).map(|i| format!("key-{}", i).into_bytes()).collect();
+        VarDatabase::write_database_single(&base_path, keys.iter(), keys.iter(), 1, &WriteOptions::default())?;
+
+        let mut overlay = OverlayDatabase::open(VarDatabase::open_single(&base_path)?, &delta_path)?;
+        overlay.upsert(b"key-1", b"fixed")?;
+        overlay.upsert(b"new", b"added")?;
+        overlay.delete(b"key-2")?;
+        overlay.upsert(b"key-3", b"gone soon")?;
+        overlay.delete(b"key-3")?;
+        overlay.sync()?;
+        assert_eq!(overlay.get(b"key-0"), Some(&b"key-0"[..]));
+        assert_eq!(overlay.get(b"key-1"), Some(&b"fixed"[..]));
+        assert_eq!(overlay.get(b"new"), Some(&b"added"[..]));
+        assert_eq!(overlay.get(b"key-2"), None);
+        assert_eq!(overlay.get(b"key-3"), None);
+        drop(overlay);
+
+        // The delta is replayed on open, and a torn last record is dropped
+        let mut bytes = fs::read(&delta_path)?;
+        let good_len = bytes.len();
+        bytes.extend_from_slice(&[DELTA_OP_UPSERT, 4, 0, 0, 0, 1, 0]);
+        fs::write(&delta_path, &bytes)?;
+        let mut overlay = OverlayDatabase::open(VarDatabase::open_single(&base_path)?, &delta_path)?;
+        assert_eq!(fs::metadata(&delta_path)?.len(), good_len as u64);
+        assert_eq!(overlay.delta().len(), 4);
+        assert_eq!(overlay.get(b"key-1"), Some(&b"fixed"[..]));
+        assert_eq!(overlay.get(b"key-3"), None);
+        overlay.upsert(b"key-4", b"after replay")?;
+
+        // Folding writes the combined contents into a new database
+        let mut builder = VarDatabase::builder(dir.path(), WriteOptions::default())?;
+        overlay.fold_into(&mut builder)?;
+        let folded_path = dir.path().join("folded.kvdb");
+        assert_eq!(builder.finish_single(&folded_path, 2)?.num_keys, 99);
+        let folded = VarDatabase::open_single(&folded_path)?;
+        for key in keys.iter().map(Vec::as_slice).chain([&b"new"[..]]) {
+            assert_eq!(folded.get(key), overlay.get(key));
+        }
+        assert_eq!(folded.get(b"key-4"), Some(&b"after replay"[..]));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_corrupt_delta_record_fails_without_truncating() -> Result<()> {
+        let dir = TempDir::new()?;
+        let path = dir.path().join("db.kvdl");
+        let mut delta = Delta::open(&path, VAR_KEY_SIZE)?;
+        for i in 0..10 {
+            delta.upsert(format!("key-{}", i).as_bytes(), b"value")?;
+        }
+        drop(delta);
+        let good = fs::read(&path)?;
+
+        // A flipped byte in the first record's key, or in its key length
+        let first_record = DeltaHeader::SIZE;
+        for position in [first_record + RECORD_PREFIX_SIZE + 1, first_record + 1] {
+            let mut bytes = good.clone();
+            bytes[position] ^= 0x40;
+            fs::write(&path, &bytes)?;
+            let err = Delta::open(&path, VAR_KEY_SIZE).err().expect("Open should fail");
+            assert!(matches!(err, Error::ChecksumMismatch { section: "delta record" }), "{}", err);
+            assert_eq!(fs::read(&path)?, bytes);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_torn_delta_tails_are_dropped() -> Result<()> {
+        let dir = TempDir::new()?;
+        let path = dir.path().join("db.kvdl");
+        let mut delta = Delta::open(&path, VAR_KEY_SIZE)?;
+        for i in 0..10 {
+            delta.upsert(format!("key-{}", i).as_bytes(), b"value")?;
+        }
+        drop(delta);
+        let good = fs::read(&path)?;
+
+        // Zeros, garbage, and a whole last record with a flipped byte, as a
+        // crash can leave where an append did not reach the disk
+        let record = encode_record(VAR_KEY_SIZE, DELTA_OP_UPSERT, b"key-10", b"value")?;
+        let mut flipped = record.clone();
+        flipped[RECORD_PREFIX_SIZE + 2] ^= 0x40;
+        for tail in [vec![0u8; 100], b"garbage garbage garbage".to_vec(), flipped] {
+            fs::write(&path, [&good[..], &tail[..]].concat())?;
+            let mut delta = Delta::open(&path, VAR_KEY_SIZE)?;
+            assert_eq!(fs::read(&path)?, good);
+            assert_eq!(delta.len(), 10);
+            delta.upsert(b"key-10", b"value")?;
+            drop(delta);
+            assert_eq!(fs::read(&path)?, [&good[..], &record[..]].concat());
+            assert_eq!(Delta::open(&path, VAR_KEY_SIZE)?.len(), 11);
+        }
+
+        Ok(())
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_failed_append_leaves_no_partial_record() -> Result<()> {
+        use std::os::fd::{AsRawFd, FromRawFd};
+
+        let dir = TempDir::new()?;
+        let path = dir.path().join("db.kvdl");
+        let mut delta = Delta::open(&path, VAR_KEY_SIZE)?;
+        delta.upsert(b"key", b"value")?;
+        let good = fs::read(&path)?;
+
+        // A file that already holds the start of the next record and cannot
+        // grow, as if the write failed partway
+        // SAFETY: the new descriptor is owned by `file`
+        let mut file = unsafe {
+            let fd = libc::memfd_create(c"delta".as_ptr(), libc::MFD_ALLOW_SEALING);
+            assert!(fd >= 0);
+            File::from_raw_fd(fd)
+        };
+        file.write_all(&good)?;
+        file.write_all(&encode_record(VAR_KEY_SIZE, DELTA_OP_UPSERT, b"other", b"value")?[..7])?;
+        // SAFETY: plain syscall on a descriptor owned by `file`
+        let sealed = unsafe { libc::fcntl(file.as_raw_fd(), libc::F_ADD_SEALS, libc::F_SEAL_GROW) };
+        assert_eq!(sealed, 0);
+        delta.file = file;
+
+        assert!(matches!(delta.upsert(b"other", b"value"), Err(Error::Io(_))));
+        assert_eq!(delta.file.metadata()?.len(), good.len() as u64);
+        assert_eq!(delta.get(b"other"), None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_delta_validates_keys() -> Result<()> {
+        let dir = TempDir::new()?;
+        let path = dir.path().join("fixed.kvdl");
+        let mut delta = Delta::open(&path, KEY_SIZE)?;
+        assert!(matches!(delta.upsert(b"short", b"v"), Err(Error::InvalidKeyLength { expected: KEY_SIZE, got: 5 })));
+        delta.upsert(&[7; KEY_SIZE], b"v")?;
+        drop(delta);
+
+        assert!(matches!(Delta::open(&path, VAR_KEY_SIZE), Err(Error::KeySizeMismatch { .. })));
+        fs::write(&path, b"garbage garbage garbage")?;
+        assert!(matches!(Delta::open(&path, KEY_SIZE), Err(Error::BadMagic { .. })));
+
+        Ok(())
+    }
+}
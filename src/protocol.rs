@@ -40,7 +40,11 @@ pub enum RespValue {
     Array(Vec<RespValue>),
 }
 
-use std::io::{self, BufRead};
+use std::io::{self, BufRead, Read};
+
+// Upper bound on the elements or bytes preallocated from a length prefix, so a
+// bogus length fails on the short read instead of on the allocation
+const MAX_PREALLOC: usize = 1 << 16;
 
 pub fn parse_resp<R: BufRead>(reader: &mut R) -> io::Result<RespValue> {
     let mut first = [0u8; 1];
@@ -69,8 +73,11 @@ pub fn parse_resp<R: BufRead>(reader: &mut R) -> io::Result<RespValue> {
             if len < 0 {
                 return Ok(RespValue::BulkString(Vec::new()));
             }
-            let mut buf = vec![0u8; len as usize];
-            reader.read_exact(&mut buf)?;
+            let mut buf = Vec::with_capacity((len as usize).min(MAX_PREALLOC));
+            reader.by_ref().take(len as u64).read_to_end(&mut buf)?;
+            if buf.len() as u64 != len as u64 {
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Truncated RESP bulk string"));
+            }
             let mut crlf = [0u8; 2];
             reader.read_exact(&mut crlf)?;
             Ok(RespValue::BulkString(buf))
@@ -79,7 +86,7 @@ pub fn parse_resp<R: BufRead>(reader: &mut R) -> io::Result<RespValue> {
             let mut len_line = String::new();
             reader.read_line(&mut len_line)?;
             let count: i64 = len_line.trim_end().parse().unwrap_or(0);
-            let mut items = Vec::with_capacity(count as usize);
+            let mut items = Vec::with_capacity((count.max(0) as usize).min(MAX_PREALLOC));
             for _ in 0..count {
                 let v = parse_resp(reader)?;
                 items.push(v);
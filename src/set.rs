@@ -0,0 +1,180 @@
+use crate::builder::{BuildReport, DatabaseBuilder};
+use crate::database::{Database, KeyCheck, OpenOptions, WriteOptions, KEY_SIZE, VAR_KEY_SIZE};
+use crate::error::{Error, Result};
+use crate::iter::KeyRef;
+use crate::verify::Corruption;
+use crate::writer::spill_dir;
+use std::path::Path;
+
+// A membership-only database: the MPHF and the keys (or their fingerprints),
+// with no value offsets and no values. Stored as a single file, in the
+// single-file layout with empty offsets and values sections.
+pub struct StaticSet<const N: usize = KEY_SIZE> {
+    db: Database<N>,
+}
+
+pub type VarStaticSet = StaticSet<VAR_KEY_SIZE>;
+
+impl<const N: usize> StaticSet<N> {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Self::open_with_options(path, &OpenOptions::default())
+    }
+
+    pub fn open_with_options<P: AsRef<Path>>(path: P, options: &OpenOptions) -> Result<Self> {
+        Ok(StaticSet { db: Database::open_single_as(path, options, true)? })
+    }
+
+    // Starts a `StaticSetBuilder` for this key width
+    pub fn builder<P: AsRef<Path>>(spill_dir: P, options: WriteOptions) -> Result<StaticSetBuilder<N>> {
+        StaticSetBuilder::new(spill_dir, options)
+    }
+
+    // Writes a set of `keys`. Keys given more than once are resolved by
+    // `WriteOptions::duplicates`, which fails by default; any other policy
+    // keeps them once.
+    pub fn write<K, PK, P>(path: P, keys: K, version: u32, options: &WriteOptions) -> Result<BuildReport>
+    where
+        K: Iterator<Item = PK>,
+        PK: AsRef<[u8]>,
+        P: AsRef<Path>,
+    {
+        let mut builder = StaticSetBuilder::<N>::new(spill_dir(path.as_ref()), options.clone())?;
+        for key in keys {
+            builder.insert(key.as_ref())?;
+        }
+        builder.finish(path, version)
+    }
+
+    // Whether `key` is in the set; exact unless the set was written with
+    // `KeyCheck::Fingerprint`
+    pub fn contains(&self, key: &[u8]) -> bool {
+        self.db.contains(key)
+    }
+
+    // Batched `contains`
+    pub fn contains_many<K: AsRef<[u8]>>(&self, keys: &[K]) -> Vec<bool> {
+        self.db.contains_many(keys)
+    }
+
+    pub fn len(&self) -> usize {
+        self.db.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.db.is_empty()
+    }
+
+    // Iterates over the keys in slot order; fails for fingerprinted sets
+    pub fn keys(&self) -> Result<impl DoubleEndedIterator<Item = KeyRef<'_, N>> + ExactSizeIterator> {
+        self.db.keys()
+    }
+
+    // See `Database::verify`
+    pub fn verify(&self) -> Vec<Corruption> {
+        self.db.verify()
+    }
+}
+
+// Builds a `StaticSet`, keeping only the keys in memory
+pub struct StaticSetBuilder<const N: usize = KEY_SIZE> {
+    inner: DatabaseBuilder<N>,
+}
+
+impl<const N: usize> StaticSetBuilder<N> {
+    // Creates a builder with temporary files in `spill_dir`. A set cannot be
+    // `KeyCheck::Trusted`, which would contain every key, and has no values to
+    // checksum in blocks.
+    pub fn new<P: AsRef<Path>>(spill_dir: P, options: WriteOptions) -> Result<Self> {
+        if options.key_check == KeyCheck::Trusted {
+            return Err(Error::InvalidArgument("A static set needs stored keys or fingerprints".to_string()));
+        }
+        if options.value_block_size.is_some() {
+            return Err(Error::InvalidArgument("A static set has no values to checksum".to_string()));
+        }
+        Ok(StaticSetBuilder { inner: DatabaseBuilder::new(spill_dir, options)?.keys_only() })
+    }
+
+    pub fn insert(&mut self, key: &[u8]) -> Result<()> {
+        self.inner.insert(key, &[])
+    }
+
+    // Number of keys inserted so far
+    pub fn len(&self) -> usize {
+        self.inner.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.inner.is_empty()
+    }
+
+    // Builds the MPHF and writes the set, opened with `StaticSet::open`
+    pub fn finish<P: AsRef<Path>>(self, path: P, version: u32) -> Result<BuildReport> {
+        self.inner.finish_single(path, version)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::{DuplicatePolicy, VarDatabase};
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_static_set_membership() -> Result<()> {
+        let dir = TempDir::new()?;
+        let path = dir.path().join("blocklist.kvset");
+
+        let ids: Vec<[u8; KEY_SIZE]> = (0..5000u128).map(|i| (i * 3).to_le_bytes()).collect();
+        let options = WriteOptions { duplicates: DuplicatePolicy::KeepFirst, ..Default::default() };
+        let report = StaticSet::<KEY_SIZE>::write(&path, ids.iter().chain(&ids[..10]), 1, &options)?;
+        assert_eq!((report.num_keys, report.duplicate_keys), (5000, 10));
+
+        let set = StaticSet::<KEY_SIZE>::open(&path)?;
+        assert_eq!(set.len(), 5000);
+        assert!(set.verify().is_empty());
+        assert!(set.contains(&ids[42]));
+        assert!(!set.contains(&1u128.to_le_bytes()));
+        assert!(!set.contains(b"short"));
+        let queries: Vec<[u8; KEY_SIZE]> = (0..300u128).map(|i| i.to_le_bytes()).collect();
+        let expected: Vec<bool> = (0..300).map(|i| i % 3 == 0).collect();
+        assert_eq!(set.contains_many(&queries), expected);
+
+        // Only the MPHF and the keys are stored
+        let size = std::fs::metadata(&path)?.len();
+        assert!(size < 5000 * KEY_SIZE as u64 + 8192, "set is {} bytes", size);
+
+        // Sets and databases cannot be opened as each other
+        assert!(matches!(VarDatabase::open_single(&path), Err(Error::KeySizeMismatch { .. })));
+        assert!(matches!(Database::<KEY_SIZE>::open_single(&path), Err(Error::InvalidHeader(_))));
+        let db_path = dir.path().join("db.kvdb");
+        Database::<KEY_SIZE>::write_database_single(&db_path, ids.iter(), ids.iter(), 1, &WriteOptions::default())?;
+        assert!(matches!(StaticSet::<KEY_SIZE>::open(&db_path), Err(Error::InvalidHeader(_))));
+
+        let trusted = WriteOptions { key_check: KeyCheck::Trusted, ..Default::default() };
+        assert!(matches!(StaticSetBuilder::<KEY_SIZE>::new(dir.path(), trusted), Err(Error::InvalidArgument(_))));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_fingerprinted_variable_key_set() -> Result<()> {
+        let dir = TempDir::new()?;
+        let path = dir.path().join("emails.kvset");
+
+        let emails: Vec<String> = (0..2000).map(|i| format!("user{}@example.com", i)).collect();
+        let options = WriteOptions { key_check: KeyCheck::Fingerprint { bits: 32 }, ..Default::default() };
+        let mut builder = VarStaticSet::builder(dir.path(), options)?;
+        for email in &emails {
+            builder.insert(email.as_bytes())?;
+        }
+        builder.finish(&path, 1)?;
+
+        let set = VarStaticSet::open(&path)?;
+        assert!(emails.iter().all(|email| set.contains(email.as_bytes())));
+        let absent: Vec<String> = (0..2000).map(|i| format!("other{}@example.com", i)).collect();
+        assert!(set.contains_many(&absent).iter().filter(|&&present| present).count() <= 1);
+        assert!(matches!(set.keys(), Err(Error::Unsupported(_))));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,363 @@
+use crate::builder::{BuildReport, DatabaseBuilder};
+use crate::database::{Database, OpenOptions, WriteOptions, KEY_SIZE};
+use crate::error::{Error, Result};
+use crate::header::{
+    parse_header, ManifestHeader, MANIFEST_VERSION, SHARD_HASH_XXH3, SHARD_LAYOUT_SINGLE_FILE, SHARD_LAYOUT_TWO_FILES,
+};
+use crate::verify::Corruption;
+use crate::writer::write_atomically;
+use std::path::{Path, PathBuf};
+use std::{fs, ops::Range};
+use xxhash_rust::xxh3::xxh3_64_with_seed;
+
+// Name of the manifest file in the directory of a sharded database
+pub const MANIFEST_FILE: &str = "manifest.kvsm";
+
+// Seed of the shard hash. It is recorded in the manifest, so changing it only
+// affects newly built databases.
+const SHARD_SEED: u64 = 0x6b76_6661_7374_7368;
+
+// How each shard of a sharded database is stored
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ShardLayout {
+    // `shard-NNNNN.data` and `shard-NNNNN.index`, opened with `Database::open`
+    #[default]
+    TwoFiles,
+    // `shard-NNNNN.kvdb`, opened with `Database::open_single`
+    SingleFile,
+}
+
+// Describes how keys are partitioned into shards. Every machine building
+// shards of the same database must use the same manifest.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ShardManifest {
+    num_shards: u32,
+    layout: ShardLayout,
+    seed: u64,
+}
+
+impl ShardManifest {
+    pub fn new(num_shards: u32, layout: ShardLayout) -> Result<Self> {
+        if num_shards == 0 {
+            return Err(Error::InvalidArgument("A sharded database needs at least one shard".to_string()));
+        }
+        Ok(ShardManifest { num_shards, layout, seed: SHARD_SEED })
+    }
+
+    pub fn num_shards(&self) -> usize {
+        self.num_shards as usize
+    }
+
+    pub fn layout(&self) -> ShardLayout {
+        self.layout
+    }
+
+    // Shard holding `key`: a seeded xxh3 of the key scaled to the shard count.
+    // Depends only on the key bytes and the manifest, never on the machine.
+    pub fn shard_of(&self, key: &[u8]) -> usize {
+        ((xxh3_64_with_seed(key, self.seed) as u128 * self.num_shards as u128) >> 64) as usize
+    }
+
+    // Files of shard `shard` within `dir`
+    pub fn shard_paths(&self, dir: &Path, shard: usize) -> Vec<PathBuf> {
+        match self.layout {
+            ShardLayout::TwoFiles => vec![
+                dir.join(format!("shard-{:05}.data", shard)),
+                dir.join(format!("shard-{:05}.index", shard)),
+            ],
+            ShardLayout::SingleFile => vec![dir.join(format!("shard-{:05}.kvdb", shard))],
+        }
+    }
+
+    // Reads the manifest of the sharded database in `dir`, along with its key width
+    pub fn read(dir: &Path) -> Result<(Self, usize)> {
+        let bytes = fs::read(dir.join(MANIFEST_FILE))?;
+        let header = parse_header(&bytes, ManifestHeader::SIZE, "KVSM manifest", ManifestHeader::from_bytes)?;
+        if header.checksum != header.compute_checksum() {
+            return Err(Error::ChecksumMismatch { section: "KVSM manifest" });
+        }
+        if header.version != MANIFEST_VERSION {
+            return Err(Error::UnsupportedVersion { section: "KVSM manifest", version: header.version });
+        }
+        if header.hash != SHARD_HASH_XXH3 {
+            return Err(Error::InvalidHeader(format!("Unknown shard hash function {}", header.hash)));
+        }
+        let layout = match header.layout {
+            SHARD_LAYOUT_TWO_FILES => ShardLayout::TwoFiles,
+            SHARD_LAYOUT_SINGLE_FILE => ShardLayout::SingleFile,
+            layout => return Err(Error::InvalidHeader(format!("Unknown shard layout {}", layout))),
+        };
+        if header.num_shards == 0 {
+            return Err(Error::InvalidHeader("Manifest has no shards".to_string()));
+        }
+
+        let manifest = ShardManifest { num_shards: header.num_shards, layout, seed: header.seed };
+        Ok((manifest, header.key_size as usize))
+    }
+
+    // Writes the manifest of a sharded database with `key_size`-byte keys to `dir`
+    pub fn write(&self, dir: &Path, key_size: usize) -> Result<()> {
+        let mut header = ManifestHeader {
+            magic: *b"KVSM",
+            version: MANIFEST_VERSION,
+            num_shards: self.num_shards,
+            hash: SHARD_HASH_XXH3,
+            layout: match self.layout {
+                ShardLayout::TwoFiles => SHARD_LAYOUT_TWO_FILES,
+                ShardLayout::SingleFile => SHARD_LAYOUT_SINGLE_FILE,
+            },
+            key_size: key_size as u64,
+            seed: self.seed,
+            checksum: 0,
+        };
+        header.checksum = header.compute_checksum();
+        write_atomically(&dir.join(MANIFEST_FILE), &header.to_bytes())?;
+        Ok(())
+    }
+}
+
+// A database split into shards, each a normal database with its own MPHF.
+// Lookups are routed to the shard given by the manifest.
+pub struct ShardedDatabase<const N: usize = KEY_SIZE> {
+    manifest: ShardManifest,
+    shards: Vec<Database<N>>,
+}
+
+impl<const N: usize> ShardedDatabase<N> {
+    // Opens the sharded database in `dir`: its manifest and every shard
+    pub fn open<P: AsRef<Path>>(dir: P) -> Result<Self> {
+        Self::open_with_options(dir, &OpenOptions::default())
+    }
+
+    pub fn open_with_options<P: AsRef<Path>>(dir: P, options: &OpenOptions) -> Result<Self> {
+        let dir = dir.as_ref();
+        let (manifest, key_size) = ShardManifest::read(dir)?;
+        if key_size != N {
+            return Err(Error::KeySizeMismatch { stored: key_size as u64, requested: N });
+        }
+
+        let shards = (0..manifest.num_shards())
+            .map(|shard| {
+                let paths = manifest.shard_paths(dir, shard);
+                match manifest.layout {
+                    ShardLayout::TwoFiles => Database::open_with_options(&paths[0], &paths[1], options),
+                    ShardLayout::SingleFile => Database::open_single_with_options(&paths[0], options),
+                }
+            })
+            .collect::<Result<_>>()?;
+        Ok(ShardedDatabase { manifest, shards })
+    }
+
+    pub fn manifest(&self) -> &ShardManifest {
+        &self.manifest
+    }
+
+    pub fn shards(&self) -> &[Database<N>] {
+        &self.shards
+    }
+
+    pub fn get(&self, key: &[u8]) -> Option<&[u8]> {
+        self.shards[self.manifest.shard_of(key)].get(key)
+    }
+
+    // Batched lookup: groups the keys by shard and runs `Database::get_many_into`
+    // on each shard, returning the results in the order of `keys`
+    pub fn get_many<K: AsRef<[u8]>>(&self, keys: &[K]) -> Vec<Option<&[u8]>> {
+        let mut positions: Vec<Vec<usize>> = vec![Vec::new(); self.shards.len()];
+        for (position, key) in keys.iter().enumerate() {
+            positions[self.manifest.shard_of(key.as_ref())].push(position);
+        }
+
+        let mut out = vec![None; keys.len()];
+        let mut batch = Vec::new();
+        let mut results = Vec::new();
+        for (shard, positions) in self.shards.iter().zip(&positions) {
+            batch.clear();
+            batch.extend(positions.iter().map(|&position| keys[position].as_ref()));
+            results.clear();
+            shard.get_many_into(&batch, &mut results);
+            for (&position, &result) in positions.iter().zip(&results) {
+                out[position] = result;
+            }
+        }
+        out
+    }
+
+    // Number of keys over all shards
+    pub fn len(&self) -> usize {
+        self.shards.iter().map(|shard| shard.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // Runs `Database::verify` on every shard, reporting corruptions with their shard
+    pub fn verify(&self) -> Vec<(usize, Corruption)> {
+        self.shards
+            .iter()
+            .enumerate()
+            .flat_map(|(shard, db)| db.verify().into_iter().map(move |corruption| (shard, corruption)))
+            .collect()
+    }
+}
+
+// Builds some or all shards of a sharded database. Keys are routed with the
+// manifest, so shards can be built by separate builders on separate machines
+// from the same input, each keeping only the keys of its own shards, as long
+// as all of them end up in the same directory.
+pub struct ShardedBuilder<const N: usize = KEY_SIZE> {
+    dir: PathBuf,
+    manifest: ShardManifest,
+    shards: Range<usize>,
+    builders: Vec<DatabaseBuilder<N>>,
+}
+
+impl<const N: usize> ShardedBuilder<N> {
+    // Builds every shard into `dir`, spilling values there
+    pub fn new<P: AsRef<Path>>(dir: P, manifest: ShardManifest, options: WriteOptions) -> Result<Self> {
+        let num_shards = manifest.num_shards();
+        Self::for_shards(dir, manifest, 0..num_shards, options)
+    }
+
+    // Builds only the shards in `shards`; keys of other shards are skipped by `insert`
+    pub fn for_shards<P: AsRef<Path>>(
+        dir: P,
+        manifest: ShardManifest,
+        shards: Range<usize>,
+        options: WriteOptions,
+    ) -> Result<Self> {
+        if shards.start > shards.end || shards.end > manifest.num_shards() {
+            return Err(Error::InvalidArgument(format!(
+                "Shard range {:?} out of bounds for {} shards",
+                shards,
+                manifest.num_shards()
+            )));
+        }
+
+        let dir = dir.as_ref().to_path_buf();
+        let builders = shards
+            .clone()
+            .map(|_| DatabaseBuilder::new(&dir, options.clone()))
+            .collect::<Result<_>>()?;
+        Ok(ShardedBuilder { dir, manifest, shards, builders })
+    }
+
+    // Adds a key and its value to its shard. Returns false, without storing
+    // anything, if the key belongs to a shard this builder does not build.
+    pub fn insert(&mut self, key: &[u8], value: &[u8]) -> Result<bool> {
+        let shard = self.manifest.shard_of(key);
+        if !self.shards.contains(&shard) {
+            return Ok(false);
+        }
+        self.builders[shard - self.shards.start].insert(key, value)?;
+        Ok(true)
+    }
+
+    // Writes the shards built here and the manifest, and returns the reports
+    // of the shards added up
+    pub fn finish(self, version: u32) -> Result<BuildReport> {
+        let mut report = BuildReport::default();
+        for (shard, builder) in self.shards.zip(self.builders) {
+            let paths = self.manifest.shard_paths(&self.dir, shard);
+            report += match self.manifest.layout {
+                ShardLayout::TwoFiles => builder.finish(&paths[0], &paths[1], version)?,
+                ShardLayout::SingleFile => builder.finish_single(&paths[0], version)?,
+            };
+        }
+        self.manifest.write(&self.dir, N)?;
+        Ok(report)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::VAR_KEY_SIZE;
+    use std::io;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_sharded_round_trip() -> io::Result<()> {
+        let dir = TempDir::new()?;
+        let manifest = ShardManifest::new(4, ShardLayout::TwoFiles)?;
+
+        let keys: Vec<[u8; KEY_SIZE]> = (0..4000u128).map(|i| i.to_le_bytes()).collect();
+        let mut builder = ShardedBuilder::<KEY_SIZE>::new(dir.path(), manifest, WriteOptions::default())?;
+        for key in &keys {
+            assert!(builder.insert(key, &key[..4])?);
+        }
+        builder.finish(1)?;
+
+        let db = ShardedDatabase::<KEY_SIZE>::open(dir.path())?;
+        assert_eq!(db.len(), 4000);
+        assert_eq!(db.manifest(), &manifest);
+        for shard in db.shards() {
+            // Keys are spread evenly over the shards
+            assert!((800..1200).contains(&shard.len()), "{} keys in a shard", shard.len());
+        }
+        for key in &keys {
+            assert_eq!(db.get(key), Some(&key[..4]));
+        }
+
+        let mut queries: Vec<[u8; KEY_SIZE]> = keys[..100].to_vec();
+        queries.push([0xff; KEY_SIZE]);
+        let results = db.get_many(&queries);
+        for (key, result) in queries.iter().zip(&results) {
+            assert_eq!(*result, db.get(key));
+        }
+        assert!(db.verify().is_empty());
+
+        // Opening with another key width is rejected
+        assert!(ShardedDatabase::<VAR_KEY_SIZE>::open(dir.path()).is_err());
+
+        // Shards without any key still answer lookups
+        let sparse_dir = TempDir::new()?;
+        let manifest = ShardManifest::new(16, ShardLayout::TwoFiles)?;
+        let mut builder = ShardedBuilder::<KEY_SIZE>::new(sparse_dir.path(), manifest, WriteOptions::default())?;
+        builder.insert(&keys[0], b"only")?;
+        builder.finish(1)?;
+        let db = ShardedDatabase::<KEY_SIZE>::open(sparse_dir.path())?;
+        assert_eq!(db.get(&keys[0]), Some(&b"only"[..]));
+        assert_eq!(db.get_many(&keys[..50]).iter().flatten().count(), 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_shards_built_independently() -> io::Result<()> {
+        let dir = TempDir::new()?;
+        let manifest = ShardManifest::new(5, ShardLayout::SingleFile)?;
+        let keys: Vec<Vec<u8>> = (0..2000u32).map(|i| format!("user:{}", i).into_bytes()).collect();
+
+        // Two builders see the same input and each build some of the shards
+        let mut stored = 0;
+        for shards in [0..2, 2..5] {
+            let mut builder =
+                ShardedBuilder::<VAR_KEY_SIZE>::for_shards(dir.path(), manifest, shards, WriteOptions::default())?;
+            for key in &keys {
+                stored += builder.insert(key, key)? as usize;
+            }
+            builder.finish(1)?;
+        }
+        assert_eq!(stored, keys.len());
+
+        let db = ShardedDatabase::<VAR_KEY_SIZE>::open(dir.path())?;
+        for key in &keys {
+            assert_eq!(db.get(key), Some(key.as_slice()));
+            assert_eq!(db.shards()[manifest.shard_of(key)].get(key), Some(key.as_slice()));
+        }
+
+        // A corrupt manifest is rejected
+        let manifest_path = dir.path().join(MANIFEST_FILE);
+        let mut bytes = fs::read(&manifest_path)?;
+        bytes[8] ^= 0x01;
+        fs::write(&manifest_path, &bytes)?;
+        let err = ShardedDatabase::<VAR_KEY_SIZE>::open(dir.path()).err().expect("Open should fail");
+        assert!(matches!(err, Error::ChecksumMismatch { section: "KVSM manifest" }));
+
+        assert!(ShardManifest::new(0, ShardLayout::TwoFiles).is_err());
+
+        Ok(())
+    }
+}
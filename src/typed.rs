@@ -0,0 +1,242 @@
+use crate::builder::{BuildReport, DatabaseBuilder};
+use crate::database::{Database, WriteOptions, KEY_SIZE, VAR_KEY_SIZE};
+use crate::error::{Error, Result};
+use crate::header::VALUE_CODEC_BINCODE;
+use serde::{de::DeserializeOwned, Serialize};
+use std::marker::PhantomData;
+use std::path::Path;
+use xxhash_rust::xxh3::xxh3_64;
+
+// Hash of a type name given to `TypedBuilder::with_type_name`, recorded in the
+// data header; 0 stands for no name
+fn type_tag(name: &str) -> u64 {
+    xxh3_64(name.as_bytes())
+}
+
+// A database of values of type `V`, encoded with bincode. The codec is
+// recorded in the data header when the database is built with a
+// `TypedBuilder`, and opening a database of raw or otherwise encoded values is
+// rejected. The value type is only checked where it was named on both sides:
+// see `TypedBuilder::with_type_name` and `TypedDatabase::with_type_name`.
+pub struct TypedDatabase<V, const N: usize = KEY_SIZE> {
+    db: Database<N>,
+    _value: PhantomData<fn() -> V>,
+}
+
+pub type VarTypedDatabase<V> = TypedDatabase<V, VAR_KEY_SIZE>;
+
+impl<V: Serialize + DeserializeOwned, const N: usize> TypedDatabase<V, N> {
+    pub fn open<P: AsRef<Path>>(data_file: P, index_file: P) -> Result<Self> {
+        Self::from_database(Database::open(data_file, index_file)?)
+    }
+
+    pub fn open_single<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Self::from_database(Database::open_single(path)?)
+    }
+
+    // Wraps an open database, checking that it holds bincode-encoded values
+    pub fn from_database(db: Database<N>) -> Result<Self> {
+        if db.value_codec().0 != VALUE_CODEC_BINCODE {
+            return Err(Error::ValueTypeMismatch { requested: std::any::type_name::<V>() });
+        }
+        Ok(TypedDatabase { db, _value: PhantomData })
+    }
+
+    // Checks that the database was built under the type name `name`, e.g.
+    // `TypedDatabase::open_single(path)?.with_type_name("Profile")?`; a
+    // database built without a name is rejected too
+    pub fn with_type_name(self, name: &'static str) -> Result<Self> {
+        if self.db.value_codec().1 != type_tag(name) {
+            return Err(Error::ValueTypeMismatch { requested: name });
+        }
+        Ok(self)
+    }
+
+    // Starts a `TypedBuilder` for this key width
+    pub fn builder<P: AsRef<Path>>(spill_dir: P, options: WriteOptions) -> Result<TypedBuilder<V, N>> {
+        TypedBuilder::new(spill_dir, options)
+    }
+
+    pub fn get(&self, key: &[u8]) -> Result<Option<V>> {
+        self.db.get(key).map(decode).transpose()
+    }
+
+    // Batched `get`, see `Database::get_many`
+    pub fn get_many<K: AsRef<[u8]>>(&self, keys: &[K]) -> Result<Vec<Option<V>>> {
+        self.db.get_many(keys).into_iter().map(|value| value.map(decode).transpose()).collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.db.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.db.is_empty()
+    }
+
+    // The underlying database, with the encoded values
+    pub fn database(&self) -> &Database<N> {
+        &self.db
+    }
+}
+
+fn decode<V: DeserializeOwned>(bytes: &[u8]) -> Result<V> {
+    bincode::deserialize(bytes).map_err(|e| Error::Codec(e.to_string()))
+}
+
+// Builds a `TypedDatabase`, encoding each value with bincode as it is inserted
+pub struct TypedBuilder<V, const N: usize = KEY_SIZE> {
+    inner: DatabaseBuilder<N>,
+    encoded: Vec<u8>,
+    _value: PhantomData<fn(&V)>,
+}
+
+impl<V: Serialize + DeserializeOwned, const N: usize> TypedBuilder<V, N> {
+    // Creates a builder spilling values to a temporary file in `spill_dir`
+    pub fn new<P: AsRef<Path>>(spill_dir: P, options: WriteOptions) -> Result<Self> {
+        let inner = DatabaseBuilder::new(spill_dir, options)?.value_codec(VALUE_CODEC_BINCODE, 0);
+        Ok(TypedBuilder { inner, encoded: Vec::new(), _value: PhantomData })
+    }
+
+    // Records `name` as the value type, checked by `TypedDatabase::with_type_name`.
+    // The name stands for the encoded form of the values rather than the Rust
+    // type, so it must stay the same for as long as the databases are read.
+    pub fn with_type_name(mut self, name: &str) -> Self {
+        self.inner = self.inner.value_codec(VALUE_CODEC_BINCODE, type_tag(name));
+        self
+    }
+
+    // See `DatabaseBuilder::with_buffer_size`
+    pub fn with_buffer_size(mut self, buffer_size: usize) -> Self {
+        self.inner = self.inner.with_buffer_size(buffer_size);
+        self
+    }
+
+    pub fn insert(&mut self, key: &[u8], value: &V) -> Result<()> {
+        self.encoded.clear();
+        bincode::serialize_into(&mut self.encoded, value).map_err(|e| Error::Codec(e.to_string()))?;
+        self.inner.insert(key, &self.encoded)
+    }
+
+    // Number of keys inserted so far
+    pub fn len(&self) -> usize {
+        self.inner.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.inner.is_empty()
+    }
+
+    // Writes a two-file database, opened with `TypedDatabase::open`
+    pub fn finish<P: AsRef<Path>>(self, path_data: P, path_index: P, version: u32) -> Result<BuildReport> {
+        self.inner.finish(path_data, path_index, version)
+    }
+
+    // Writes a single-file database, opened with `TypedDatabase::open_single`
+    pub fn finish_single<P: AsRef<Path>>(self, path: P, version: u32) -> Result<BuildReport> {
+        self.inner.finish_single(path, version)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::{DuplicatePolicy, VarDatabase};
+    use crate::header::VALUE_CODEC_RAW;
+    use serde::Deserialize;
+    use std::sync::Arc;
+    use tempfile::TempDir;
+
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    struct Profile {
+        name: String,
+        age: u32,
+        tags: Vec<String>,
+    }
+
+    // `Profile` under another Rust name
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Person {
+        name: String,
+        age: u32,
+        tags: Vec<String>,
+    }
+
+    #[test]
+    fn test_typed_values_round_trip() -> Result<()> {
+        let dir = TempDir::new()?;
+        let path = dir.path().join("profiles.kvdb");
+        let profiles: Vec<Profile> = (0..1000u32)
+            .map(|i| Profile { name: format!("user{}", i), age: i % 90, tags: vec!["a".repeat(i as usize % 5)] })
+            .collect();
+
+        let mut builder =
+            VarTypedDatabase::<Profile>::builder(dir.path(), WriteOptions::default())?.with_type_name("Profile");
+        for profile in &profiles {
+            builder.insert(profile.name.as_bytes(), profile)?;
+        }
+        builder.finish_single(&path, 1)?;
+
+        let db = VarTypedDatabase::<Profile>::open_single(&path)?.with_type_name("Profile")?;
+        assert_eq!(db.len(), 1000);
+        assert_eq!(db.get(b"user42")?, Some(profiles[42].clone()));
+        assert_eq!(db.get(b"nobody")?, None);
+        let keys = [&b"user7"[..], b"missing", b"user999"];
+        assert_eq!(db.get_many(&keys)?, vec![Some(profiles[7].clone()), None, Some(profiles[999].clone())]);
+
+        // The stored tag is the given name, not the Rust name of the type
+        let person = VarTypedDatabase::<Person>::open_single(&path)?.with_type_name("Profile")?.get(b"user3")?;
+        assert_eq!(person, Some(Person { name: "user3".to_string(), age: 3, tags: vec!["aaa".to_string()] }));
+
+        // Merging typed databases keeps the value type
+        let merged_path = dir.path().join("merged.kvdb");
+        let options = WriteOptions { duplicates: DuplicatePolicy::KeepFirst, ..Default::default() };
+        VarDatabase::merge_single(&[db.database(), db.database()], &merged_path, 2, &options)?;
+        let merged = VarTypedDatabase::<Profile>::open_single(&merged_path)?.with_type_name("Profile")?;
+        assert_eq!(merged.get(b"user5")?, Some(profiles[5].clone()));
+
+        // Unless a closure folds the values, which are then just bytes
+        let concat = |_: &[u8], a: &[u8], b: &[u8]| [a, b].concat();
+        let options = WriteOptions { duplicates: DuplicatePolicy::Merge(Arc::new(concat)), ..Default::default() };
+        VarDatabase::merge_single(&[db.database(), db.database()], &merged_path, 3, &options)?;
+        let err = VarTypedDatabase::<Profile>::open_single(&merged_path).err().expect("Open should fail");
+        assert!(matches!(err, Error::ValueTypeMismatch { .. }));
+        assert_eq!(VarDatabase::open_single(&merged_path)?.value_codec(), (VALUE_CODEC_RAW, 0));
+        Ok(())
+    }
+
+    #[test]
+    fn test_wrong_type_or_codec_is_rejected() -> Result<()> {
+        let dir = TempDir::new()?;
+        let typed_path = dir.path().join("counters.kvdb");
+        let mut builder = VarTypedDatabase::<u64>::builder(dir.path(), WriteOptions::default())?.with_type_name("u64");
+        builder.insert(b"hits", &7)?;
+        builder.finish_single(&typed_path, 1)?;
+
+        let err = VarTypedDatabase::<i64>::open_single(&typed_path)?.with_type_name("i64").err().expect("Should fail");
+        assert!(matches!(err, Error::ValueTypeMismatch { requested: "i64" }));
+        assert_eq!(VarTypedDatabase::<u64>::open_single(&typed_path)?.with_type_name("u64")?.get(b"hits")?, Some(7));
+        // The type is not checked unless it is named when opening
+        assert_eq!(VarTypedDatabase::<i64>::open_single(&typed_path)?.get(b"hits")?, Some(7));
+        // The encoded values are still readable as bytes
+        assert_eq!(VarDatabase::open_single(&typed_path)?.get(b"hits"), Some(&7u64.to_le_bytes()[..]));
+
+        // A database of raw values has no codec
+        let raw_path = dir.path().join("raw.kvdb");
+        let options = WriteOptions::default();
+        VarDatabase::write_database_single(&raw_path, [b"hits"].iter(), [[7u8; 8]].iter(), 1, &options)?;
+        let err = VarTypedDatabase::<u64>::open_single(&raw_path).err().expect("Open should fail");
+        assert!(matches!(err, Error::ValueTypeMismatch { .. }));
+
+        // A database built without a type name does not pass a check for one
+        let untagged_path = dir.path().join("untagged.kvdb");
+        let mut builder = VarTypedDatabase::<u64>::builder(dir.path(), WriteOptions::default())?;
+        builder.insert(b"hits", &7)?;
+        builder.finish_single(&untagged_path, 1)?;
+        let untagged = VarTypedDatabase::<u64>::open_single(&untagged_path)?;
+        assert_eq!(untagged.get(b"hits")?, Some(7));
+        let err = untagged.with_type_name("u64").err().expect("Should fail");
+        assert!(matches!(err, Error::ValueTypeMismatch { requested: "u64" }));
+        Ok(())
+    }
+}
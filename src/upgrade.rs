@@ -0,0 +1,169 @@
+use crate::builder::DatabaseBuilder;
+use crate::database::KEY_SIZE;
+use crate::error::{Error, Result};
+use memmap2::Mmap;
+use std::fs::File;
+use std::path::Path;
+
+/* Baseline layout, written by the first release (all integers little-endian):
+
+Data file:                           Index file:
++------------------------------+     +------------------------------+
+| magic "DABA", version (u32)  |     | magic "KIDX", version 1      |
+| num_keys, key_size (u64)     |     | num_keys, mphf_size (u64)    |
+| values_start (u64)           |     | keys_offset (u64)            |
+| values in MPHF slot order    |     | offsets_offset (u64)         |
++------------------------------+     | MPHF                         |
+                                     | 16-byte keys in slot order   |
+                                     | u64 value offsets            |
+                                     +------------------------------+
+
+Current builds cannot open it in place: it has no checksums and its MPHF is
+not aligned for ε-copy deserialization. `upgrade_into` rebuilds it instead. */
+
+// Index version of the baseline layout
+pub const BASELINE_INDEX_VERSION: u32 = 1;
+
+const BASELINE_DATA_HEADER_SIZE: usize = 32;
+const BASELINE_INDEX_HEADER_SIZE: usize = 40;
+
+fn u32_at(bytes: &[u8], at: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(bytes.get(at..at + 4)?.try_into().ok()?))
+}
+
+fn u64_at(bytes: &[u8], at: usize) -> Option<u64> {
+    Some(u64::from_le_bytes(bytes.get(at..at + 8)?.try_into().ok()?))
+}
+
+// Whether `index` starts with the header of a baseline index file
+pub(crate) fn is_baseline_index(index: &[u8]) -> bool {
+    index.starts_with(b"KIDX") && u32_at(index, 4) == Some(BASELINE_INDEX_VERSION)
+}
+
+// Inserts every entry of a database in the baseline layout into `builder`,
+// which then writes it in the current layout. Returns the version number
+// recorded in the baseline data header, to be passed on to `finish`.
+pub fn upgrade_into<P: AsRef<Path>>(
+    data_file: P,
+    index_file: P,
+    builder: &mut DatabaseBuilder<KEY_SIZE>,
+) -> Result<u32> {
+    let data = unsafe { Mmap::map(&File::open(data_file)?)? };
+    let index = unsafe { Mmap::map(&File::open(index_file)?)? };
+
+    if data.len() < BASELINE_DATA_HEADER_SIZE {
+        return Err(Error::Truncated { section: "baseline DABA header" });
+    }
+    if index.len() < BASELINE_INDEX_HEADER_SIZE {
+        return Err(Error::Truncated { section: "baseline index header" });
+    }
+    if !data.starts_with(b"DABA") {
+        return Err(Error::BadMagic { section: "baseline DABA header" });
+    }
+    if !is_baseline_index(&index) {
+        return Err(Error::InvalidHeader("index file is not in the baseline layout".to_string()));
+    }
+
+    // Both headers were checked to be long enough above
+    let field = |bytes: &[u8], at| u64_at(bytes, at).unwrap_or_default();
+    let version = u32_at(&data, 4).unwrap_or_default();
+    let (num_keys, key_size, values_start) = (field(&data, 8), field(&data, 16), field(&data, 24));
+    let (keys_offset, offsets_offset) = (field(&index, 24), field(&index, 32));
+    if field(&index, 8) != num_keys {
+        return Err(Error::KeyCountMismatch { data: num_keys, index: field(&index, 8) });
+    }
+    if key_size != KEY_SIZE as u64 {
+        return Err(Error::KeySizeMismatch { stored: key_size, requested: KEY_SIZE });
+    }
+
+    let section = |start: u64, len: u64, section: &'static str| -> Result<&[u8]> {
+        start
+            .checked_add(len)
+            .filter(|&end| end <= index.len() as u64)
+            .map(|end| &index[start as usize..end as usize])
+            .ok_or(Error::Truncated { section })
+    };
+    let keys = section(keys_offset, num_keys.saturating_mul(KEY_SIZE as u64), "baseline keys section")?;
+    let offsets = section(offsets_offset, num_keys.saturating_mul(8), "baseline offsets section")?;
+    let values = data.get(values_start as usize..).ok_or(Error::Truncated { section: "baseline values" })?;
+
+    let offsets: Vec<u64> = offsets.as_chunks::<8>().0.iter().map(|bytes| u64::from_le_bytes(*bytes)).collect();
+    for (slot, key) in keys.as_chunks::<KEY_SIZE>().0.iter().enumerate() {
+        let start = offsets[slot];
+        let end = offsets.get(slot + 1).copied().unwrap_or(values.len() as u64);
+        let value = values
+            .get(start as usize..end as usize)
+            .filter(|_| start <= end)
+            .ok_or_else(|| Error::InvalidHeader(format!("baseline value offsets of slot {} out of bounds", slot)))?;
+        builder.insert(key, value)?;
+    }
+    Ok(version)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::{Database, WriteOptions};
+    use std::fs;
+    use tempfile::TempDir;
+
+    // Writes `entries` in the baseline layout, with a placeholder MPHF that the upgrade never reads
+    fn write_baseline(data_path: &Path, index_path: &Path, entries: &[([u8; KEY_SIZE], Vec<u8>)]) -> Result<()> {
+        let num_keys = entries.len() as u64;
+        let mut data = b"DABA\x07\0\0\0".to_vec();
+        for field in [num_keys, KEY_SIZE as u64, BASELINE_DATA_HEADER_SIZE as u64] {
+            data.extend_from_slice(&field.to_le_bytes());
+        }
+
+        let mphf = vec![0xa5u8; 200];
+        let keys_offset = (BASELINE_INDEX_HEADER_SIZE + mphf.len()) as u64;
+        let offsets_offset = keys_offset + num_keys * KEY_SIZE as u64;
+        let mut index = b"KIDX\x01\0\0\0".to_vec();
+        for field in [num_keys, mphf.len() as u64, keys_offset, offsets_offset] {
+            index.extend_from_slice(&field.to_le_bytes());
+        }
+        index.extend_from_slice(&mphf);
+        let mut offsets = Vec::new();
+        for (key, value) in entries {
+            index.extend_from_slice(key);
+            offsets.extend_from_slice(&((data.len() - BASELINE_DATA_HEADER_SIZE) as u64).to_le_bytes());
+            data.extend_from_slice(value);
+        }
+        index.extend_from_slice(&offsets);
+        fs::write(data_path, data)?;
+        fs::write(index_path, index)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_upgrade_baseline_database() -> Result<()> {
+        let dir = TempDir::new()?;
+        let (data, index) = (dir.path().join("old.data"), dir.path().join("old.index"));
+        let entries: Vec<([u8; KEY_SIZE], Vec<u8>)> =
+            (0..500u128).map(|i| (i.to_le_bytes(), format!("value-{}", i).into_bytes())).collect();
+        write_baseline(&data, &index, &entries)?;
+
+        // Opening it points at the upgrade
+        let err = Database::<KEY_SIZE>::open(&data, &index).err().expect("Open should fail");
+        assert!(matches!(err, Error::Unsupported(_)), "{}", err);
+
+        let mut builder = Database::<KEY_SIZE>::builder(dir.path(), WriteOptions::default())?;
+        let version = upgrade_into(&data, &index, &mut builder)?;
+        assert_eq!(version, 7);
+        let path = dir.path().join("new.kvdb");
+        builder.finish_single(&path, version)?;
+
+        let db = Database::<KEY_SIZE>::open_single(&path)?;
+        assert_eq!(db.len(), 500);
+        for (key, value) in &entries {
+            assert_eq!(db.get(key), Some(&value[..]));
+        }
+
+        // A current database is not mistaken for a baseline one
+        let mut builder = Database::<KEY_SIZE>::builder(dir.path(), WriteOptions::default())?;
+        let (new_data, new_index) = (dir.path().join("new.data"), dir.path().join("new.index"));
+        Database::<KEY_SIZE>::write_database(&new_data, &new_index, [[1u8; KEY_SIZE]].iter(), [b"v"].iter(), 1)?;
+        assert!(matches!(upgrade_into(&new_data, &new_index, &mut builder), Err(Error::InvalidHeader(_))));
+        Ok(())
+    }
+}
@@ -0,0 +1,226 @@
+use crate::database::{Database, Sections};
+use crate::header::IndexHeader;
+use std::{fmt, ops::Range};
+use xxhash_rust::xxh3::xxh3_64;
+
+// A corrupt part of a database, as reported by `Database::verify`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Corruption {
+    // The contents of a section do not match its checksum
+    Section { section: &'static str },
+    // A block of values does not match its checksum; `slots` are the slots
+    // whose values overlap the block (with shared values, the smallest range
+    // holding them, which may include others)
+    ValueBlock { block: usize, slots: Range<usize> },
+    // The index entries of one slot are inconsistent
+    Slot { slot: usize, problem: &'static str },
+}
+
+impl fmt::Display for Corruption {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Corruption::Section { section } => write!(f, "Checksum mismatch in {} section", section),
+            Corruption::ValueBlock { block, slots } => {
+                write!(f, "Checksum mismatch in value block {} (slots {:?})", block, slots)
+            }
+            Corruption::Slot { slot, problem } => write!(f, "Slot {}: {}", slot, problem),
+        }
+    }
+}
+
+// Compares every section against the checksums in `index_header`. Only reads
+// raw bytes, so it is safe to run before the MPHF is deserialized.
+pub(crate) fn section_corruptions(
+    index_header: &IndexHeader,
+    data: &[u8],
+    index: &[u8],
+    sections: &Sections,
+) -> Vec<Corruption> {
+    let checks = [
+        ("MPHF", &index[sections.mphf.clone()], index_header.mphf_checksum),
+        ("keys", &index[sections.keys.clone()], index_header.keys_checksum),
+        ("key heap", &index[sections.key_heap.clone()], index_header.key_heap_checksum),
+        ("offsets", &index[sections.offsets.clone()], index_header.offsets_checksum),
+        ("value checksums", &index[sections.value_checksums.clone()], index_header.value_checksums_checksum),
+        ("sorted keys", &index[sections.sorted_keys.clone()], index_header.sorted_keys_checksum),
+        ("values", &data[sections.values.clone()], index_header.values_checksum),
+    ];
+    checks
+        .into_iter()
+        .filter(|&(_, bytes, checksum)| xxh3_64(bytes) != checksum)
+        .map(|(section, ..)| Corruption::Section { section })
+        .collect()
+}
+
+impl<const N: usize> Database<N> {
+    // Scans the whole database and reports every corruption found: sections
+    // whose checksum does not match, value blocks whose checksum does not match
+    // (when the database was written with `WriteOptions::value_block_size`), and
+    // slots whose value offsets are out of order or whose stored key does not
+    // hash to the slot. An empty result means the database is intact.
+    pub fn verify(&self) -> Vec<Corruption> {
+        let mut corruptions = section_corruptions(&self.index_header, &self.mmap_data, &self.mmap_index, &self.sections);
+        corruptions.extend(self.value_block_corruptions());
+        corruptions.extend(self.slot_corruptions());
+        corruptions
+    }
+
+    fn value_block_corruptions(&self) -> Vec<Corruption> {
+        let block_size = self.index_header.value_block_size as usize;
+        if block_size == 0 {
+            return Vec::new();
+        }
+
+        let values = &self.mmap_data[self.sections.values.clone()];
+        let checksums = self.mmap_index[self.sections.value_checksums.clone()].as_chunks::<8>().0;
+        let offsets = self.all_offsets();
+        values
+            .chunks(block_size)
+            .zip(checksums)
+            .enumerate()
+            .filter(|&(_, (block, checksum))| xxh3_64(block).to_le_bytes() != *checksum)
+            .map(|(block, _)| {
+                // Slots whose values start before the block ends and end after it starts
+                let start = (block * block_size) as u64;
+                let end = start + block_size as u64;
+                let (first, last) = match self.value_width() {
+                    Some(width) if width > 0 => {
+                        (start as usize / width, (end as usize).div_ceil(width).min(self.len()))
+                    }
+                    // Shared values are in no slot order: span every slot pointing into the block
+                    _ if self.has_shared_values() => {
+                        let mut slots = (0..self.len()).filter(|&slot| {
+                            self.value_range(slot)
+                                .is_some_and(|range| (range.start as u64) < end && range.end as u64 > start)
+                        });
+                        let first = slots.next();
+                        first.map_or((0, 0), |first| (first, slots.next_back().unwrap_or(first) + 1))
+                    }
+                    _ => (
+                        offsets.partition_point(|&offset| offset <= start).saturating_sub(1),
+                        offsets.partition_point(|&offset| offset < end),
+                    ),
+                };
+                Corruption::ValueBlock { block, slots: first..last }
+            })
+            .collect()
+    }
+
+    fn slot_corruptions(&self) -> Vec<Corruption> {
+        let mut corruptions = Vec::new();
+        let offsets = self.all_offsets();
+        let values_len = self.sections.values.len() as u64;
+
+        // Static sets have no offsets
+        for slot in 0..self.len() {
+            if self.has_shared_values() {
+                if self.value_range(slot).is_none_or(|range| range.end as u64 > values_len) {
+                    corruptions.push(Corruption::Slot { slot, problem: "value out of bounds" });
+                }
+            } else if let Some(&offset) = offsets.get(slot) {
+                let end = offsets.get(slot + 1).copied().unwrap_or(values_len);
+                if offset > end || end > values_len {
+                    corruptions.push(Corruption::Slot { slot, problem: "value offsets out of order or out of bounds" });
+                }
+            }
+
+            if self.has_keys() {
+                match self.stored_key(slot) {
+                    None => corruptions.push(Corruption::Slot { slot, problem: "stored key out of bounds" }),
+                    Some(key) if self.slot(key) != Some(slot) => {
+                        corruptions.push(Corruption::Slot { slot, problem: "stored key does not hash to its slot" })
+                    }
+                    Some(_) => {}
+                }
+            }
+        }
+        corruptions
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Corruption;
+    use crate::database::{Database, OpenOptions, VarDatabase, WriteOptions, KEY_SIZE};
+    use crate::error::Error;
+    use std::io;
+    use tempfile::NamedTempFile;
+
+    #[test]
+    fn test_verify_reports_corrupt_sections_and_slots() -> io::Result<()> {
+        let data_file = NamedTempFile::new()?;
+        let index_file = NamedTempFile::new()?;
+
+        let keys: Vec<Vec<u8>> = (0..200u32).map(|i| format!("key-{}", i).into_bytes()).collect();
+        let values: Vec<Vec<u8>> = (0..200u32).map(|i| format!("value-{:04}", i).into_bytes()).collect();
+        let options = WriteOptions { value_block_size: Some(256), ..Default::default() };
+        VarDatabase::write_database_with_options(
+            data_file.path(),
+            index_file.path(),
+            keys.iter(),
+            values.iter(),
+            1,
+            &options,
+        )?;
+        assert!(VarDatabase::open(data_file.path(), index_file.path())?.verify().is_empty());
+
+        // Flip one byte of one value: the values section and the block holding it are reported
+        let mut data = std::fs::read(data_file.path())?;
+        let corrupt_at = data.len() - 300;
+        data[corrupt_at] ^= 0xff;
+        std::fs::write(data_file.path(), &data)?;
+
+        let db = VarDatabase::open(data_file.path(), index_file.path())?;
+        let corruptions = db.verify();
+        assert_eq!(corruptions[0], Corruption::Section { section: "values" });
+        let Corruption::ValueBlock { slots, .. } = &corruptions[1] else {
+            panic!("Expected a value block corruption, got {:?}", corruptions);
+        };
+        let corrupt_slots: Vec<usize> = slots
+            .clone()
+            .filter(|&slot| db.value_at(slot).is_some_and(|value| !values.iter().any(|v| v == value)))
+            .collect();
+        assert_eq!(corrupt_slots.len(), 1);
+        assert_eq!(corruptions.len(), 2);
+
+        let err = VarDatabase::open_with_options(
+            data_file.path(),
+            index_file.path(),
+            &OpenOptions { verify_on_open: true },
+        )
+        .err()
+        .expect("Open should fail");
+        assert!(matches!(err, Error::Corrupt(Corruption::Section { section: "values" })));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_corrupt_keys_and_header_are_detected() -> io::Result<()> {
+        let data_file = NamedTempFile::new()?;
+        let index_file = NamedTempFile::new()?;
+
+        let keys: Vec<[u8; KEY_SIZE]> = (0..100u128).map(|i| i.to_le_bytes()).collect();
+        Database::<KEY_SIZE>::write_database(data_file.path(), index_file.path(), keys.iter(), keys.iter(), 1)?;
+        let db = Database::<KEY_SIZE>::open(data_file.path(), index_file.path())?;
+        let keys_offset = db.sections.keys.start;
+        drop(db);
+
+        // A corrupt stored key no longer hashes to its slot
+        let mut index = std::fs::read(index_file.path())?;
+        index[keys_offset + KEY_SIZE * 3] ^= 0x01;
+        std::fs::write(index_file.path(), &index)?;
+        let corruptions = Database::<KEY_SIZE>::open(data_file.path(), index_file.path())?.verify();
+        assert!(corruptions.contains(&Corruption::Section { section: "keys" }));
+        assert!(corruptions.iter().any(|c| matches!(c, Corruption::Slot { slot: 3, .. })));
+
+        // Any change to either header fails the header checksum on open
+        let mut data = std::fs::read(data_file.path())?;
+        data[4] ^= 0x01;
+        std::fs::write(data_file.path(), &data)?;
+        let err = Database::<KEY_SIZE>::open(data_file.path(), index_file.path()).err().expect("Open should fail");
+        assert!(matches!(err, Error::ChecksumMismatch { section: "headers" }));
+
+        Ok(())
+    }
+}
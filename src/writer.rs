@@ -0,0 +1,476 @@
+use crate::builder::{BuildReport, DatabaseBuilder, TempFile};
+use crate::error::{Error, Result};
+use crate::database::{align_up, Database, Key, KeyCheck, KeyPtrHash, WriteOptions, INDEX_VERSION, MPHF_ALIGN, SECTION_ALIGN};
+use crate::header::{
+    DabaHeader, FileHeader, IndexHeader, SectionEntry, INDEX_FLAG_EF_OFFSETS, SECTION_KEYS, SECTION_KEY_HEAP,
+    SECTION_META, SECTION_MPHF, SECTION_OFFSETS, SECTION_SORTED_KEYS, SECTION_VALUES, SECTION_VALUE_CHECKSUMS,
+    SINGLE_FILE_VERSION,
+};
+use cacheline_ef::CachelineEfVec;
+use epserde::prelude::*;
+use ptr_hash::PtrHashParams;
+use rayon::prelude::*;
+use std::path::{Path, PathBuf};
+use std::{
+    fs::{self, File},
+    io::{self, BufWriter, Read, Seek, SeekFrom, Write},
+};
+use xxhash_rust::xxh3::{xxh3_64, Xxh3};
+
+// Alignment of every section in a single-file database; enough for the MPHF
+// and for viewing keys and offsets in place
+const SINGLE_FILE_ALIGN: u64 = MPHF_ALIGN;
+
+// Index sections of a database in MPHF slot order, ready to be laid out either
+// in a `KIDX` index file or in a single-file database
+pub(crate) struct IndexSections {
+    pub(crate) num_keys: u64,
+    pub(crate) flags: u64,
+    pub(crate) key_check: KeyCheck,
+    pub(crate) mphf: Vec<u8>,
+    pub(crate) keys: Vec<u8>,
+    pub(crate) key_heap: Vec<u8>,
+    pub(crate) offsets: Vec<u8>,
+    pub(crate) value_block_size: u64,
+    pub(crate) value_checksums: Vec<u8>,
+    pub(crate) values_checksum: u64,
+    pub(crate) sorted_keys: Vec<u8>,
+}
+
+impl IndexSections {
+    // Index header describing these sections laid out one after the other from
+    // the start of an index file, with `values_size` bytes of values. Its
+    // `header_checksum` is left to the caller, see `IndexHeader::compute_checksum`.
+    fn index_header(&self, values_size: u64) -> IndexHeader {
+        let mphf_offset = align_up(IndexHeader::SIZE as u64, MPHF_ALIGN);
+        let keys_offset = align_up(mphf_offset + self.mphf.len() as u64, SECTION_ALIGN);
+        // Compressed offsets are deserialized in place like the MPHF
+        let offsets_align = if self.flags & INDEX_FLAG_EF_OFFSETS != 0 { MPHF_ALIGN } else { SECTION_ALIGN };
+        let offsets_offset = align_up(keys_offset + self.keys.len() as u64, offsets_align);
+        let key_heap_offset = offsets_offset + self.offsets.len() as u64;
+        let value_checksums_offset = align_up(key_heap_offset + self.key_heap.len() as u64, SECTION_ALIGN);
+        let sorted_keys_offset = align_up(value_checksums_offset + self.value_checksums.len() as u64, SECTION_ALIGN);
+
+        IndexHeader {
+            magic: *b"KIDX",
+            version: INDEX_VERSION,
+            num_keys: self.num_keys,
+            mphf_size: self.mphf.len() as u64,
+            keys_offset,
+            offsets_offset,
+            mphf_offset,
+            flags: self.flags,
+            key_heap_offset,
+            key_heap_size: self.key_heap.len() as u64,
+            key_check: self.key_check.to_code(),
+            value_block_size: self.value_block_size,
+            value_checksums_offset,
+            value_checksums_size: self.value_checksums.len() as u64,
+            mphf_checksum: xxh3_64(&self.mphf),
+            keys_checksum: xxh3_64(&self.keys),
+            key_heap_checksum: xxh3_64(&self.key_heap),
+            offsets_checksum: xxh3_64(&self.offsets),
+            values_checksum: self.values_checksum,
+            value_checksums_checksum: xxh3_64(&self.value_checksums),
+            sorted_keys_offset,
+            sorted_keys_size: self.sorted_keys.len() as u64,
+            sorted_keys_checksum: xxh3_64(&self.sorted_keys),
+            offsets_size: self.offsets.len() as u64,
+            values_size,
+            data_slot: 0,
+            header_checksum: 0,
+        }
+    }
+
+    // Size of the value block checksums section for `values_size` bytes of values
+    fn value_checksums_size(&self, values_size: u64) -> u64 {
+        match self.value_block_size {
+            0 => 0,
+            block_size => values_size.div_ceil(block_size) * 8,
+        }
+    }
+}
+
+// Checksums values as they are written: one xxh3 over all of them, plus one
+// per block of `block_size` bytes when block checksums are enabled
+pub(crate) struct ValuesHasher {
+    all: Xxh3,
+    block: Xxh3,
+    block_size: u64,
+    block_len: u64,
+    block_checksums: Vec<u8>,
+}
+
+impl ValuesHasher {
+    pub(crate) fn new(block_size: u64) -> Self {
+        ValuesHasher { all: Xxh3::new(), block: Xxh3::new(), block_size, block_len: 0, block_checksums: Vec::new() }
+    }
+
+    pub(crate) fn update(&mut self, mut bytes: &[u8]) {
+        self.all.update(bytes);
+        if self.block_size == 0 {
+            return;
+        }
+        while !bytes.is_empty() {
+            let take = bytes.len().min((self.block_size - self.block_len) as usize);
+            self.block.update(&bytes[..take]);
+            self.block_len += take as u64;
+            bytes = &bytes[take..];
+            if self.block_len == self.block_size {
+                self.finish_block();
+            }
+        }
+    }
+
+    fn finish_block(&mut self) {
+        self.block_checksums.extend_from_slice(&self.block.digest().to_le_bytes());
+        self.block.reset();
+        self.block_len = 0;
+    }
+
+    // Returns the checksum of all values and the block checksums section
+    pub(crate) fn finish(mut self) -> (u64, Vec<u8>) {
+        if self.block_len > 0 {
+            self.finish_block();
+        }
+        (self.all.digest(), self.block_checksums)
+    }
+}
+
+// Writes `bytes` at `offset`, zero-padding from the current position (which must not be past it)
+fn write_at<W: Write + Seek>(writer: &mut W, offset: u64, bytes: &[u8]) -> io::Result<()> {
+    let position = writer.stream_position()?;
+    writer.write_all(&vec![0u8; (offset - position) as usize])?;
+    writer.write_all(bytes)
+}
+
+// Moves a fully written temporary file over `path`: its contents are synced
+// first, so that after a crash `path` holds either the old file or the whole
+// new one. Readers that have the old file open keep their view of it.
+pub(crate) fn publish(temp: TempFile, file: File, path: &Path) -> io::Result<()> {
+    file.sync_all()?;
+    drop(file);
+    temp.persist(path)
+}
+
+// Makes renames in `dir` durable
+pub(crate) fn sync_dir(dir: &Path) -> io::Result<()> {
+    #[cfg(unix)]
+    File::open(dir)?.sync_all()?;
+    #[cfg(not(unix))]
+    let _ = dir;
+    Ok(())
+}
+
+// Writes `bytes` to `path` through a temporary file in the same directory, so
+// the file is replaced atomically
+pub(crate) fn write_atomically(path: &Path, bytes: &[u8]) -> io::Result<()> {
+    let (temp, mut file) = TempFile::create_in(spill_dir(path), "publish")?;
+    file.write_all(bytes)?;
+    publish(temp, file, path)?;
+    sync_dir(spill_dir(path))
+}
+
+// Path of the data file in `slot` of a two-file database at `data_file`: slot 0
+// is `data_file` itself, slot 1 is `data_file` with ".alt" appended
+pub(crate) fn data_slot_path(data_file: &Path, slot: u64) -> PathBuf {
+    match slot {
+        0 => data_file.to_path_buf(),
+        _ => {
+            let mut path = data_file.as_os_str().to_owned();
+            path.push(".alt");
+            PathBuf::from(path)
+        }
+    }
+}
+
+// Data slot recorded in the index at `path_index`, None if there is no readable index there
+fn published_data_slot(path_index: &Path) -> Option<u64> {
+    let mut bytes = [0u8; IndexHeader::SIZE];
+    File::open(path_index).ok()?.read_exact(&mut bytes).ok()?;
+    IndexHeader::from_bytes(&bytes).filter(|header| header.version == INDEX_VERSION).map(|header| header.data_slot)
+}
+
+// Writes a two-file database. `write_values` writes the values (`values_size`
+// bytes) in slot order and returns their checksums (see `ValuesHasher::finish`).
+//
+// The data file goes to whichever of the two data slots (see `data_slot_path`)
+// the current index does not point at, and the index records the slot it was
+// built with. Both files are written under temporary names and synced; the data
+// file is renamed into its slot first and the index last, so the index rename
+// is the single step that publishes the new database. Until it is durable the
+// old index and the data file it points at are left untouched: a reader, or a
+// crash at any point, sees either the whole old database or the whole new one.
+// The old slot's data file is removed once the new index is in place.
+pub(crate) fn write_two_files<P, F>(
+    path_data: P,
+    path_index: P,
+    mut sections: IndexSections,
+    header: DabaHeader,
+    values_size: u64,
+    write_values: F,
+) -> io::Result<()>
+where
+    P: AsRef<Path>,
+    F: FnOnce(&mut BufWriter<File>) -> io::Result<(u64, Vec<u8>)>,
+{
+    let (path_data, path_index) = (path_data.as_ref(), path_index.as_ref());
+    // The slot the published index does not use, so its data file stays in place until the switch
+    let slot = match published_data_slot(path_index) {
+        Some(0) => 1,
+        _ => 0,
+    };
+
+    // Write data file: header, then values in the order determined by MPHF
+    let (data_temp, data_file) = TempFile::create_in(spill_dir(path_data), "data")?;
+    data_file.set_len(DabaHeader::SIZE as u64 + values_size)?;
+    let mut data_file = BufWriter::new(data_file);
+    data_file.write_all(&header.to_bytes())?;
+    (sections.values_checksum, sections.value_checksums) = write_values(&mut data_file)?;
+    let data_file = data_file.into_inner().map_err(|e| e.into_error())?;
+
+    // Write index file
+    let mut index_header = IndexHeader { data_slot: slot, ..sections.index_header(values_size) };
+    index_header.header_checksum = index_header.compute_checksum(&header);
+    let (index_temp, index_file) = TempFile::create_in(spill_dir(path_index), "index")?;
+    let mut index_file = BufWriter::new(index_file);
+    index_file.write_all(&index_header.to_bytes())?;
+    write_at(&mut index_file, index_header.mphf_offset, &sections.mphf)?;
+    write_at(&mut index_file, index_header.keys_offset, &sections.keys)?;
+    write_at(&mut index_file, index_header.offsets_offset, &sections.offsets)?;
+    write_at(&mut index_file, index_header.key_heap_offset, &sections.key_heap)?;
+    write_at(&mut index_file, index_header.value_checksums_offset, &sections.value_checksums)?;
+    write_at(&mut index_file, index_header.sorted_keys_offset, &sections.sorted_keys)?;
+    let index_file = index_file.into_inner().map_err(|e| e.into_error())?;
+
+    publish(data_temp, data_file, &data_slot_path(path_data, slot))?;
+    sync_dir(spill_dir(path_data))?;
+    publish(index_temp, index_file, path_index)?;
+    sync_dir(spill_dir(path_index))?;
+
+    // Readers that opened the old database keep their mapping of its data file
+    match fs::remove_file(data_slot_path(path_data, 1 - slot)) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+// Writes a single-file database, opened with `Database::open_single`. The
+// values (`values_size` bytes) go last, so they are written first and the
+// metadata, which holds their checksum, is filled in afterwards. Like
+// `write_two_files`, the file is written under a temporary name and renamed
+// into place once complete.
+pub(crate) fn write_single_file<P, F>(
+    path: P,
+    mut sections: IndexSections,
+    header: DabaHeader,
+    values_size: u64,
+    write_values: F,
+) -> io::Result<()>
+where
+    P: AsRef<Path>,
+    F: FnOnce(&mut BufWriter<File>) -> io::Result<(u64, Vec<u8>)>,
+{
+    let meta_size = (DabaHeader::SIZE + IndexHeader::SIZE) as u64;
+
+    // Lay out the sections after the file header and section directory
+    let sizes = [
+        (SECTION_META, meta_size),
+        (SECTION_MPHF, sections.mphf.len() as u64),
+        (SECTION_KEYS, sections.keys.len() as u64),
+        (SECTION_KEY_HEAP, sections.key_heap.len() as u64),
+        (SECTION_OFFSETS, sections.offsets.len() as u64),
+        (SECTION_VALUE_CHECKSUMS, sections.value_checksums_size(values_size)),
+        (SECTION_SORTED_KEYS, sections.sorted_keys.len() as u64),
+        (SECTION_VALUES, values_size),
+    ];
+    let mut cursor = (FileHeader::SIZE + sizes.len() * SectionEntry::SIZE) as u64;
+    let entries: Vec<SectionEntry> = sizes
+        .iter()
+        .map(|&(kind, size)| {
+            let offset = align_up(cursor, SINGLE_FILE_ALIGN);
+            cursor = offset + size;
+            SectionEntry { kind, offset, size }
+        })
+        .collect();
+
+    let path = path.as_ref();
+    let (temp, mut file) = TempFile::create_in(spill_dir(path), "kvdb")?;
+    file.set_len(cursor)?;
+    file.seek(SeekFrom::Start(entries[entries.len() - 1].offset))?;
+    let mut values_file = BufWriter::new(file);
+    (sections.values_checksum, sections.value_checksums) = write_values(&mut values_file)?;
+    let mut file = BufWriter::new(values_file.into_inner().map_err(|e| e.into_error())?);
+    file.seek(SeekFrom::Start(0))?;
+
+    // The metadata section holds the same headers as the two-file layout; the
+    // section offsets in its IndexHeader are unused since the directory locates them
+    let mut index_header = IndexHeader {
+        mphf_offset: 0,
+        keys_offset: 0,
+        offsets_offset: 0,
+        key_heap_offset: 0,
+        value_checksums_offset: 0,
+        sorted_keys_offset: 0,
+        ..sections.index_header(values_size)
+    };
+    index_header.header_checksum = index_header.compute_checksum(&header);
+    let meta = [&header.to_bytes()[..], &index_header.to_bytes()[..]].concat();
+
+    let file_header = FileHeader {
+        magic: *b"KVDB",
+        version: SINGLE_FILE_VERSION,
+        num_sections: entries.len() as u32,
+    };
+    file.write_all(&file_header.to_bytes())?;
+    for entry in &entries {
+        file.write_all(&entry.to_bytes())?;
+    }
+    let section_bytes = [
+        &meta,
+        &sections.mphf,
+        &sections.keys,
+        &sections.key_heap,
+        &sections.offsets,
+        &sections.value_checksums,
+        &sections.sorted_keys,
+    ];
+    for (entry, bytes) in entries.iter().zip(section_bytes) {
+        write_at(&mut file, entry.offset, bytes)?;
+    }
+    let file = file.into_inner().map_err(|e| e.into_error())?;
+
+    publish(temp, file, path)?;
+    sync_dir(spill_dir(path))
+}
+
+impl<const N: usize> Database<N> {
+    pub fn write_database<K, V, PK, PV, P>(
+        path_data: P,
+        path_index: P,
+        keys_iter: K,
+        values_iter: V,
+        version: u32,
+    ) -> Result<BuildReport>
+    where
+        K: Iterator<Item = PK>,
+        PK: AsRef<[u8]>,
+        V: Iterator<Item = PV>,
+        PV: AsRef<[u8]>,
+        P: AsRef<Path>,
+    {
+        Self::write_database_with_options(path_data, path_index, keys_iter, values_iter, version, &WriteOptions::default())
+    }
+
+    // Writes a database through a `DatabaseBuilder` that spills values next to the data file
+    pub fn write_database_with_options<K, V, PK, PV, P>(
+        path_data: P,
+        path_index: P,
+        keys_iter: K,
+        values_iter: V,
+        version: u32,
+        options: &WriteOptions,
+    ) -> Result<BuildReport>
+    where
+        K: Iterator<Item = PK>,
+        PK: AsRef<[u8]>,
+        V: Iterator<Item = PV>,
+        PV: AsRef<[u8]>,
+        P: AsRef<Path>,
+    {
+        let mut builder = DatabaseBuilder::<N>::new(spill_dir(path_data.as_ref()), options.clone())?;
+        insert_pairs(&mut builder, keys_iter, values_iter)?;
+        builder.finish(path_data, path_index, version)
+    }
+
+    // Writes a single-file database, opened with `Database::open_single`
+    pub fn write_database_single<K, V, PK, PV, P>(
+        path: P,
+        keys_iter: K,
+        values_iter: V,
+        version: u32,
+        options: &WriteOptions,
+    ) -> Result<BuildReport>
+    where
+        K: Iterator<Item = PK>,
+        PK: AsRef<[u8]>,
+        V: Iterator<Item = PV>,
+        PV: AsRef<[u8]>,
+        P: AsRef<Path>,
+    {
+        let mut builder = DatabaseBuilder::<N>::new(spill_dir(path.as_ref()), options.clone())?;
+        insert_pairs(&mut builder, keys_iter, values_iter)?;
+        builder.finish_single(path, version)
+    }
+}
+
+// Inserts keys and values pairwise, failing if there are more of one than of the other
+fn insert_pairs<const N: usize, K, V, PK, PV>(
+    builder: &mut DatabaseBuilder<N>,
+    mut keys_iter: K,
+    mut values_iter: V,
+) -> Result<()>
+where
+    K: Iterator<Item = PK>,
+    PK: AsRef<[u8]>,
+    V: Iterator<Item = PV>,
+    PV: AsRef<[u8]>,
+{
+    loop {
+        match (keys_iter.next(), values_iter.next()) {
+            (Some(k), Some(v)) => builder.insert(k.as_ref(), v.as_ref())?,
+            (None, None) => return Ok(()),
+            (k, v) => {
+                let keys = builder.len() + k.is_some() as usize + keys_iter.count();
+                let values = builder.len() + v.is_some() as usize + values_iter.count();
+                return Err(Error::LengthMismatch { keys, values });
+            }
+        }
+    }
+}
+
+// Directory holding `path`, where temporary files of its build are created
+pub(crate) fn spill_dir(path: &Path) -> &Path {
+    match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    }
+}
+
+// Encodes the value offsets `starts` with cacheline Elias-Fano and serializes
+// them with epserde, or returns None if they are too sparse for it (see
+// `WriteOptions::compress_offsets`)
+pub(crate) fn encode_ef_offsets(starts: &[usize], values_size: usize) -> Result<Option<Vec<u8>>> {
+    // The encoding holds 40-bit values
+    if starts.is_empty() || values_size as u64 >= 1 << 40 {
+        return Ok(None);
+    }
+    let starts: Vec<u64> = starts.par_iter().map(|&start| start as u64).collect();
+    let Some(ef) = CachelineEfVec::try_new(&starts) else {
+        return Ok(None);
+    };
+    let mut bytes = Vec::new();
+    ef.serialize(&mut bytes)
+        .map_err(|e| Error::Io(io::Error::other(format!("Failed to serialize offsets: {:?}", e))))?;
+    Ok(Some(bytes))
+}
+
+// Builds the MPHF over `keys` on the current thread pool and serializes it with epserde. Also returns, for
+// every MPHF slot, the position in `keys` of the key mapped to it. The keys must be distinct.
+pub(crate) fn build_mphf<const N: usize>(keys: &[Key<N>]) -> Result<(Vec<u8>, Vec<usize>)> {
+    let mphf = KeyPtrHash::<N>::try_new(keys, PtrHashParams::default())
+        .ok_or_else(|| Error::Mphf(format!("Failed to build MPHF over {} keys", keys.len())))?;
+
+    let slots: Vec<usize> = keys.par_iter().map(|key| mphf.index(key)).collect();
+    let mut mphf_to_original = vec![0usize; keys.len()];
+    for (original_idx, slot) in slots.into_iter().enumerate() {
+        mphf_to_original[slot] = original_idx;
+    }
+
+    // Serialize MPHF using epserde
+    let mut mphf_bytes = Vec::new();
+    mphf.serialize(&mut mphf_bytes)
+        .map_err(|e| Error::Mphf(format!("Failed to serialize MPHF: {:?}", e)))?;
+
+    Ok((mphf_bytes, mphf_to_original))
+}